@@ -0,0 +1,164 @@
+use std::os::raw::c_void;
+use windows::{core::Error, Win32::{Foundation::{HWND, HANDLE, HGLOBAL, GlobalFree}, System::{DataExchange::{OpenClipboard, CloseClipboard, EmptyClipboard, GetClipboardData, SetClipboardData, IsClipboardFormatAvailable}, Memory::{GlobalAlloc, GlobalLock, GlobalUnlock, GlobalSize, GMEM_MOVEABLE}, Ole::{CLIPBOARD_FORMAT, CF_UNICODETEXT, CF_DIB, CF_DIBV5}}}};
+
+use crate::{WindowHandle, image::Image};
+
+
+// Text and images exchanged with other programs. Apps can take a `&mut dyn Clipboard` so tests can hand them a `MemoryClipboard` instead of the system's.
+pub trait Clipboard {
+	fn get_text(&mut self) -> Result<Option<String>, String>;
+	fn set_text(&mut self, text: &str) -> Result<(), String>;
+	fn get_image(&mut self) -> Result<Option<Image>, String>;
+	fn set_image(&mut self, image: &Image) -> Result<(), String>;
+}
+
+
+// Like the system clipboard, setting one kind of content replaces the other.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum MemoryClipboard {
+	#[default]
+	Empty,
+	Text(String),
+	Image(Image)
+}
+impl Clipboard for MemoryClipboard {
+	fn get_text(&mut self) -> Result<Option<String>, String> {
+		Ok(match self {
+			MemoryClipboard::Text(text) => Some(text.clone()),
+			_ => None
+		})
+	}
+	
+	fn set_text(&mut self, text: &str) -> Result<(), String> {
+		*self = MemoryClipboard::Text(String::from(text));
+		Ok(())
+	}
+	
+	fn get_image(&mut self) -> Result<Option<Image>, String> {
+		Ok(match self {
+			MemoryClipboard::Image(image) => Some(image.clone()),
+			_ => None
+		})
+	}
+	
+	fn set_image(&mut self, image: &Image) -> Result<(), String> {
+		*self = MemoryClipboard::Image(image.clone());
+		Ok(())
+	}
+}
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct SystemClipboard {
+	hwnd: HWND
+}
+
+// Keeps the clipboard open for one operation, it has to be closed again for other programs to use it.
+struct OpenedClipboard;
+impl OpenedClipboard {
+	fn open(hwnd: HWND) -> Result<Self, String> {
+		unsafe { OpenClipboard(hwnd) }.map_err(|err| format!("Error opening clipboard: {err}"))?;
+		Ok(Self)
+	}
+	
+	fn read(&self, format: CLIPBOARD_FORMAT) -> Result<Option<Vec<u8>>, String> {
+		if unsafe { IsClipboardFormatAvailable(format.0 as u32) }.is_err() {
+			return Ok(None);
+		}
+		let handle = unsafe { GetClipboardData(format.0 as u32) }.map_err(|err| format!("Error reading clipboard: {err}"))?;
+		let memory = HGLOBAL(handle.0 as *mut c_void);
+		let pointer = unsafe { GlobalLock(memory) };
+		if pointer.is_null() {
+			return Err(format!("Error reading clipboard: {}", Error::from_win32()));
+		}
+		let data = unsafe { std::slice::from_raw_parts(pointer as *const u8, GlobalSize(memory)) }.to_vec();
+		// Unlocking the last lock reports an error even though it worked.
+		let _ = unsafe { GlobalUnlock(memory) };
+		Ok(Some(data))
+	}
+	
+	fn write(&self, format: CLIPBOARD_FORMAT, data: &[u8]) -> Result<(), String> {
+		let memory = unsafe { GlobalAlloc(GMEM_MOVEABLE, data.len()) }.map_err(|err| format!("Error writing clipboard: {err}"))?;
+		let pointer = unsafe { GlobalLock(memory) };
+		if pointer.is_null() {
+			let err = Error::from_win32();
+			let _ = unsafe { GlobalFree(memory) };
+			return Err(format!("Error writing clipboard: {err}"));
+		}
+		unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), pointer as *mut u8, data.len()) };
+		let _ = unsafe { GlobalUnlock(memory) };
+		
+		// The clipboard owns the memory once it accepted it.
+		if let Err(err) = unsafe { SetClipboardData(format.0 as u32, HANDLE(memory.0 as isize)) } {
+			let _ = unsafe { GlobalFree(memory) };
+			return Err(format!("Error writing clipboard: {err}"));
+		}
+		Ok(())
+	}
+}
+impl Drop for OpenedClipboard {
+	fn drop(&mut self) {
+		let _ = unsafe { CloseClipboard() };
+	}
+}
+
+impl Clipboard for SystemClipboard {
+	fn get_text(&mut self) -> Result<Option<String>, String> {
+		let Some(data) = OpenedClipboard::open(self.hwnd)?.read(CF_UNICODETEXT)? else {
+			return Ok(None);
+		};
+		let wide: Vec<u16> = data.chunks_exact(2).map(|pair| u16::from_le_bytes([pair[0], pair[1]])).take_while(|&unit| unit != 0).collect();
+		Ok(Some(String::from_utf16_lossy(&wide)))
+	}
+	
+	fn set_text(&mut self, text: &str) -> Result<(), String> {
+		let data: Vec<u8> = text.encode_utf16().chain(Some(0)).flat_map(u16::to_le_bytes).collect();
+		let clipboard = OpenedClipboard::open(self.hwnd)?;
+		unsafe { EmptyClipboard() }.map_err(|err| format!("Error clearing clipboard: {err}"))?;
+		clipboard.write(CF_UNICODETEXT, &data)
+	}
+	
+	// Prefers the version 5 bitmap, which can carry alpha. Windows converts between the bitmap formats on its own.
+	fn get_image(&mut self) -> Result<Option<Image>, String> {
+		let clipboard = OpenedClipboard::open(self.hwnd)?;
+		let data = match clipboard.read(CF_DIBV5)? {
+			Some(data) => data,
+			None => match clipboard.read(CF_DIB)? {
+				Some(data) => data,
+				None => return Ok(None)
+			}
+		};
+		drop(clipboard);
+		Image::decode_dib(&data).map(Some).map_err(|err| format!("Error reading clipboard image: {err}"))
+	}
+	
+	fn set_image(&mut self, image: &Image) -> Result<(), String> {
+		let data = image.encode_dib();
+		let clipboard = OpenedClipboard::open(self.hwnd)?;
+		unsafe { EmptyClipboard() }.map_err(|err| format!("Error clearing clipboard: {err}"))?;
+		clipboard.write(CF_DIBV5, &data)
+	}
+}
+
+impl WindowHandle {
+	pub fn clipboard(&self) -> SystemClipboard {
+		SystemClipboard { hwnd: self.hwnd }
+	}
+	
+	pub fn clipboard_get_text(&self) -> Result<Option<String>, String> {
+		self.clipboard().get_text()
+	}
+	
+	pub fn clipboard_set_text(&self, text: &str) -> Result<(), String> {
+		self.clipboard().set_text(text)
+	}
+	
+	// To paste into the window, draw the result with `Image::draw`. To copy the window, pass `Image::from_frame`.
+	pub fn clipboard_get_image(&self) -> Result<Option<Image>, String> {
+		self.clipboard().get_image()
+	}
+	
+	pub fn clipboard_set_image(&self, image: &Image) -> Result<(), String> {
+		self.clipboard().set_image(image)
+	}
+}
@@ -0,0 +1,191 @@
+use std::{cell::RefCell, collections::VecDeque, ffi::{OsStr, OsString}, path::{Path, PathBuf}};
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use windows::{core::{ComInterface, PCWSTR}, Win32::{Foundation::{HWND, ERROR_CANCELLED}, System::Com::{CoCreateInstance, CoInitializeEx, CoUninitialize, CoTaskMemFree, CLSCTX_INPROC_SERVER, COINIT_APARTMENTTHREADED}, UI::Shell::{IFileDialog, IFileOpenDialog, IFileSaveDialog, IShellItem, FileOpenDialog, FileSaveDialog, SHCreateItemFromParsingName, FOS_FORCEFILESYSTEM, FOS_PICKFOLDERS, FOS_FILEMUSTEXIST, FOS_PATHMUSTEXIST, FOS_OVERWRITEPROMPT, SIGDN_FILESYSPATH, FILEOPENDIALOGOPTIONS, Common::COMDLG_FILTERSPEC}}};
+
+use crate::WindowHandle;
+
+
+// Shows the file dialogs of a window. Filters pair a name with semicolon separated patterns, like `("Images", "*.png;*.bmp")`.
+// Each method returns None when the user cancels.
+pub trait DialogProvider {
+	fn open_file(&mut self, filters: &[(&str, &str)], initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String>;
+	fn save_file(&mut self, filters: &[(&str, &str)], initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String>;
+	fn pick_folder(&mut self, initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String>;
+}
+
+
+// Answers dialogs in order from a list instead of asking the user, for tests. Running out of answers is an error.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScriptedDialogs {
+	answers: VecDeque<Option<PathBuf>>
+}
+impl ScriptedDialogs {
+	pub fn new(answers: impl IntoIterator<Item = Option<PathBuf>>) -> Self {
+		Self { answers: answers.into_iter().collect() }
+	}
+	
+	pub fn push_answer(&mut self, answer: Option<PathBuf>) {
+		self.answers.push_back(answer);
+	}
+	
+	pub fn remaining(&self) -> usize {
+		self.answers.len()
+	}
+	
+	fn next_answer(&mut self) -> Result<Option<PathBuf>, String> {
+		self.answers.pop_front().ok_or_else(|| String::from("Error showing dialog: No scripted answers left."))
+	}
+}
+impl DialogProvider for ScriptedDialogs {
+	fn open_file(&mut self, _filters: &[(&str, &str)], _initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		self.next_answer()
+	}
+	
+	fn save_file(&mut self, _filters: &[(&str, &str)], _initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		self.next_answer()
+	}
+	
+	fn pick_folder(&mut self, _initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		self.next_answer()
+	}
+}
+
+
+// The common item dialogs, modal to the window.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NativeDialogs {
+	hwnd: HWND
+}
+
+// The dialogs need COM on the calling thread, it's released again if it wasn't set up already.
+struct ComApartment(bool);
+impl ComApartment {
+	fn enter() -> Self {
+		Self(unsafe { CoInitializeEx(None, COINIT_APARTMENTTHREADED) }.is_ok())
+	}
+}
+impl Drop for ComApartment {
+	fn drop(&mut self) {
+		if self.0 {
+			unsafe { CoUninitialize() };
+		}
+	}
+}
+
+fn wide(text: &OsStr) -> Vec<u16> {
+	text.encode_wide().chain(Some(0)).collect()
+}
+
+// Sets up and shows a dialog, then reads the chosen path.
+fn show_dialog(hwnd: HWND, dialog: &IFileDialog, filters: &[(&str, &str)], initial_dir: Option<&Path>, options: FILEOPENDIALOGOPTIONS) -> Result<Option<PathBuf>, String> {
+	unsafe {
+		let options = dialog.GetOptions().map_err(|err| format!("Error showing dialog: {err}"))? | options | FOS_FORCEFILESYSTEM;
+		dialog.SetOptions(options).map_err(|err| format!("Error showing dialog: {err}"))?;
+		
+		// The specs point into these buffers, so they have to outlive the call.
+		let names: Vec<Vec<u16>> = filters.iter().map(|(name, _)| wide(OsStr::new(name))).collect();
+		let patterns: Vec<Vec<u16>> = filters.iter().map(|(_, pattern)| wide(OsStr::new(pattern))).collect();
+		let specs: Vec<COMDLG_FILTERSPEC> = names.iter().zip(&patterns).map(|(name, pattern)| COMDLG_FILTERSPEC { pszName: PCWSTR(name.as_ptr()), pszSpec: PCWSTR(pattern.as_ptr()) }).collect();
+		if !specs.is_empty() {
+			dialog.SetFileTypes(&specs).map_err(|err| format!("Error showing dialog: {err}"))?;
+		}
+		
+		if let Some(initial_dir) = initial_dir {
+			// A folder that doesn't exist is left to the dialog's default.
+			let initial_dir = wide(initial_dir.as_os_str());
+			if let Ok(folder) = SHCreateItemFromParsingName::<_, _, IShellItem>(PCWSTR(initial_dir.as_ptr()), None) {
+				dialog.SetFolder(&folder).map_err(|err| format!("Error showing dialog: {err}"))?;
+			}
+		}
+		
+		match dialog.Show(hwnd) {
+			Ok(()) => {}
+			Err(err) if err.code() == ERROR_CANCELLED.into() => return Ok(None),
+			Err(err) => return Err(format!("Error showing dialog: {err}"))
+		}
+		let item = dialog.GetResult().map_err(|err| format!("Error reading dialog result: {err}"))?;
+		let name = item.GetDisplayName(SIGDN_FILESYSPATH).map_err(|err| format!("Error reading dialog result: {err}"))?;
+		let path = PathBuf::from(OsString::from_wide(name.as_wide()));
+		CoTaskMemFree(Some(name.0 as *const _));
+		Ok(Some(path))
+	}
+}
+
+impl DialogProvider for NativeDialogs {
+	fn open_file(&mut self, filters: &[(&str, &str)], initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		let _apartment = ComApartment::enter();
+		let dialog: IFileOpenDialog = unsafe { CoCreateInstance(&FileOpenDialog, None, CLSCTX_INPROC_SERVER) }.map_err(|err| format!("Error creating dialog: {err}"))?;
+		show_dialog(self.hwnd, &dialog.cast().map_err(|err| format!("Error creating dialog: {err}"))?, filters, initial_dir, FOS_FILEMUSTEXIST | FOS_PATHMUSTEXIST)
+	}
+	
+	fn save_file(&mut self, filters: &[(&str, &str)], initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		let _apartment = ComApartment::enter();
+		let dialog: IFileSaveDialog = unsafe { CoCreateInstance(&FileSaveDialog, None, CLSCTX_INPROC_SERVER) }.map_err(|err| format!("Error creating dialog: {err}"))?;
+		show_dialog(self.hwnd, &dialog.cast().map_err(|err| format!("Error creating dialog: {err}"))?, filters, initial_dir, FOS_OVERWRITEPROMPT | FOS_PATHMUSTEXIST)
+	}
+	
+	fn pick_folder(&mut self, initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		let _apartment = ComApartment::enter();
+		let dialog: IFileOpenDialog = unsafe { CoCreateInstance(&FileOpenDialog, None, CLSCTX_INPROC_SERVER) }.map_err(|err| format!("Error creating dialog: {err}"))?;
+		show_dialog(self.hwnd, &dialog.cast().map_err(|err| format!("Error creating dialog: {err}"))?, &[], initial_dir, FOS_PICKFOLDERS | FOS_PATHMUSTEXIST)
+	}
+}
+
+
+thread_local! {
+	// Providers set with `set_dialog_provider`, windows without one use the native dialogs.
+	static PROVIDERS: RefCell<Vec<(HWND, Box<dyn DialogProvider>)>> = RefCell::new(Vec::new());
+}
+
+impl WindowHandle {
+	pub fn native_dialogs(&self) -> NativeDialogs {
+		NativeDialogs { hwnd: self.hwnd }
+	}
+	
+	// Replaces the window's dialogs, such as with `ScriptedDialogs` in tests. None goes back to the native dialogs.
+	pub fn set_dialog_provider(&self, provider: Option<Box<dyn DialogProvider>>) {
+		PROVIDERS.with_borrow_mut(|providers| {
+			providers.retain(|(hwnd, _)| *hwnd != self.hwnd);
+			if let Some(provider) = provider {
+				providers.push((self.hwnd, provider));
+			}
+		});
+	}
+	
+	// The provider is taken out of the registry while it runs, since a modal dialog keeps handling the window's messages.
+	fn with_dialog_provider<T>(&self, show: impl FnOnce(&mut dyn DialogProvider) -> T) -> T {
+		let provider = PROVIDERS.with_borrow_mut(|providers| {
+			let index = providers.iter().position(|(hwnd, _)| *hwnd == self.hwnd)?;
+			Some(providers.remove(index).1)
+		});
+		match provider {
+			Some(mut provider) => {
+				let result = show(provider.as_mut());
+				// Keeps a provider that was set while the dialog was open.
+				PROVIDERS.with_borrow_mut(|providers| {
+					if !providers.iter().any(|(hwnd, _)| *hwnd == self.hwnd) {
+						providers.push((self.hwnd, provider));
+					}
+				});
+				result
+			}
+			None => show(&mut self.native_dialogs())
+		}
+	}
+	
+	pub fn open_file_dialog(&self, filters: &[(&str, &str)], initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		self.with_dialog_provider(|provider| provider.open_file(filters, initial_dir))
+	}
+	
+	pub fn save_file_dialog(&self, filters: &[(&str, &str)], initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		self.with_dialog_provider(|provider| provider.save_file(filters, initial_dir))
+	}
+	
+	pub fn pick_folder(&self, initial_dir: Option<&Path>) -> Result<Option<PathBuf>, String> {
+		self.with_dialog_provider(|provider| provider.pick_folder(initial_dir))
+	}
+}
+
+pub(crate) fn forget_provider(hwnd: HWND) {
+	PROVIDERS.with_borrow_mut(|providers| providers.retain(|(provider_hwnd, _)| *provider_hwnd != hwnd));
+}
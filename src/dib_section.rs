@@ -0,0 +1,79 @@
+use std::os::raw::c_void;
+use windows::Win32::{Foundation::HWND, Graphics::Gdi::{BITMAPINFO, BITMAPINFOHEADER, RGBQUAD, BI_RGB, DIB_RGB_COLORS, HBITMAP, CreateDIBSection, DeleteObject, GetDC, ReleaseDC}};
+
+
+// A top-down 32-bit bitmap whose pixels the process can access directly. The bitmap is deleted when dropped.
+pub(crate) struct DibSection {
+	bitmap: HBITMAP,
+	pixels: *mut u8,
+	len: usize
+}
+impl Default for DibSection {
+	fn default() -> Self {
+		Self::empty()
+	}
+}
+impl DibSection {
+	// Has no bitmap and no pixels, used when allocating one failed.
+	pub(crate) fn empty() -> Self {
+		Self { bitmap: HBITMAP(0), pixels: std::ptr::NonNull::dangling().as_ptr(), len: 0 }
+	}
+	
+	pub(crate) fn new(hwnd: HWND, width: i32, height: i32) -> Result<Self, String> {
+		if width <= 0 || height <= 0 {
+			return Ok(Self::empty());
+		}
+		let bmi = BITMAPINFO {
+			bmiHeader: BITMAPINFOHEADER {
+				biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+				biWidth: width,
+				biHeight: -height,
+				biPlanes: 1,
+				biBitCount: 32,
+				biCompression: BI_RGB.0,
+				biSizeImage: 0,
+				biXPelsPerMeter: 0,
+				biYPelsPerMeter: 0,
+				biClrUsed: 0,
+				biClrImportant: 0
+			},
+			bmiColors: [RGBQUAD::default(); 1]
+		};
+		
+		let mut pixels: *mut c_void = std::ptr::null_mut();
+		let bitmap = unsafe {
+			let dc = GetDC(hwnd);
+			let bitmap = CreateDIBSection(dc, &bmi, DIB_RGB_COLORS, &mut pixels, None, 0);
+			ReleaseDC(hwnd, dc);
+			bitmap
+		}.map_err(|err| format!("Error allocating bitmap: {err}"))?;
+		if pixels.is_null() {
+			unsafe { DeleteObject(bitmap) };
+			return Err(String::from("Error allocating bitmap: No pixel memory."));
+		}
+		Ok(Self { bitmap, pixels: pixels as *mut u8, len: 4 * width as usize * height as usize })
+	}
+	
+	pub(crate) fn bitmap(&self) -> Option<HBITMAP> {
+		if self.bitmap.is_invalid() {
+			None
+		} else {
+			Some(self.bitmap)
+		}
+	}
+	
+	pub(crate) fn pixels(&self) -> &[u8] {
+		unsafe { std::slice::from_raw_parts(self.pixels, self.len) }
+	}
+	
+	pub(crate) fn pixels_mut(&mut self) -> &mut [u8] {
+		unsafe { std::slice::from_raw_parts_mut(self.pixels, self.len) }
+	}
+}
+impl Drop for DibSection {
+	fn drop(&mut self) {
+		if !self.bitmap.is_invalid() {
+			unsafe { DeleteObject(self.bitmap) };
+		}
+	}
+}
@@ -0,0 +1,40 @@
+use std::any::Any;
+use windows::Win32::{Foundation::{HWND, WPARAM, LPARAM}, Graphics::Gdi::InvalidateRect, UI::WindowsAndMessaging::{PostMessageW, WM_APP}};
+
+use crate::WindowHandle;
+
+
+// Posted by an `EventProxy`, the LPARAM owns a boxed payload.
+pub(crate) const WM_USER_EVENT: u32 = WM_APP + 2;
+
+// Reaches a window from other threads. Payloads arrive in `on_user_event` on the window's thread, in the order they were sent.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct EventProxy {
+	hwnd: HWND
+}
+impl EventProxy {
+	// Fails once the window is closed.
+	pub fn send(&self, payload: impl Any + Send) -> Result<(), String> {
+		let payload: Box<Box<dyn Any + Send>> = Box::new(Box::new(payload));
+		let payload = Box::into_raw(payload);
+		if let Err(err) = unsafe { PostMessageW(self.hwnd, WM_USER_EVENT, WPARAM(0), LPARAM(payload as isize)) } {
+			drop(unsafe { Box::from_raw(payload) });
+			return Err(format!("Error sending user event: {err}"));
+		}
+		Ok(())
+	}
+	
+	pub fn request_redraw(&self) {
+		unsafe { InvalidateRect(self.hwnd, None, false) };
+	}
+}
+
+impl WindowHandle {
+	pub fn event_proxy(&self) -> EventProxy {
+		EventProxy { hwnd: self.hwnd }
+	}
+}
+
+pub(crate) fn take_payload(lparam: LPARAM) -> Box<dyn Any + Send> {
+	*unsafe { Box::from_raw(lparam.0 as *mut Box<dyn Any + Send>) }
+}
@@ -0,0 +1,37 @@
+use std::{ffi::OsString, path::PathBuf};
+use std::os::windows::ffi::OsStringExt;
+use windows::Win32::{Foundation::{HWND, POINT, WPARAM}, UI::Shell::{HDROP, DragAcceptFiles, DragQueryFileW, DragQueryPoint, DragFinish}};
+
+
+// WM_DROPFILES only reports the drop itself, there's no feedback while files are dragged over the window.
+pub(crate) fn accept_files(hwnd: HWND) {
+	unsafe { DragAcceptFiles(hwnd, true) };
+}
+
+// Reads the paths and client coordinates of a WM_DROPFILES message and releases its data.
+pub(crate) fn take_dropped_files(wparam: WPARAM) -> (Vec<PathBuf>, i16, i16) {
+	let hdrop = HDROP(wparam.0 as isize);
+	let count = unsafe { DragQueryFileW(hdrop, u32::MAX, None) };
+	let paths = collect_paths(count, |index, buffer| unsafe { DragQueryFileW(hdrop, index, buffer) });
+	
+	let mut point = POINT::default();
+	unsafe { DragQueryPoint(hdrop, &mut point) };
+	unsafe { DragFinish(hdrop) };
+	(paths, point.x as i16, point.y as i16)
+}
+
+// For a drop that can't be handled anymore.
+pub(crate) fn release_dropped_files(wparam: WPARAM) {
+	unsafe { DragFinish(HDROP(wparam.0 as isize)) };
+}
+
+// `query` works like DragQueryFileW, without a buffer it returns the length of the path at the index,
+// with one it copies the path and its terminator in and returns the number of characters copied.
+pub(crate) fn collect_paths(count: u32, mut query: impl FnMut(u32, Option<&mut [u16]>) -> u32) -> Vec<PathBuf> {
+	(0..count).map(|index| {
+		let length = query(index, None) as usize;
+		let mut buffer = vec![0u16; length + 1];
+		let copied = (query(index, Some(&mut buffer)) as usize).min(length);
+		PathBuf::from(OsString::from_wide(&buffer[..copied]))
+	}).collect()
+}
@@ -0,0 +1,259 @@
+use std::{fmt, path::Path};
+
+use crate::Rect;
+
+mod blit;
+mod bmp;
+mod gif;
+mod netpbm;
+mod png;
+mod tga;
+mod transform;
+mod y4m;
+mod zlib;
+
+pub use blit::{Blit, BlendMode, Rotation};
+pub use gif::GifEncoder;
+pub use png::PngFormat;
+pub use transform::{Affine, Filter};
+pub use y4m::Y4mEncoder;
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ImageError {
+	UnexpectedEnd,
+	UnknownFormat,
+	Unsupported(String),
+	Malformed(String),
+	TooLarge { width: u64, height: u64 },
+	Io(String)
+}
+impl fmt::Display for ImageError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			ImageError::UnexpectedEnd => write!(f, "Image data ends unexpectedly."),
+			ImageError::UnknownFormat => write!(f, "Image format not recognized."),
+			ImageError::Unsupported(message) => write!(f, "Unsupported image: {message}"),
+			ImageError::Malformed(message) => write!(f, "Malformed image: {message}"),
+			ImageError::TooLarge { width, height } => write!(f, "Image of {width}x{height} pixels is too large."),
+			ImageError::Io(message) => write!(f, "Error accessing image file: {message}")
+		}
+	}
+}
+impl std::error::Error for ImageError {}
+
+
+// Decoders refuse anything bigger than this many pixels instead of attempting the allocation.
+const MAX_PIXELS: u64 = 1 << 28;
+
+// An image in the same BGRA layout as the window's pixel buffer, rows top to bottom without padding.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Image {
+	width: u32,
+	height: u32,
+	pixels: Vec<u8>
+}
+impl Image {
+	pub fn new(width: u32, height: u32) -> Result<Self, ImageError> {
+		let size = pixel_count(width as u64, height as u64)? * 4;
+		Ok(Self { width, height, pixels: vec![0; size] })
+	}
+	
+	pub fn from_bgra(width: u32, height: u32, pixels: Vec<u8>) -> Result<Self, ImageError> {
+		if pixels.len() as u64 != pixel_count(width as u64, height as u64)? as u64 * 4 {
+			return Err(ImageError::Malformed(format!("{} bytes don't make a {width}x{height} image.", pixels.len())));
+		}
+		Ok(Self { width, height, pixels })
+	}
+	
+	// Copies a frame out of the window's pixel buffer. The padding byte of each pixel isn't alpha, so the copy is opaque.
+	pub fn from_frame(pixel_buffer: &[u8], client_rect: &Rect) -> Self {
+		let width = client_rect.width().max(0) as u32;
+		let height = (client_rect.height().max(0) as usize).min(pixel_buffer.len() / (4 * width as usize).max(1)) as u32;
+		let mut pixels = pixel_buffer[..4 * width as usize * height as usize].to_vec();
+		pixels.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 255);
+		Self { width, height, pixels }
+	}
+	
+	pub fn decode(data: &[u8]) -> Result<Self, ImageError> {
+		match data {
+			[b'B', b'M', ..] => Self::decode_bmp(data),
+			[b'P', b'1'..=b'6', ..] => Self::decode_netpbm(data),
+			[0x89, b'P', b'N', b'G', ..] => Self::decode_png(data),
+			// TGA files have no signature, so they are the fallback.
+			_ => Self::decode_tga(data).map_err(|_| ImageError::UnknownFormat)
+		}
+	}
+	
+	pub fn decode_bmp(data: &[u8]) -> Result<Self, ImageError> {
+		bmp::decode(data)
+	}
+	
+	// A BMP without its file header, as found in memory and on the clipboard.
+	pub fn decode_dib(data: &[u8]) -> Result<Self, ImageError> {
+		bmp::decode_packed(data)
+	}
+	
+	pub fn decode_netpbm(data: &[u8]) -> Result<Self, ImageError> {
+		netpbm::decode(data)
+	}
+	
+	pub fn decode_tga(data: &[u8]) -> Result<Self, ImageError> {
+		tga::decode(data)
+	}
+	
+	pub fn decode_png(data: &[u8]) -> Result<Self, ImageError> {
+		png::decode(data)
+	}
+	
+	pub fn encode_png(&self, format: PngFormat) -> Result<Vec<u8>, ImageError> {
+		png::encode(self, format)
+	}
+	
+	pub fn save_png(&self, path: impl AsRef<Path>, format: PngFormat) -> Result<(), ImageError> {
+		write_file(path.as_ref(), &self.encode_png(format)?)
+	}
+	
+	pub fn encode_bmp(&self) -> Vec<u8> {
+		bmp::encode(self)
+	}
+	
+	pub fn encode_dib(&self) -> Vec<u8> {
+		bmp::encode_dib_v5(self)
+	}
+	
+	pub fn save_bmp(&self, path: impl AsRef<Path>) -> Result<(), ImageError> {
+		write_file(path.as_ref(), &self.encode_bmp())
+	}
+	
+	// Picks the format from the extension, PNG keeps the alpha channel and BMP drops it.
+	pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ImageError> {
+		let path = path.as_ref();
+		match path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_ascii_lowercase()).as_deref() {
+			Some("png") => self.save_png(path, PngFormat::Rgba8),
+			Some("bmp") => self.save_bmp(path),
+			_ => Err(ImageError::Unsupported(format!("Saving {}, only .png and .bmp are supported.", path.display())))
+		}
+	}
+	
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, ImageError> {
+		let path = path.as_ref();
+		let data = std::fs::read(path).map_err(|err| ImageError::Io(format!("{}: {err}", path.display())))?;
+		match path.extension().and_then(|extension| extension.to_str()).map(|extension| extension.to_ascii_lowercase()).as_deref() {
+			Some("tga") => Self::decode_tga(&data),
+			_ => Self::decode(&data)
+		}
+	}
+	
+	pub fn width(&self) -> u32 {
+		self.width
+	}
+	
+	pub fn height(&self) -> u32 {
+		self.height
+	}
+	
+	pub fn pixels(&self) -> &[u8] {
+		&self.pixels
+	}
+	
+	pub fn pixels_mut(&mut self) -> &mut [u8] {
+		&mut self.pixels
+	}
+	
+	pub fn into_pixels(self) -> Vec<u8> {
+		self.pixels
+	}
+	
+	pub fn get_pixel(&self, x: u32, y: u32) -> Option<[u8; 4]> {
+		if x >= self.width || y >= self.height {
+			return None;
+		}
+		let i = 4 * (y as usize * self.width as usize + x as usize);
+		Some([self.pixels[i], self.pixels[i + 1], self.pixels[i + 2], self.pixels[i + 3]])
+	}
+	
+	pub fn set_pixel(&mut self, x: u32, y: u32, bgra: [u8; 4]) {
+		if x < self.width && y < self.height {
+			let i = 4 * (y as usize * self.width as usize + x as usize);
+			self.pixels[i..i + 4].copy_from_slice(&bgra);
+		}
+	}
+	
+	// Copies the image into a pixel buffer with its top left corner at `x`, `y`, clipped to the client area.
+	pub fn draw(&self, pixel_buffer: &mut [u8], client_rect: &Rect, x: i32, y: i32) {
+		let buffer_width = client_rect.width().max(0) as i64;
+		let buffer_height = (client_rect.height().max(0) as i64).min(pixel_buffer.len() as i64 / (buffer_width * 4).max(1));
+		
+		let left = (x as i64).max(0);
+		let right = (x as i64 + self.width as i64).min(buffer_width);
+		if left >= right {
+			return;
+		}
+		for row in (y as i64).max(0)..(y as i64 + self.height as i64).min(buffer_height) {
+			let source = 4 * ((row - y as i64) * self.width as i64 + (left - x as i64)) as usize;
+			let destination = 4 * (row * buffer_width + left) as usize;
+			let length = 4 * (right - left) as usize;
+			pixel_buffer[destination..destination + length].copy_from_slice(&self.pixels[source..source + length]);
+		}
+	}
+}
+
+fn write_file(path: &Path, data: &[u8]) -> Result<(), ImageError> {
+	std::fs::write(path, data).map_err(|err| ImageError::Io(format!("{}: {err}", path.display())))
+}
+
+fn pixel_count(width: u64, height: u64) -> Result<usize, ImageError> {
+	match width.checked_mul(height) {
+		Some(count) if count <= MAX_PIXELS => Ok(count as usize),
+		_ => Err(ImageError::TooLarge { width, height })
+	}
+}
+
+
+// Bounds checked little endian reader shared by the decoders.
+struct Reader<'a> {
+	data: &'a [u8],
+	position: usize
+}
+impl<'a> Reader<'a> {
+	fn new(data: &'a [u8]) -> Self {
+		Self { data, position: 0 }
+	}
+	
+	fn at(data: &'a [u8], position: usize) -> Result<Self, ImageError> {
+		if position > data.len() {
+			return Err(ImageError::UnexpectedEnd);
+		}
+		Ok(Self { data, position })
+	}
+	
+	fn bytes(&mut self, count: usize) -> Result<&'a [u8], ImageError> {
+		let end = self.position.checked_add(count).filter(|&end| end <= self.data.len()).ok_or(ImageError::UnexpectedEnd)?;
+		let bytes = &self.data[self.position..end];
+		self.position = end;
+		Ok(bytes)
+	}
+	
+	fn remaining(&self) -> usize {
+		self.data.len() - self.position
+	}
+	
+	fn u8(&mut self) -> Result<u8, ImageError> {
+		Ok(self.bytes(1)?[0])
+	}
+	
+	fn u16(&mut self) -> Result<u16, ImageError> {
+		let bytes = self.bytes(2)?;
+		Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+	}
+	
+	fn u32(&mut self) -> Result<u32, ImageError> {
+		let bytes = self.bytes(4)?;
+		Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+	
+	fn i32(&mut self) -> Result<i32, ImageError> {
+		Ok(self.u32()? as i32)
+	}
+}
@@ -0,0 +1,159 @@
+use crate::Rect;
+
+use super::Image;
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum BlendMode {
+	#[default]
+	Opaque,
+	Alpha,
+	PremultipliedAlpha,
+	// Pixels of this blue, green and red are skipped, alpha is ignored.
+	ColorKey([u8; 3])
+}
+
+// Clockwise, applied after flipping.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Rotation {
+	#[default]
+	None,
+	Quarter,
+	Half,
+	ThreeQuarters
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct Blit {
+	source: Option<Rect>,
+	blend: BlendMode,
+	flip_horizontal: bool,
+	flip_vertical: bool,
+	rotation: Rotation
+}
+impl Blit {
+	pub fn new() -> Self {
+		Self::default()
+	}
+	
+	// Part of the image to draw, like one sprite of a sheet. It's clipped to the image.
+	pub fn source(mut self, source: Rect) -> Self {
+		self.source = Some(source);
+		self
+	}
+	
+	pub fn blend(mut self, blend: BlendMode) -> Self {
+		self.blend = blend;
+		self
+	}
+	
+	pub fn flip_horizontal(mut self, flip: bool) -> Self {
+		self.flip_horizontal = flip;
+		self
+	}
+	
+	pub fn flip_vertical(mut self, flip: bool) -> Self {
+		self.flip_vertical = flip;
+		self
+	}
+	
+	pub fn rotate(mut self, rotation: Rotation) -> Self {
+		self.rotation = rotation;
+		self
+	}
+}
+
+// Division by 255 with rounding, exact for products of two bytes.
+fn div_255(value: u32) -> u32 {
+	(value + 128 + ((value + 128) >> 8)) >> 8
+}
+
+impl Image {
+	// Draws the image with its transformed top left corner at `x`, `y`, clipped to the client area.
+	pub fn blit(&self, pixel_buffer: &mut [u8], client_rect: &Rect, x: i32, y: i32, blit: &Blit) {
+		let source = blit.source.unwrap_or(Rect { left: 0, top: 0, right: self.width as i32, bottom: self.height as i32 });
+		let source_left = source.left.clamp(0, self.width as i32) as i64;
+		let source_top = source.top.clamp(0, self.height as i32) as i64;
+		let source_width = (source.right.clamp(0, self.width as i32) as i64 - source_left).max(0);
+		let source_height = (source.bottom.clamp(0, self.height as i32) as i64 - source_top).max(0);
+		let (width, height) = match blit.rotation {
+			Rotation::None | Rotation::Half => (source_width, source_height),
+			Rotation::Quarter | Rotation::ThreeQuarters => (source_height, source_width)
+		};
+		
+		let buffer_width = client_rect.width().max(0) as i64;
+		let buffer_height = (client_rect.height().max(0) as i64).min(pixel_buffer.len() as i64 / (buffer_width * 4).max(1));
+		let left = (x as i64).max(0);
+		let right = (x as i64 + width).min(buffer_width);
+		let top = (y as i64).max(0);
+		let bottom = (y as i64 + height).min(buffer_height);
+		if left >= right || top >= bottom {
+			return;
+		}
+		
+		// Source position of the destination's top left pixel and how it moves per destination column and row, before flipping.
+		let (origin, column_step, row_step) = match blit.rotation {
+			Rotation::None => ((0, 0), (1, 0), (0, 1)),
+			Rotation::Quarter => ((0, source_height - 1), (0, -1), (1, 0)),
+			Rotation::Half => ((source_width - 1, source_height - 1), (-1, 0), (0, -1)),
+			Rotation::ThreeQuarters => ((source_width - 1, 0), (0, 1), (-1, 0))
+		};
+		let flip = |(u, v): (i64, i64), (du, dv): (i64, i64)| -> ((i64, i64), (i64, i64)) {
+			let (u, du) = if blit.flip_horizontal { (source_width - 1 - u, -du) } else { (u, du) };
+			let (v, dv) = if blit.flip_vertical { (source_height - 1 - v, -dv) } else { (v, dv) };
+			((u, v), (du, dv))
+		};
+		let (origin, column_step) = flip(origin, column_step);
+		let row_step = (if blit.flip_horizontal { -row_step.0 } else { row_step.0 }, if blit.flip_vertical { -row_step.1 } else { row_step.1 });
+		
+		let image_width = self.width as i64;
+		for row in top..bottom {
+			let (offset_x, offset_y) = (left - x as i64, row - y as i64);
+			let mut u = source_left + origin.0 + offset_x * column_step.0 + offset_y * row_step.0;
+			let mut v = source_top + origin.1 + offset_x * column_step.1 + offset_y * row_step.1;
+			let destination_row = &mut pixel_buffer[4 * (row * buffer_width + left) as usize..4 * (row * buffer_width + right) as usize];
+			
+			// Untransformed opaque rows are plain copies.
+			if blit.blend == BlendMode::Opaque && column_step == (1, 0) {
+				let start = 4 * (v * image_width + u) as usize;
+				destination_row.copy_from_slice(&self.pixels[start..start + destination_row.len()]);
+				continue;
+			}
+			
+			for destination in destination_row.chunks_exact_mut(4) {
+				let i = 4 * (v * image_width + u) as usize;
+				let source = &self.pixels[i..i + 4];
+				u += column_step.0;
+				v += column_step.1;
+				
+				match blit.blend {
+					BlendMode::Opaque => destination.copy_from_slice(source),
+					BlendMode::ColorKey(key) => {
+						if source[..3] != key {
+							destination.copy_from_slice(source);
+						}
+					}
+					BlendMode::Alpha => {
+						let alpha = source[3] as u32;
+						match alpha {
+							0 => {}
+							255 => destination.copy_from_slice(source),
+							_ => {
+								for channel in 0..3 {
+									destination[channel] = div_255(source[channel] as u32 * alpha + destination[channel] as u32 * (255 - alpha)) as u8;
+								}
+								destination[3] = (alpha + div_255(destination[3] as u32 * (255 - alpha))) as u8;
+							}
+						}
+					}
+					BlendMode::PremultipliedAlpha => {
+						let inverse = 255 - source[3] as u32;
+						for channel in 0..4 {
+							destination[channel] = (source[channel] as u32 + div_255(destination[channel] as u32 * inverse)).min(255) as u8;
+						}
+					}
+				}
+			}
+		}
+	}
+}
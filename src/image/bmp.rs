@@ -0,0 +1,230 @@
+use super::{Image, ImageError, Reader, pixel_count};
+
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+pub(super) fn decode(data: &[u8]) -> Result<Image, ImageError> {
+	let mut reader = Reader::new(data);
+	if reader.bytes(2)? != b"BM" {
+		return Err(ImageError::Malformed(String::from("Missing BMP signature.")));
+	}
+	reader.bytes(8)?;
+	let pixel_offset = reader.u32()? as usize;
+	
+	let header_size = reader.u32()?;
+	let (width, height, bit_count, compression, colors_used) = match header_size {
+		12 => {
+			let width = reader.u16()? as i32;
+			let height = reader.u16()? as i32;
+			reader.u16()?;
+			(width, height, reader.u16()?, BI_RGB, 0)
+		}
+		40 | 52 | 56 | 108 | 124 => {
+			let width = reader.i32()?;
+			let height = reader.i32()?;
+			reader.u16()?;
+			let bit_count = reader.u16()?;
+			let compression = reader.u32()?;
+			reader.bytes(12)?;
+			let colors_used = reader.u32()?;
+			reader.u32()?;
+			(width, height, bit_count, compression, colors_used)
+		}
+		_ => return Err(ImageError::Unsupported(format!("BMP header size {header_size}.")))
+	};
+	
+	if width <= 0 || height == 0 || height == i32::MIN {
+		return Err(ImageError::Malformed(format!("BMP size {width}x{height}.")));
+	}
+	let top_down = height < 0;
+	let width = width as u32;
+	let height = height.unsigned_abs();
+	pixel_count(width as u64, height as u64)?;
+	
+	// Channel masks for 16 and 32 bit images, bitfields either follow a 40 byte header or are part of a longer one.
+	let masks = match (compression, bit_count) {
+		(BI_BITFIELDS | BI_ALPHABITFIELDS, 16 | 32) => {
+			let mut mask_reader = Reader::at(data, 14 + 40)?;
+			let red = mask_reader.u32()?;
+			let green = mask_reader.u32()?;
+			let blue = mask_reader.u32()?;
+			let alpha = if header_size >= 56 || compression == BI_ALPHABITFIELDS { mask_reader.u32()? } else { 0 };
+			[red, green, blue, alpha]
+		}
+		(BI_RGB, 16) => [0x7C00, 0x03E0, 0x001F, 0],
+		(BI_RGB, 32) => [0x00FF0000, 0x0000FF00, 0x000000FF, 0xFF000000],
+		(BI_RGB, 1 | 4 | 8 | 24) => [0; 4],
+		(1 | 2, _) => return Err(ImageError::Unsupported(String::from("Run length encoded BMP."))),
+		_ => return Err(ImageError::Unsupported(format!("BMP with {bit_count} bits per pixel and compression {compression}.")))
+	};
+	
+	let palette = if bit_count <= 8 {
+		let entry_size = if header_size == 12 { 3 } else { 4 };
+		let count = match colors_used {
+			0 => 1usize << bit_count,
+			count => (count as usize).min(1 << bit_count)
+		};
+		let mut palette_reader = Reader::at(data, 14 + header_size as usize)?;
+		let mut palette = Vec::with_capacity(count);
+		for _ in 0..count {
+			let entry = palette_reader.bytes(entry_size)?;
+			palette.push([entry[0], entry[1], entry[2], 255]);
+		}
+		palette
+	} else {
+		Vec::new()
+	};
+	
+	let stride = (bit_count as usize * width as usize).div_ceil(32) * 4;
+	let rows = Reader::at(data, pixel_offset)?.bytes(stride * height as usize)?;
+	
+	let mut image = Image::new(width, height)?;
+	let mut has_alpha = false;
+	for y in 0..height {
+		let source_row = if top_down { y } else { height - 1 - y } as usize;
+		let row = &rows[source_row * stride..(source_row + 1) * stride];
+		let destination = &mut image.pixels[4 * y as usize * width as usize..4 * (y as usize + 1) * width as usize];
+		
+		for (x, pixel) in destination.chunks_exact_mut(4).enumerate() {
+			let bgra = match bit_count {
+				1 | 4 | 8 => {
+					let bit = x * bit_count as usize;
+					let index = (row[bit / 8] >> (8 - bit_count as usize - bit % 8)) & ((1u16 << bit_count) - 1) as u8;
+					*palette.get(index as usize).ok_or_else(|| ImageError::Malformed(format!("BMP palette index {index} out of range.")))?
+				}
+				24 => [row[3 * x], row[3 * x + 1], row[3 * x + 2], 255],
+				_ => {
+					let value = match bit_count {
+						16 => u16::from_le_bytes([row[2 * x], row[2 * x + 1]]) as u32,
+						_ => u32::from_le_bytes([row[4 * x], row[4 * x + 1], row[4 * x + 2], row[4 * x + 3]])
+					};
+					let alpha = masked_channel(value, masks[3]);
+					has_alpha |= masks[3] != 0 && alpha != 0;
+					[masked_channel(value, masks[2]), masked_channel(value, masks[1]), masked_channel(value, masks[0]), alpha]
+				}
+			};
+			pixel.copy_from_slice(&bgra);
+		}
+	}
+	
+	// Plenty of writers leave the alpha byte at zero, an image with no alpha at all is treated as opaque.
+	if !has_alpha {
+		image.pixels.chunks_exact_mut(4).for_each(|pixel| pixel[3] = 255);
+	}
+	Ok(image)
+}
+
+// Decodes a BMP without its file header, the form the clipboard uses.
+pub(super) fn decode_packed(data: &[u8]) -> Result<Image, ImageError> {
+	let mut reader = Reader::new(data);
+	let header_size = reader.u32()?;
+	let (bit_count, compression, colors_used) = match header_size {
+		12 => {
+			reader.bytes(6)?;
+			(reader.u16()?, BI_RGB, 0)
+		}
+		_ => {
+			reader.bytes(10)?;
+			let bit_count = reader.u16()?;
+			let compression = reader.u32()?;
+			reader.bytes(12)?;
+			(bit_count, compression, reader.u32()?)
+		}
+	};
+	
+	// The pixels follow the header, the masks of a 40 byte header and the palette, which the file header would otherwise point past.
+	let masks_size = match (header_size, compression) {
+		(40, BI_BITFIELDS) => 12,
+		(40, BI_ALPHABITFIELDS) => 16,
+		_ => 0
+	};
+	let palette_size = match (bit_count, colors_used) {
+		(1 | 4 | 8, 0) => 1 << bit_count,
+		(1 | 4 | 8, count) => (count as usize).min(1 << bit_count),
+		(_, count) => count as usize
+	} * if header_size == 12 { 3 } else { 4 };
+	let pixel_offset = 14 + header_size as usize + masks_size + palette_size;
+	
+	let mut file = Vec::with_capacity(14 + data.len());
+	file.extend_from_slice(b"BM");
+	file.extend_from_slice(&((14 + data.len()) as u32).to_le_bytes());
+	file.extend_from_slice(&[0; 4]);
+	file.extend_from_slice(&(pixel_offset as u32).to_le_bytes());
+	file.extend_from_slice(data);
+	decode(&file)
+}
+
+// Extracts the channel selected by `mask` and scales it to 8 bits.
+fn masked_channel(value: u32, mask: u32) -> u8 {
+	if mask == 0 {
+		return 0;
+	}
+	let shift = mask.trailing_zeros();
+	let max = mask >> shift;
+	(((value & mask) >> shift) as u64 * 255 / max as u64) as u8
+}
+
+// Writes a bottom-up 24 bit BMP, the variant every reader understands.
+pub(super) fn encode(image: &Image) -> Vec<u8> {
+	let stride = (3 * image.width as usize).div_ceil(4) * 4;
+	let pixel_offset = 14 + 40;
+	let file_size = pixel_offset + stride * image.height as usize;
+	
+	let mut output = Vec::with_capacity(file_size);
+	output.extend_from_slice(b"BM");
+	output.extend_from_slice(&(file_size as u32).to_le_bytes());
+	output.extend_from_slice(&[0; 4]);
+	output.extend_from_slice(&(pixel_offset as u32).to_le_bytes());
+	
+	output.extend_from_slice(&40u32.to_le_bytes());
+	output.extend_from_slice(&(image.width as i32).to_le_bytes());
+	output.extend_from_slice(&(image.height as i32).to_le_bytes());
+	output.extend_from_slice(&1u16.to_le_bytes());
+	output.extend_from_slice(&24u16.to_le_bytes());
+	output.extend_from_slice(&BI_RGB.to_le_bytes());
+	output.extend_from_slice(&((stride * image.height as usize) as u32).to_le_bytes());
+	// 2835 pixels per meter is 72 DPI.
+	output.extend_from_slice(&2835i32.to_le_bytes());
+	output.extend_from_slice(&2835i32.to_le_bytes());
+	output.extend_from_slice(&[0; 8]);
+	
+	for row in image.pixels.chunks_exact((4 * image.width as usize).max(1)).rev() {
+		let start = output.len();
+		for bgra in row.chunks_exact(4) {
+			output.extend_from_slice(&bgra[..3]);
+		}
+		output.resize(start + stride, 0);
+	}
+	output
+}
+
+// Packed 32 bit BMP with a version 5 header and an alpha mask, as the clipboard's CF_DIBV5 format.
+pub(super) fn encode_dib_v5(image: &Image) -> Vec<u8> {
+	let image_size = 4 * image.width as usize * image.height as usize;
+	let mut output = Vec::with_capacity(124 + image_size);
+	output.extend_from_slice(&124u32.to_le_bytes());
+	output.extend_from_slice(&(image.width as i32).to_le_bytes());
+	output.extend_from_slice(&(image.height as i32).to_le_bytes());
+	output.extend_from_slice(&1u16.to_le_bytes());
+	output.extend_from_slice(&32u16.to_le_bytes());
+	output.extend_from_slice(&BI_BITFIELDS.to_le_bytes());
+	output.extend_from_slice(&(image_size as u32).to_le_bytes());
+	output.extend_from_slice(&2835i32.to_le_bytes());
+	output.extend_from_slice(&2835i32.to_le_bytes());
+	output.extend_from_slice(&[0; 8]);
+	for mask in [0x00FF0000u32, 0x0000FF00, 0x000000FF, 0xFF000000] {
+		output.extend_from_slice(&mask.to_le_bytes());
+	}
+	// sRGB color space, then unused endpoints and gammas, then the rendering intent for images.
+	output.extend_from_slice(b"BGRs");
+	output.extend_from_slice(&[0; 48]);
+	output.extend_from_slice(&4u32.to_le_bytes());
+	output.extend_from_slice(&[0; 12]);
+	
+	for row in image.pixels.chunks_exact((4 * image.width as usize).max(1)).rev() {
+		output.extend_from_slice(row);
+	}
+	output
+}
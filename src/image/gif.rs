@@ -0,0 +1,215 @@
+use std::{collections::HashMap, io::Write, time::Duration};
+
+use super::{Image, ImageError};
+
+
+// Writes an endlessly looping animated GIF, every frame gets its own 256 color palette.
+pub struct GifEncoder<W: Write> {
+	writer: W,
+	width: u16,
+	height: u16
+}
+impl<W: Write> GifEncoder<W> {
+	pub fn new(mut writer: W, width: u16, height: u16) -> Result<Self, ImageError> {
+		let mut header = b"GIF89a".to_vec();
+		header.extend_from_slice(&width.to_le_bytes());
+		header.extend_from_slice(&height.to_le_bytes());
+		// No global color table, background color 0, square pixels.
+		header.extend_from_slice(&[0, 0, 0]);
+		header.extend_from_slice(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00");
+		writer.write_all(&header).map_err(|err| ImageError::Io(format!("Error writing GIF: {err}")))?;
+		Ok(Self { writer, width, height })
+	}
+	
+	pub fn width(&self) -> u16 {
+		self.width
+	}
+	
+	pub fn height(&self) -> u16 {
+		self.height
+	}
+	
+	// GIF delays are counted in hundredths of a second.
+	pub fn add_frame(&mut self, image: &Image, delay: Duration) -> Result<(), ImageError> {
+		if image.width != self.width as u32 || image.height != self.height as u32 {
+			return Err(ImageError::Malformed(format!("Frame of {}x{} in a {}x{} GIF.", image.width, image.height, self.width, self.height)));
+		}
+		let (palette, indices) = quantize(image);
+		let delay = ((delay.as_millis() + 5) / 10).min(u16::MAX as u128) as u16;
+		
+		let mut frame = vec![0x21, 0xF9, 0x04, 0x04];
+		frame.extend_from_slice(&delay.to_le_bytes());
+		frame.extend_from_slice(&[0, 0]);
+		
+		frame.push(0x2C);
+		frame.extend_from_slice(&[0, 0, 0, 0]);
+		frame.extend_from_slice(&self.width.to_le_bytes());
+		frame.extend_from_slice(&self.height.to_le_bytes());
+		// Local color table of 2^8 entries.
+		frame.push(0x87);
+		for index in 0..256 {
+			frame.extend_from_slice(&palette.get(index).copied().unwrap_or([0; 3]));
+		}
+		
+		frame.push(8);
+		for block in lzw(&indices, 8).chunks(255) {
+			frame.push(block.len() as u8);
+			frame.extend_from_slice(block);
+		}
+		frame.push(0);
+		self.writer.write_all(&frame).map_err(|err| ImageError::Io(format!("Error writing GIF: {err}")))
+	}
+	
+	pub fn finish(mut self) -> Result<W, ImageError> {
+		self.writer.write_all(&[0x3B]).and_then(|_| self.writer.flush()).map_err(|err| ImageError::Io(format!("Error writing GIF: {err}")))?;
+		Ok(self.writer)
+	}
+}
+
+
+// Reduces an image to at most 256 RGB colors. Images that already have few colors keep them exactly, others go through median cut.
+fn quantize(image: &Image) -> (Vec<[u8; 3]>, Vec<u8>) {
+	let mut exact: HashMap<[u8; 3], u8> = HashMap::new();
+	let mut palette = Vec::new();
+	let mut indices = Vec::with_capacity(image.pixels.len() / 4);
+	for bgra in image.pixels.chunks_exact(4) {
+		let rgb = [bgra[2], bgra[1], bgra[0]];
+		let next = palette.len();
+		match exact.get(&rgb) {
+			Some(&index) => indices.push(index),
+			None if next < 256 => {
+				exact.insert(rgb, next as u8);
+				palette.push(rgb);
+				indices.push(next as u8);
+			}
+			None => return median_cut(image)
+		}
+	}
+	(palette, indices)
+}
+
+fn key(bgra: &[u8]) -> usize {
+	((bgra[2] as usize >> 3) << 10) | ((bgra[1] as usize >> 3) << 5) | (bgra[0] as usize >> 3)
+}
+
+fn channel(key: usize, axis: usize) -> u32 {
+	((key >> (10 - 5 * axis)) & 31) as u32
+}
+
+fn median_cut(image: &Image) -> (Vec<[u8; 3]>, Vec<u8>) {
+	// Colors are counted at 5 bits per channel, which keeps the histogram small.
+	let mut histogram = vec![0u32; 1 << 15];
+	for bgra in image.pixels.chunks_exact(4) {
+		histogram[key(bgra)] += 1;
+	}
+	let mut colors: Vec<(usize, u32)> = histogram.iter().enumerate().filter(|(_, &count)| count > 0).map(|(key, &count)| (key, count)).collect();
+	
+	// Boxes are ranges of `colors`, the widest box along any channel gets split at its median pixel.
+	let mut boxes = vec![(0, colors.len())];
+	while boxes.len() < 256 {
+		let widest = boxes.iter().enumerate()
+			.filter(|(_, &(start, end))| end - start > 1)
+			.map(|(index, &(start, end))| {
+				let (axis, range) = (0..3).map(|axis| {
+					let values = colors[start..end].iter().map(|&(key, _)| channel(key, axis));
+					(axis, values.clone().max().unwrap() - values.min().unwrap())
+				}).max_by_key(|&(_, range)| range).unwrap();
+				(index, axis, range)
+			})
+			.max_by_key(|&(_, _, range)| range);
+		let Some((index, axis, _)) = widest else {
+			break;
+		};
+		
+		let (start, end) = boxes[index];
+		colors[start..end].sort_unstable_by_key(|&(key, _)| channel(key, axis));
+		let total: u64 = colors[start..end].iter().map(|&(_, count)| count as u64).sum();
+		let mut running = 0;
+		let mut split = start + 1;
+		for (offset, &(_, count)) in colors[start..end - 1].iter().enumerate() {
+			running += count as u64;
+			split = start + offset + 1;
+			if 2 * running >= total {
+				break;
+			}
+		}
+		boxes[index] = (start, split);
+		boxes.push((split, end));
+	}
+	
+	let mut lookup = vec![0u8; 1 << 15];
+	let palette: Vec<[u8; 3]> = boxes.iter().enumerate().map(|(index, &(start, end))| {
+		let mut sums = [0u64; 3];
+		let mut total = 0u64;
+		for &(key, count) in &colors[start..end] {
+			lookup[key] = index as u8;
+			for (axis, sum) in sums.iter_mut().enumerate() {
+				let value = channel(key, axis);
+				*sum += ((value << 3) | (value >> 2)) as u64 * count as u64;
+			}
+			total += count as u64;
+		}
+		sums.map(|sum| (sum / total) as u8)
+	}).collect();
+	
+	(palette, image.pixels.chunks_exact(4).map(|bgra| lookup[key(bgra)]).collect())
+}
+
+
+// Variable length LZW as GIF wants it: codes packed least significant bit first, the table reset when it fills up.
+fn lzw(indices: &[u8], minimum_code_size: u8) -> Vec<u8> {
+	let clear_code = 1u16 << minimum_code_size;
+	let end_code = clear_code + 1;
+	let mut output = Vec::new();
+	let mut buffer = 0u32;
+	let mut buffered_bits = 0;
+	let mut code_size = minimum_code_size + 1;
+	let mut next_code = end_code + 1;
+	let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+	
+	// Codes grow a bit once the table has used up the current size, checked after each code like decoders do.
+	let mut emit = |code: u16, code_size: &mut u8, next_code: u16| {
+		buffer |= (code as u32) << buffered_bits;
+		buffered_bits += *code_size;
+		while buffered_bits >= 8 {
+			output.push(buffer as u8);
+			buffer >>= 8;
+			buffered_bits -= 8;
+		}
+		if next_code >= 1 << *code_size && *code_size < 12 {
+			*code_size += 1;
+		}
+	};
+	
+	emit(clear_code, &mut code_size, next_code);
+	let mut prefix: Option<u16> = None;
+	for &index in indices {
+		let Some(current) = prefix else {
+			prefix = Some(index as u16);
+			continue;
+		};
+		if let Some(&code) = table.get(&(current, index)) {
+			prefix = Some(code);
+			continue;
+		}
+		emit(current, &mut code_size, next_code);
+		if next_code >= 4095 {
+			emit(clear_code, &mut code_size, next_code);
+			table.clear();
+			code_size = minimum_code_size + 1;
+			next_code = end_code + 1;
+		} else {
+			table.insert((current, index), next_code);
+			next_code += 1;
+		}
+		prefix = Some(index as u16);
+	}
+	if let Some(current) = prefix {
+		emit(current, &mut code_size, next_code);
+	}
+	emit(end_code, &mut code_size, next_code);
+	if buffered_bits > 0 {
+		output.push(buffer as u8);
+	}
+	output
+}
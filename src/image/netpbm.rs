@@ -0,0 +1,143 @@
+use super::{Image, ImageError, pixel_count};
+
+
+struct Tokens<'a> {
+	data: &'a [u8],
+	position: usize
+}
+impl Tokens<'_> {
+	fn skip_whitespace_and_comments(&mut self) {
+		while let Some(&byte) = self.data.get(self.position) {
+			if byte == b'#' {
+				while self.data.get(self.position).is_some_and(|&byte| byte != b'\n' && byte != b'\r') {
+					self.position += 1;
+				}
+			} else if byte.is_ascii_whitespace() {
+				self.position += 1;
+			} else {
+				break;
+			}
+		}
+	}
+	
+	fn number(&mut self) -> Result<u32, ImageError> {
+		self.skip_whitespace_and_comments();
+		let start = self.position;
+		while self.data.get(self.position).is_some_and(u8::is_ascii_digit) {
+			self.position += 1;
+		}
+		if start == self.position {
+			return match self.data.get(self.position) {
+				Some(&byte) => Err(ImageError::Malformed(format!("Expected a number, found {:?}.", byte as char))),
+				None => Err(ImageError::UnexpectedEnd)
+			};
+		}
+		std::str::from_utf8(&self.data[start..self.position]).ok()
+			.and_then(|digits| digits.parse().ok())
+			.ok_or_else(|| ImageError::Malformed(String::from("Number out of range.")))
+	}
+	
+	// Plain PBM bits don't need to be separated by whitespace.
+	fn bit(&mut self) -> Result<bool, ImageError> {
+		self.skip_whitespace_and_comments();
+		let bit = match self.data.get(self.position) {
+			Some(b'0') => false,
+			Some(b'1') => true,
+			Some(&byte) => return Err(ImageError::Malformed(format!("Expected 0 or 1, found {:?}.", byte as char))),
+			None => return Err(ImageError::UnexpectedEnd)
+		};
+		self.position += 1;
+		Ok(bit)
+	}
+}
+
+pub(super) fn decode(data: &[u8]) -> Result<Image, ImageError> {
+	let kind = match data {
+		[b'P', kind @ b'1'..=b'6', ..] => kind - b'0',
+		_ => return Err(ImageError::Malformed(String::from("Missing Netpbm signature.")))
+	};
+	let mut tokens = Tokens { data, position: 2 };
+	
+	let width = tokens.number()?;
+	let height = tokens.number()?;
+	let max_value = match kind {
+		1 | 4 => 1,
+		_ => tokens.number()?
+	};
+	if width == 0 || height == 0 {
+		return Err(ImageError::Malformed(format!("Netpbm size {width}x{height}.")));
+	}
+	if max_value == 0 || max_value > 65535 {
+		return Err(ImageError::Malformed(format!("Netpbm maximum value {max_value}.")));
+	}
+	let count = pixel_count(width as u64, height as u64)?;
+	let mut image = Image::new(width, height)?;
+	
+	let scale = |value: u32| -> Result<u8, ImageError> {
+		if value > max_value {
+			return Err(ImageError::Malformed(format!("Sample {value} is above the maximum value {max_value}.")));
+		}
+		Ok(((value * 255 + max_value / 2) / max_value) as u8)
+	};
+	
+	match kind {
+		1 => {
+			for pixel in image.pixels.chunks_exact_mut(4) {
+				let value = if tokens.bit()? { 0 } else { 255 };
+				pixel.copy_from_slice(&[value, value, value, 255]);
+			}
+		}
+		2 | 3 => {
+			for pixel in image.pixels.chunks_exact_mut(4) {
+				let (red, green, blue) = match kind {
+					2 => {
+						let gray = scale(tokens.number()?)?;
+						(gray, gray, gray)
+					}
+					_ => (scale(tokens.number()?)?, scale(tokens.number()?)?, scale(tokens.number()?)?)
+				};
+				pixel.copy_from_slice(&[blue, green, red, 255]);
+			}
+		}
+		_ => {
+			// Binary formats have exactly one whitespace byte between the header and the samples.
+			let start = tokens.position + 1;
+			let samples = data.get(start..).ok_or(ImageError::UnexpectedEnd)?;
+			
+			if kind == 4 {
+				let stride = (width as usize).div_ceil(8);
+				if samples.len() < stride * height as usize {
+					return Err(ImageError::UnexpectedEnd);
+				}
+				for (i, pixel) in image.pixels.chunks_exact_mut(4).enumerate() {
+					let (x, y) = (i % width as usize, i / width as usize);
+					let value = if samples[y * stride + x / 8] & (0x80 >> (x % 8)) != 0 { 0 } else { 255 };
+					pixel.copy_from_slice(&[value, value, value, 255]);
+				}
+			} else {
+				let channels = if kind == 5 { 1 } else { 3 };
+				let sample_size = if max_value < 256 { 1 } else { 2 };
+				if samples.len() < count * channels * sample_size {
+					return Err(ImageError::UnexpectedEnd);
+				}
+				let sample = |index: usize| -> u32 {
+					match sample_size {
+						1 => samples[index] as u32,
+						_ => u16::from_be_bytes([samples[2 * index], samples[2 * index + 1]]) as u32
+					}
+				};
+				for (i, pixel) in image.pixels.chunks_exact_mut(4).enumerate() {
+					let (red, green, blue) = match channels {
+						1 => {
+							let gray = scale(sample(i))?;
+							(gray, gray, gray)
+						}
+						_ => (scale(sample(3 * i))?, scale(sample(3 * i + 1))?, scale(sample(3 * i + 2))?)
+					};
+					pixel.copy_from_slice(&[blue, green, red, 255]);
+				}
+			}
+		}
+	}
+	Ok(image)
+}
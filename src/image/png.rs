@@ -0,0 +1,324 @@
+use super::{Image, ImageError, pixel_count, zlib};
+
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PngFormat {
+	Rgba8,
+	Rgb8
+}
+
+fn crc32(bytes: impl IntoIterator<Item = u8>) -> u32 {
+	let mut crc = 0xFFFFFFFFu32;
+	for byte in bytes {
+		crc ^= byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+	u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// Passes of the Adam7 interlace as (x start, y start, x step, y step).
+const ADAM7: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+struct Header {
+	width: usize,
+	height: usize,
+	bit_depth: u8,
+	color_type: u8,
+	interlaced: bool
+}
+impl Header {
+	fn channels(&self) -> usize {
+		match self.color_type {
+			0 | 3 => 1,
+			2 => 3,
+			4 => 2,
+			_ => 4
+		}
+	}
+	
+	fn bits_per_pixel(&self) -> usize {
+		self.channels() * self.bit_depth as usize
+	}
+	
+	fn stride(&self, width: usize) -> usize {
+		(width * self.bits_per_pixel()).div_ceil(8)
+	}
+}
+
+pub(super) fn decode(data: &[u8]) -> Result<Image, ImageError> {
+	if !data.starts_with(SIGNATURE) {
+		return Err(ImageError::Malformed(String::from("Missing PNG signature.")));
+	}
+	
+	let mut header = None;
+	let mut palette: Vec<[u8; 4]> = Vec::new();
+	let mut transparency: Option<Vec<u8>> = None;
+	let mut compressed = Vec::new();
+	
+	let mut position = SIGNATURE.len();
+	loop {
+		let chunk_header = data.get(position..position + 8).ok_or(ImageError::UnexpectedEnd)?;
+		let length = be_u32(chunk_header) as usize;
+		let kind = &chunk_header[4..8];
+		let chunk_end = position.checked_add(12 + length).filter(|&end| end <= data.len()).ok_or(ImageError::UnexpectedEnd)?;
+		let chunk = &data[position + 8..position + 8 + length];
+		if crc32(data[position + 4..position + 8 + length].iter().copied()) != be_u32(&data[chunk_end - 4..]) {
+			return Err(ImageError::Malformed(format!("Checksum mismatch in {} chunk.", String::from_utf8_lossy(kind))));
+		}
+		position = chunk_end;
+		
+		match kind {
+			b"IHDR" => {
+				if chunk.len() != 13 {
+					return Err(ImageError::Malformed(String::from("IHDR chunk has the wrong size.")));
+				}
+				let parsed = Header {
+					width: be_u32(chunk) as usize,
+					height: be_u32(&chunk[4..]) as usize,
+					bit_depth: chunk[8],
+					color_type: chunk[9],
+					interlaced: match chunk[12] {
+						0 => false,
+						1 => true,
+						method => return Err(ImageError::Unsupported(format!("PNG interlace method {method}.")))
+					}
+				};
+				let valid_depths: &[u8] = match parsed.color_type {
+					0 => &[1, 2, 4, 8, 16],
+					3 => &[1, 2, 4, 8],
+					2 | 4 | 6 => &[8, 16],
+					color_type => return Err(ImageError::Malformed(format!("PNG color type {color_type}.")))
+				};
+				if !valid_depths.contains(&parsed.bit_depth) {
+					return Err(ImageError::Malformed(format!("PNG bit depth {} for color type {}.", parsed.bit_depth, parsed.color_type)));
+				}
+				if chunk[10] != 0 || chunk[11] != 0 {
+					return Err(ImageError::Unsupported(String::from("PNG compression or filter method.")));
+				}
+				if parsed.width == 0 || parsed.height == 0 {
+					return Err(ImageError::Malformed(format!("PNG size {}x{}.", parsed.width, parsed.height)));
+				}
+				pixel_count(parsed.width as u64, parsed.height as u64)?;
+				header = Some(parsed);
+			}
+			b"PLTE" => {
+				if !chunk.len().is_multiple_of(3) || chunk.len() > 256 * 3 {
+					return Err(ImageError::Malformed(String::from("PLTE chunk has the wrong size.")));
+				}
+				palette = chunk.chunks_exact(3).map(|rgb| [rgb[2], rgb[1], rgb[0], 255]).collect();
+			}
+			b"tRNS" => transparency = Some(chunk.to_vec()),
+			b"IDAT" => compressed.extend_from_slice(chunk),
+			b"IEND" => break,
+			_ => {
+				// Unknown critical chunks (uppercase first letter) change how the image must be read.
+				if kind[0].is_ascii_uppercase() {
+					return Err(ImageError::Unsupported(format!("PNG chunk {}.", String::from_utf8_lossy(kind))));
+				}
+			}
+		}
+	}
+	
+	let header = header.ok_or_else(|| ImageError::Malformed(String::from("Missing IHDR chunk.")))?;
+	if header.color_type == 3 {
+		if palette.is_empty() {
+			return Err(ImageError::Malformed(String::from("Missing PLTE chunk.")));
+		}
+		if let Some(alphas) = &transparency {
+			for (entry, &alpha) in palette.iter_mut().zip(alphas) {
+				entry[3] = alpha;
+			}
+		}
+	}
+	// Gray and RGB images may name one color that is fully transparent.
+	let transparent_color: Option<Vec<u16>> = match (header.color_type, &transparency) {
+		(0 | 2, Some(values)) if values.len() >= 2 * header.channels() => Some(values.chunks_exact(2).take(header.channels()).map(|value| u16::from_be_bytes([value[0], value[1]])).collect()),
+		_ => None
+	};
+	
+	let mut image = Image::new(header.width as u32, header.height as u32)?;
+	let passes: Vec<(usize, usize, usize, usize)> = if header.interlaced { ADAM7.to_vec() } else { vec![(0, 0, 1, 1)] };
+	// Every row of every pass starts with its filter type byte.
+	let expected_length = passes.iter().map(|&(x_start, y_start, x_step, y_step)| {
+		let pass_width = header.width.saturating_sub(x_start).div_ceil(x_step);
+		let pass_height = header.height.saturating_sub(y_start).div_ceil(y_step);
+		if pass_width == 0 || pass_height == 0 { 0 } else { (header.stride(pass_width) + 1) * pass_height }
+	}).sum();
+	let filtered = zlib::inflate(&compressed, expected_length)?;
+	
+	let mut offset = 0;
+	for (x_start, y_start, x_step, y_step) in passes {
+		let pass_width = header.width.saturating_sub(x_start).div_ceil(x_step);
+		let pass_height = header.height.saturating_sub(y_start).div_ceil(y_step);
+		if pass_width == 0 || pass_height == 0 {
+			continue;
+		}
+		let stride = header.stride(pass_width);
+		let size = (stride + 1) * pass_height;
+		let pass_data = filtered.get(offset..offset + size).ok_or(ImageError::UnexpectedEnd)?;
+		offset += size;
+		
+		let rows = unfilter(pass_data, stride, header.bits_per_pixel().div_ceil(8))?;
+		for (row_index, row) in rows.chunks_exact(stride).enumerate() {
+			let y = y_start + row_index * y_step;
+			for column in 0..pass_width {
+				let x = x_start + column * x_step;
+				let bgra = pixel(&header, row, column, &palette, transparent_color.as_deref())?;
+				image.set_pixel(x as u32, y as u32, bgra);
+			}
+		}
+	}
+	Ok(image)
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+	let p = a as i16 + b as i16 - c as i16;
+	let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+	if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+}
+
+// Reverses the per row filters, `data` holds each row prefixed with its filter type.
+fn unfilter(data: &[u8], stride: usize, bytes_per_pixel: usize) -> Result<Vec<u8>, ImageError> {
+	let rows = data.len() / (stride + 1);
+	let mut output = vec![0u8; rows * stride];
+	for row in 0..rows {
+		let filter = data[row * (stride + 1)];
+		let source = &data[row * (stride + 1) + 1..(row + 1) * (stride + 1)];
+		let (previous_rows, current) = output.split_at_mut(row * stride);
+		let previous = if row == 0 { None } else { Some(&previous_rows[(row - 1) * stride..]) };
+		let current = &mut current[..stride];
+		
+		for i in 0..stride {
+			let left = if i >= bytes_per_pixel { current[i - bytes_per_pixel] } else { 0 };
+			let up = previous.map_or(0, |previous| previous[i]);
+			let up_left = if i >= bytes_per_pixel { previous.map_or(0, |previous| previous[i - bytes_per_pixel]) } else { 0 };
+			current[i] = source[i].wrapping_add(match filter {
+				0 => 0,
+				1 => left,
+				2 => up,
+				3 => ((left as u16 + up as u16) / 2) as u8,
+				4 => paeth(left, up, up_left),
+				_ => return Err(ImageError::Malformed(format!("PNG filter type {filter}.")))
+			});
+		}
+	}
+	Ok(output)
+}
+
+fn sample(header: &Header, row: &[u8], index: usize) -> u16 {
+	match header.bit_depth {
+		16 => u16::from_be_bytes([row[2 * index], row[2 * index + 1]]),
+		8 => row[index] as u16,
+		depth => {
+			let bit = index * depth as usize;
+			((row[bit / 8] >> (8 - depth as usize - bit % 8)) & ((1u16 << depth) - 1) as u8) as u16
+		}
+	}
+}
+
+fn pixel(header: &Header, row: &[u8], column: usize, palette: &[[u8; 4]], transparent_color: Option<&[u16]>) -> Result<[u8; 4], ImageError> {
+	let channels = header.channels();
+	let samples: Vec<u16> = (0..channels).map(|channel| sample(header, row, column * channels + channel)).collect();
+	let max = (1u32 << header.bit_depth) - 1;
+	let scale = |value: u16| (value as u32 * 255 / max) as u8;
+	let transparent = transparent_color == Some(&samples[..]);
+	
+	Ok(match header.color_type {
+		0 => {
+			let gray = scale(samples[0]);
+			[gray, gray, gray, if transparent { 0 } else { 255 }]
+		}
+		2 => [scale(samples[2]), scale(samples[1]), scale(samples[0]), if transparent { 0 } else { 255 }],
+		3 => *palette.get(samples[0] as usize).ok_or_else(|| ImageError::Malformed(format!("PNG palette index {} out of range.", samples[0])))?,
+		4 => {
+			let gray = scale(samples[0]);
+			[gray, gray, gray, scale(samples[1])]
+		}
+		_ => [scale(samples[2]), scale(samples[1]), scale(samples[0]), scale(samples[3])]
+	})
+}
+
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+	output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+	output.extend_from_slice(kind);
+	output.extend_from_slice(data);
+	output.extend_from_slice(&crc32(kind.iter().chain(data).copied()).to_be_bytes());
+}
+
+// Filters each row with whichever filter gives the smallest sum of absolute differences, the usual heuristic.
+fn filter_rows(raw: &[u8], stride: usize, bytes_per_pixel: usize) -> Vec<u8> {
+	let rows = raw.len() / stride.max(1);
+	let mut output = Vec::with_capacity(rows * (stride + 1));
+	let mut candidate = vec![0u8; stride];
+	let mut best = vec![0u8; stride];
+	
+	for row in 0..rows {
+		let current = &raw[row * stride..(row + 1) * stride];
+		let previous = if row == 0 { None } else { Some(&raw[(row - 1) * stride..row * stride]) };
+		let mut best_filter = 0;
+		let mut best_score = u64::MAX;
+		
+		for filter in 0..5u8 {
+			for i in 0..stride {
+				let left = if i >= bytes_per_pixel { current[i - bytes_per_pixel] } else { 0 };
+				let up = previous.map_or(0, |previous| previous[i]);
+				let up_left = if i >= bytes_per_pixel { previous.map_or(0, |previous| previous[i - bytes_per_pixel]) } else { 0 };
+				candidate[i] = current[i].wrapping_sub(match filter {
+					0 => 0,
+					1 => left,
+					2 => up,
+					3 => ((left as u16 + up as u16) / 2) as u8,
+					_ => paeth(left, up, up_left)
+				});
+			}
+			let score = candidate.iter().map(|&byte| (byte as i8).unsigned_abs() as u64).sum();
+			if score < best_score {
+				best_score = score;
+				best_filter = filter;
+				std::mem::swap(&mut best, &mut candidate);
+			}
+		}
+		output.push(best_filter);
+		output.extend_from_slice(&best);
+	}
+	output
+}
+
+// PNG has no empty images, IHDR requires a width and height of at least 1.
+pub(super) fn encode(image: &Image, format: PngFormat) -> Result<Vec<u8>, ImageError> {
+	if image.width == 0 || image.height == 0 {
+		return Err(ImageError::Unsupported(format!("PNG size {}x{}.", image.width, image.height)));
+	}
+	let (color_type, channels) = match format {
+		PngFormat::Rgba8 => (6u8, 4usize),
+		PngFormat::Rgb8 => (2u8, 3usize)
+	};
+	
+	let mut raw = Vec::with_capacity(image.width as usize * image.height as usize * channels);
+	for bgra in image.pixels.chunks_exact(4) {
+		raw.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]][..channels]);
+	}
+	let filtered = filter_rows(&raw, image.width as usize * channels, channels);
+	
+	let mut header = Vec::with_capacity(13);
+	header.extend_from_slice(&image.width.to_be_bytes());
+	header.extend_from_slice(&image.height.to_be_bytes());
+	header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+	
+	let mut output = SIGNATURE.to_vec();
+	write_chunk(&mut output, b"IHDR", &header);
+	for data in zlib::deflate(&filtered).chunks(1 << 20) {
+		write_chunk(&mut output, b"IDAT", data);
+	}
+	write_chunk(&mut output, b"IEND", &[]);
+	Ok(output)
+}
@@ -0,0 +1,124 @@
+use super::{Image, ImageError, Reader, pixel_count};
+
+
+pub(super) fn decode(data: &[u8]) -> Result<Image, ImageError> {
+	let mut reader = Reader::new(data);
+	let id_length = reader.u8()?;
+	let color_map_type = reader.u8()?;
+	let image_type = reader.u8()?;
+	let color_map_first = reader.u16()? as usize;
+	let color_map_length = reader.u16()? as usize;
+	let color_map_depth = reader.u8()?;
+	reader.bytes(4)?;
+	let width = reader.u16()? as u32;
+	let height = reader.u16()? as u32;
+	let depth = reader.u8()?;
+	let descriptor = reader.u8()?;
+	
+	let run_length_encoded = match image_type {
+		1..=3 => false,
+		9..=11 => true,
+		_ => return Err(ImageError::Unsupported(format!("TGA image type {image_type}.")))
+	};
+	let color_mapped = image_type & 7 == 1;
+	let grayscale = image_type & 7 == 3;
+	if color_map_type > 1 || (color_mapped && color_map_type != 1) {
+		return Err(ImageError::Malformed(format!("TGA color map type {color_map_type}.")));
+	}
+	if width == 0 || height == 0 {
+		return Err(ImageError::Malformed(format!("TGA size {width}x{height}.")));
+	}
+	let count = pixel_count(width as u64, height as u64)?;
+	
+	let alpha_bits = descriptor & 0x0F;
+	let right_to_left = descriptor & 0x10 != 0;
+	let top_to_bottom = descriptor & 0x20 != 0;
+	
+	reader.bytes(id_length as usize)?;
+	let color_map = match color_map_type {
+		1 => {
+			let entry_size = (color_map_depth as usize).div_ceil(8);
+			let mut color_map = vec![[0, 0, 0, 255]; color_map_first + color_map_length];
+			for entry in &mut color_map[color_map_first..] {
+				*entry = color(reader.bytes(entry_size)?, color_map_depth, alpha_bits)?;
+			}
+			color_map
+		}
+		_ => Vec::new()
+	};
+	
+	match (color_mapped, grayscale, depth) {
+		(true, _, 8 | 16) | (false, true, 8 | 16) | (false, false, 15 | 16 | 24 | 32) => (),
+		_ => return Err(ImageError::Unsupported(format!("TGA image type {image_type} with {depth} bits per pixel.")))
+	}
+	let pixel_size = (depth as usize).div_ceil(8);
+	
+	let to_bgra = |bytes: &[u8]| -> Result<[u8; 4], ImageError> {
+		if color_mapped {
+			let index = match pixel_size {
+				1 => bytes[0] as usize,
+				_ => u16::from_le_bytes([bytes[0], bytes[1]]) as usize
+			};
+			color_map.get(index).copied().ok_or_else(|| ImageError::Malformed(format!("TGA color map index {index} out of range.")))
+		} else if grayscale {
+			let alpha = if pixel_size == 2 { bytes[1] } else { 255 };
+			Ok([bytes[0], bytes[0], bytes[0], alpha])
+		} else {
+			color(bytes, depth, alpha_bits)
+		}
+	};
+	
+	// Checked before allocating, so a header alone can't ask for gigabytes. A packet covers at most 128 pixels with at least one pixel value.
+	let available = match run_length_encoded {
+		true => reader.remaining() / (1 + pixel_size) * 128,
+		false => reader.remaining() / pixel_size
+	};
+	if count > available {
+		return Err(ImageError::UnexpectedEnd);
+	}
+	let mut pixels = Vec::with_capacity(count);
+	if run_length_encoded {
+		while pixels.len() < count {
+			let packet = reader.u8()?;
+			let length = (packet & 0x7F) as usize + 1;
+			if packet & 0x80 != 0 {
+				let pixel = to_bgra(reader.bytes(pixel_size)?)?;
+				pixels.extend(std::iter::repeat_n(pixel, length));
+			} else {
+				for _ in 0..length {
+					pixels.push(to_bgra(reader.bytes(pixel_size)?)?);
+				}
+			}
+		}
+		// A packet may run past the last pixel, the extra pixels are ignored.
+		pixels.truncate(count);
+	} else {
+		for bytes in reader.bytes(count * pixel_size)?.chunks_exact(pixel_size) {
+			pixels.push(to_bgra(bytes)?);
+		}
+	}
+	
+	let mut image = Image::new(width, height)?;
+	for (i, pixel) in pixels.iter().enumerate() {
+		let (x, y) = (i as u32 % width, i as u32 / width);
+		let x = if right_to_left { width - 1 - x } else { x };
+		let y = if top_to_bottom { y } else { height - 1 - y };
+		image.set_pixel(x, y, *pixel);
+	}
+	Ok(image)
+}
+
+// Converts a true color pixel or color map entry, 32 and 16 bit colors only carry alpha if the descriptor says so.
+fn color(bytes: &[u8], depth: u8, alpha_bits: u8) -> Result<[u8; 4], ImageError> {
+	match depth {
+		15 | 16 => {
+			let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+			let expand = |channel: u16| ((channel & 0x1F) * 255 / 31) as u8;
+			let alpha = if depth == 16 && alpha_bits > 0 && value & 0x8000 == 0 { 0 } else { 255 };
+			Ok([expand(value), expand(value >> 5), expand(value >> 10), alpha])
+		}
+		24 => Ok([bytes[0], bytes[1], bytes[2], 255]),
+		32 => Ok([bytes[0], bytes[1], bytes[2], if alpha_bits > 0 { bytes[3] } else { 255 }]),
+		_ => Err(ImageError::Unsupported(format!("TGA color depth {depth}.")))
+	}
+}
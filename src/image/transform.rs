@@ -0,0 +1,202 @@
+use crate::Rect;
+
+use super::{Image, ImageError};
+
+
+// Maps image coordinates to buffer coordinates: x' = xx * x + xy * y + x0, y' = yx * x + yy * y + y0.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Affine {
+	pub xx: f64,
+	pub yx: f64,
+	pub xy: f64,
+	pub yy: f64,
+	pub x0: f64,
+	pub y0: f64
+}
+impl Affine {
+	pub fn identity() -> Self {
+		Self { xx: 1.0, yx: 0.0, xy: 0.0, yy: 1.0, x0: 0.0, y0: 0.0 }
+	}
+	
+	pub fn translation(x: f64, y: f64) -> Self {
+		Self { x0: x, y0: y, ..Self::identity() }
+	}
+	
+	pub fn scaling(x: f64, y: f64) -> Self {
+		Self { xx: x, yy: y, ..Self::identity() }
+	}
+	
+	// Clockwise on screen, since y grows downwards.
+	pub fn rotation(radians: f64) -> Self {
+		let (sin, cos) = radians.sin_cos();
+		Self { xx: cos, yx: sin, xy: -sin, yy: cos, ..Self::identity() }
+	}
+	
+	// The transform that applies `self` first and `next` after it.
+	pub fn then(&self, next: &Affine) -> Self {
+		Self {
+			xx: next.xx * self.xx + next.xy * self.yx,
+			yx: next.yx * self.xx + next.yy * self.yx,
+			xy: next.xx * self.xy + next.xy * self.yy,
+			yy: next.yx * self.xy + next.yy * self.yy,
+			x0: next.xx * self.x0 + next.xy * self.y0 + next.x0,
+			y0: next.yx * self.x0 + next.yy * self.y0 + next.y0
+		}
+	}
+	
+	pub fn inverse(&self) -> Option<Self> {
+		let determinant = self.xx * self.yy - self.xy * self.yx;
+		if determinant.abs() < 1e-12 || !determinant.is_finite() {
+			return None;
+		}
+		let (xx, xy, yx, yy) = (self.yy / determinant, -self.xy / determinant, -self.yx / determinant, self.xx / determinant);
+		Some(Self { xx, yx, xy, yy, x0: -(xx * self.x0 + xy * self.y0), y0: -(yx * self.x0 + yy * self.y0) })
+	}
+	
+	pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+		(self.xx * x + self.xy * y + self.x0, self.yx * x + self.yy * y + self.y0)
+	}
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum Filter {
+	Nearest,
+	#[default]
+	Bilinear,
+	Bicubic
+}
+
+// Catmull-Rom weights for the four taps around a sample at fraction `t` past the second tap.
+fn cubic_weights(t: f32) -> [f32; 4] {
+	let t2 = t * t;
+	let t3 = t2 * t;
+	[
+		(-t3 + 2.0 * t2 - t) / 2.0,
+		(3.0 * t3 - 5.0 * t2 + 2.0) / 2.0,
+		(-3.0 * t3 + 4.0 * t2 + t) / 2.0,
+		(t3 - t2) / 2.0
+	]
+}
+
+impl Image {
+	// Premultiplied color of the pixel at `x`, `y`, clamped to the edges.
+	fn premultiplied(&self, x: i64, y: i64) -> [f32; 4] {
+		let x = x.clamp(0, self.width as i64 - 1) as usize;
+		let y = y.clamp(0, self.height as i64 - 1) as usize;
+		let i = 4 * (y * self.width as usize + x);
+		let alpha = self.pixels[i + 3] as f32;
+		[self.pixels[i] as f32 * alpha / 255.0, self.pixels[i + 1] as f32 * alpha / 255.0, self.pixels[i + 2] as f32 * alpha / 255.0, alpha]
+	}
+	
+	fn sample(&self, u: f64, v: f64, filter: Filter) -> [f32; 4] {
+		match filter {
+			Filter::Nearest => self.premultiplied(u.floor() as i64, v.floor() as i64),
+			Filter::Bilinear | Filter::Bicubic => {
+				// Pixel centers sit at half coordinates.
+				let (u, v) = (u - 0.5, v - 0.5);
+				let (left, top) = (u.floor(), v.floor());
+				let (tx, ty) = ((u - left) as f32, (v - top) as f32);
+				let (left, top) = (left as i64, top as i64);
+				let (weights_x, weights_y, first) = match filter {
+					Filter::Bilinear => ([0.0, 1.0 - tx, tx, 0.0], [0.0, 1.0 - ty, ty, 0.0], 1),
+					_ => (cubic_weights(tx), cubic_weights(ty), 0)
+				};
+				
+				let mut sum = [0.0f32; 4];
+				for (row, weight_y) in weights_y.iter().enumerate().take(4 - first).skip(first) {
+					for (column, weight_x) in weights_x.iter().enumerate().take(4 - first).skip(first) {
+						let weight = weight_x * weight_y;
+						let pixel = self.premultiplied(left + column as i64 - 1, top + row as i64 - 1);
+						for channel in 0..4 {
+							sum[channel] += weight * pixel[channel];
+						}
+					}
+				}
+				// Bicubic overshoots, color can't exceed alpha in premultiplied form.
+				let alpha = sum[3].clamp(0.0, 255.0);
+				[sum[0].clamp(0.0, alpha), sum[1].clamp(0.0, alpha), sum[2].clamp(0.0, alpha), alpha]
+			}
+		}
+	}
+	
+	// Draws the image through `transform` and composites it over the buffer by its alpha. `clip` limits drawing to part of the client area, such as a region being redrawn.
+	pub fn draw_transformed(&self, pixel_buffer: &mut [u8], client_rect: &Rect, transform: &Affine, filter: Filter, clip: Option<&Rect>) {
+		let Some(inverse) = transform.inverse() else {
+			return;
+		};
+		if self.width == 0 || self.height == 0 {
+			return;
+		}
+		let buffer_width = client_rect.width().max(0) as i64;
+		let buffer_height = (client_rect.height().max(0) as i64).min(pixel_buffer.len() as i64 / (buffer_width * 4).max(1));
+		
+		// Bounding box of the transformed image, limited to the buffer and the clip rectangle.
+		let corners = [(0.0, 0.0), (self.width as f64, 0.0), (0.0, self.height as f64), (self.width as f64, self.height as f64)].map(|(x, y)| transform.apply(x, y));
+		let mut left = corners.iter().map(|corner| corner.0).fold(f64::INFINITY, f64::min).floor().max(0.0) as i64;
+		let mut top = corners.iter().map(|corner| corner.1).fold(f64::INFINITY, f64::min).floor().max(0.0) as i64;
+		let mut right = (corners.iter().map(|corner| corner.0).fold(f64::NEG_INFINITY, f64::max).ceil() as i64).min(buffer_width);
+		let mut bottom = (corners.iter().map(|corner| corner.1).fold(f64::NEG_INFINITY, f64::max).ceil() as i64).min(buffer_height);
+		if let Some(clip) = clip {
+			left = left.max(clip.left as i64);
+			top = top.max(clip.top as i64);
+			right = right.min(clip.right as i64);
+			bottom = bottom.min(clip.bottom as i64);
+		}
+		
+		let (width, height) = (self.width as f64, self.height as f64);
+		for row in top..bottom {
+			for column in left..right {
+				let (u, v) = inverse.apply(column as f64 + 0.5, row as f64 + 0.5);
+				if u < 0.0 || v < 0.0 || u >= width || v >= height {
+					continue;
+				}
+				let source = self.sample(u, v, filter);
+				let inverse_alpha = 1.0 - source[3] / 255.0;
+				let i = 4 * (row * buffer_width + column) as usize;
+				for channel in 0..4 {
+					pixel_buffer[i + channel] = (source[channel] + pixel_buffer[i + channel] as f32 * inverse_alpha).round().min(255.0) as u8;
+				}
+			}
+		}
+	}
+	
+	// Shrinks the image by averaging the area each new pixel covers, which keeps fine detail from aliasing in thumbnails.
+	pub fn downscale(&self, width: u32, height: u32) -> Result<Image, ImageError> {
+		if width == 0 || height == 0 || width > self.width || height > self.height {
+			return Err(ImageError::Unsupported(format!("Downscaling {}x{} to {width}x{height}.", self.width, self.height)));
+		}
+		let premultiplied: Vec<[f32; 4]> = (0..self.height as i64).flat_map(|y| (0..self.width as i64).map(move |x| (x, y))).map(|(x, y)| self.premultiplied(x, y)).collect();
+		let horizontal = area_average(&premultiplied, self.width as usize, self.height as usize, width as usize, 1);
+		let both = area_average(&horizontal, self.height as usize, width as usize, height as usize, width as usize);
+		
+		let mut image = Image::new(width, height)?;
+		for (pixel, color) in image.pixels.chunks_exact_mut(4).zip(both) {
+			let alpha = color[3].round().clamp(0.0, 255.0);
+			let unpremultiply = |value: f32| if alpha > 0.0 { (value * 255.0 / alpha).round().clamp(0.0, 255.0) as u8 } else { 0 };
+			pixel.copy_from_slice(&[unpremultiply(color[0]), unpremultiply(color[1]), unpremultiply(color[2]), alpha as u8]);
+		}
+		Ok(image)
+	}
+}
+
+// Averages runs of `length` samples down to `new_length` along one axis. Samples along the axis are `stride` apart, and there are `lines` such runs.
+fn area_average(samples: &[[f32; 4]], length: usize, lines: usize, new_length: usize, stride: usize) -> Vec<[f32; 4]> {
+	let scale = length as f64 / new_length as f64;
+	let (line_step, new_stride, new_line_step) = if stride == 1 { (length, 1, new_length) } else { (1, stride, 1) };
+	let mut output = vec![[0.0f32; 4]; new_length * lines];
+	for line in 0..lines {
+		for position in 0..new_length {
+			let (start, end) = (position as f64 * scale, (position + 1) as f64 * scale);
+			let mut sum = [0.0f32; 4];
+			for source in start.floor() as usize..(end.ceil() as usize).min(length) {
+				let weight = ((end.min(source as f64 + 1.0) - start.max(source as f64)) / scale) as f32;
+				let sample = samples[line * line_step + source * stride];
+				for channel in 0..4 {
+					sum[channel] += weight * sample[channel];
+				}
+			}
+			output[line * new_line_step + position * new_stride] = sum;
+		}
+	}
+	output
+}
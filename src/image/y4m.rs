@@ -0,0 +1,54 @@
+use std::io::Write;
+
+use super::{Image, ImageError};
+
+
+// Writes uncompressed YUV4MPEG2 with full resolution chroma, which ffmpeg and most encoders read from a pipe.
+pub struct Y4mEncoder<W: Write> {
+	writer: W,
+	width: u32,
+	height: u32
+}
+impl<W: Write> Y4mEncoder<W> {
+	pub fn new(mut writer: W, width: u32, height: u32, frames_per_second: u32) -> Result<Self, ImageError> {
+		if width == 0 || height == 0 || frames_per_second == 0 {
+			return Err(ImageError::Malformed(format!("Y4M of {width}x{height} at {frames_per_second} frames per second.")));
+		}
+		writeln!(writer, "YUV4MPEG2 W{width} H{height} F{frames_per_second}:1 Ip A1:1 C444").map_err(|err| ImageError::Io(format!("Error writing Y4M: {err}")))?;
+		Ok(Self { writer, width, height })
+	}
+	
+	pub fn width(&self) -> u32 {
+		self.width
+	}
+	
+	pub fn height(&self) -> u32 {
+		self.height
+	}
+	
+	pub fn add_frame(&mut self, image: &Image) -> Result<(), ImageError> {
+		if image.width != self.width || image.height != self.height {
+			return Err(ImageError::Malformed(format!("Frame of {}x{} in a {}x{} Y4M.", image.width, image.height, self.width, self.height)));
+		}
+		let count = image.pixels.len() / 4;
+		let mut frame = Vec::with_capacity(6 + 3 * count);
+		frame.extend_from_slice(b"FRAME\n");
+		frame.resize(6 + 3 * count, 0);
+		let (luma, chroma) = frame[6..].split_at_mut(count);
+		let (blue_difference, red_difference) = chroma.split_at_mut(count);
+		
+		// BT.601 with the usual limited range, in 8 bit fixed point.
+		for (i, bgra) in image.pixels.chunks_exact(4).enumerate() {
+			let (blue, green, red) = (bgra[0] as i32, bgra[1] as i32, bgra[2] as i32);
+			luma[i] = (((66 * red + 129 * green + 25 * blue + 128) >> 8) + 16) as u8;
+			blue_difference[i] = (((-38 * red - 74 * green + 112 * blue + 128) >> 8) + 128) as u8;
+			red_difference[i] = (((112 * red - 94 * green - 18 * blue + 128) >> 8) + 128) as u8;
+		}
+		self.writer.write_all(&frame).map_err(|err| ImageError::Io(format!("Error writing Y4M: {err}")))
+	}
+	
+	pub fn finish(mut self) -> Result<W, ImageError> {
+		self.writer.flush().map_err(|err| ImageError::Io(format!("Error writing Y4M: {err}")))?;
+		Ok(self.writer)
+	}
+}
@@ -0,0 +1,566 @@
+use std::collections::BinaryHeap;
+
+use super::ImageError;
+
+
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order in which the code length code lengths are stored in a dynamic block header.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn adler32(data: &[u8]) -> u32 {
+	let (mut a, mut b) = (1u32, 0u32);
+	// 5552 is the most bytes that can be summed before `b` could overflow.
+	for chunk in data.chunks(5552) {
+		for &byte in chunk {
+			a += byte as u32;
+			b += a;
+		}
+		a %= 65521;
+		b %= 65521;
+	}
+	(b << 16) | a
+}
+
+
+struct BitReader<'a> {
+	data: &'a [u8],
+	position: usize,
+	bit_buffer: u64,
+	bit_count: u32
+}
+impl BitReader<'_> {
+	fn bits(&mut self, count: u32) -> Result<u32, ImageError> {
+		while self.bit_count < count {
+			let byte = *self.data.get(self.position).ok_or(ImageError::UnexpectedEnd)?;
+			self.position += 1;
+			self.bit_buffer |= (byte as u64) << self.bit_count;
+			self.bit_count += 8;
+		}
+		let value = (self.bit_buffer & ((1u64 << count) - 1)) as u32;
+		self.bit_buffer >>= count;
+		self.bit_count -= count;
+		Ok(value)
+	}
+	
+	fn align_to_byte(&mut self) {
+		self.bit_buffer >>= self.bit_count % 8;
+		self.bit_count -= self.bit_count % 8;
+	}
+}
+
+// Canonical Huffman decoding table, symbols sorted by code length then value.
+struct Huffman {
+	counts: [u16; 16],
+	symbols: Vec<u16>
+}
+impl Huffman {
+	fn new(lengths: &[u8]) -> Result<Self, ImageError> {
+		let mut counts = [0u16; 16];
+		for &length in lengths {
+			counts[length as usize] += 1;
+		}
+		counts[0] = 0;
+		
+		// Over-subscribed codes can't be decoded, incomplete ones are allowed since single code trees are legal.
+		let mut left = 1i32;
+		for &count in &counts[1..] {
+			left = (left << 1) - count as i32;
+			if left < 0 {
+				return Err(ImageError::Malformed(String::from("Over-subscribed Huffman code.")));
+			}
+		}
+		
+		let mut offsets = [0u16; 16];
+		for length in 1..15 {
+			offsets[length + 1] = offsets[length] + counts[length];
+		}
+		let mut symbols = vec![0; lengths.len()];
+		for (symbol, &length) in lengths.iter().enumerate() {
+			if length != 0 {
+				symbols[offsets[length as usize] as usize] = symbol as u16;
+				offsets[length as usize] += 1;
+			}
+		}
+		Ok(Self { counts, symbols })
+	}
+	
+	fn decode(&self, reader: &mut BitReader) -> Result<u16, ImageError> {
+		let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+		for length in 1..16 {
+			code |= reader.bits(1)? as i32;
+			let count = self.counts[length] as i32;
+			if code - first < count {
+				return Ok(self.symbols[(index + code - first) as usize]);
+			}
+			index += count;
+			first = (first + count) << 1;
+			code <<= 1;
+		}
+		Err(ImageError::Malformed(String::from("Invalid Huffman code.")))
+	}
+}
+
+fn fixed_lengths() -> ([u8; 288], [u8; 30]) {
+	let mut literal_lengths = [8u8; 288];
+	literal_lengths[144..256].fill(9);
+	literal_lengths[256..280].fill(7);
+	(literal_lengths, [5u8; 30])
+}
+
+// Fails once the output grows past `max_length`, so a few bytes can't expand into gigabytes.
+pub(super) fn inflate(data: &[u8], max_length: usize) -> Result<Vec<u8>, ImageError> {
+	if data.len() < 2 {
+		return Err(ImageError::UnexpectedEnd);
+	}
+	if data[0] & 0x0F != 8 || !(((data[0] as u16) << 8) | data[1] as u16).is_multiple_of(31) {
+		return Err(ImageError::Malformed(String::from("Invalid zlib header.")));
+	}
+	if data[1] & 0x20 != 0 {
+		return Err(ImageError::Unsupported(String::from("zlib preset dictionary.")));
+	}
+	
+	let mut reader = BitReader { data, position: 2, bit_buffer: 0, bit_count: 0 };
+	let mut output = Vec::new();
+	loop {
+		let last = reader.bits(1)? == 1;
+		match reader.bits(2)? {
+			0 => {
+				reader.align_to_byte();
+				let length = reader.bits(16)?;
+				let inverse = reader.bits(16)?;
+				if length != !inverse & 0xFFFF {
+					return Err(ImageError::Malformed(String::from("Stored block length mismatch.")));
+				}
+				if output.len() + length as usize > max_length {
+					return Err(too_long());
+				}
+				for _ in 0..length {
+					output.push(reader.bits(8)? as u8);
+				}
+			}
+			1 => {
+				let (literal_lengths, distance_lengths) = fixed_lengths();
+				inflate_block(&mut reader, &mut output, max_length, &Huffman::new(&literal_lengths)?, &Huffman::new(&distance_lengths)?)?;
+			}
+			2 => {
+				let (literals, distances) = read_dynamic_trees(&mut reader)?;
+				inflate_block(&mut reader, &mut output, max_length, &literals, &distances)?;
+			}
+			_ => return Err(ImageError::Malformed(String::from("Invalid deflate block type.")))
+		}
+		if last {
+			break;
+		}
+	}
+	
+	reader.align_to_byte();
+	let checksum = (0..4).try_fold(0u32, |checksum, _| Ok::<u32, ImageError>((checksum << 8) | reader.bits(8)?))?;
+	if checksum != adler32(&output) {
+		return Err(ImageError::Malformed(String::from("zlib checksum mismatch.")));
+	}
+	Ok(output)
+}
+
+fn read_dynamic_trees(reader: &mut BitReader) -> Result<(Huffman, Huffman), ImageError> {
+	let literal_count = reader.bits(5)? as usize + 257;
+	let distance_count = reader.bits(5)? as usize + 1;
+	let code_length_count = reader.bits(4)? as usize + 4;
+	if literal_count > 286 || distance_count > 30 {
+		return Err(ImageError::Malformed(String::from("Too many Huffman codes.")));
+	}
+	
+	let mut code_length_lengths = [0u8; 19];
+	for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+		code_length_lengths[index] = reader.bits(3)? as u8;
+	}
+	let code_lengths = Huffman::new(&code_length_lengths)?;
+	
+	let mut lengths = vec![0u8; literal_count + distance_count];
+	let mut index = 0;
+	while index < lengths.len() {
+		let (value, repeat) = match code_lengths.decode(reader)? {
+			symbol @ 0..=15 => (symbol as u8, 1),
+			16 => match index {
+				0 => return Err(ImageError::Malformed(String::from("Repeated code length without a previous one."))),
+				_ => (lengths[index - 1], 3 + reader.bits(2)? as usize)
+			},
+			17 => (0, 3 + reader.bits(3)? as usize),
+			_ => (0, 11 + reader.bits(7)? as usize)
+		};
+		if index + repeat > lengths.len() {
+			return Err(ImageError::Malformed(String::from("Code lengths overflow.")));
+		}
+		lengths[index..index + repeat].fill(value);
+		index += repeat;
+	}
+	if lengths[256] == 0 {
+		return Err(ImageError::Malformed(String::from("Missing end of block code.")));
+	}
+	
+	Ok((Huffman::new(&lengths[..literal_count])?, Huffman::new(&lengths[literal_count..])?))
+}
+
+fn too_long() -> ImageError {
+	ImageError::Malformed(String::from("zlib data is longer than expected."))
+}
+
+fn inflate_block(reader: &mut BitReader, output: &mut Vec<u8>, max_length: usize, literals: &Huffman, distances: &Huffman) -> Result<(), ImageError> {
+	loop {
+		let symbol = literals.decode(reader)? as usize;
+		match symbol {
+			0..=255 if output.len() == max_length => return Err(too_long()),
+			0..=255 => output.push(symbol as u8),
+			256 => return Ok(()),
+			257..=285 => {
+				let length = LENGTH_BASE[symbol - 257] as usize + reader.bits(LENGTH_EXTRA[symbol - 257] as u32)? as usize;
+				let distance_symbol = distances.decode(reader)? as usize;
+				if distance_symbol >= 30 {
+					return Err(ImageError::Malformed(String::from("Invalid distance code.")));
+				}
+				let distance = DISTANCE_BASE[distance_symbol] as usize + reader.bits(DISTANCE_EXTRA[distance_symbol] as u32)? as usize;
+				if distance > output.len() {
+					return Err(ImageError::Malformed(String::from("Distance reaches before the start of the data.")));
+				}
+				if output.len() + length > max_length {
+					return Err(too_long());
+				}
+				// Copies byte by byte, the source may overlap what is being written.
+				let start = output.len() - distance;
+				for i in 0..length {
+					output.push(output[start + i]);
+				}
+			}
+			_ => return Err(ImageError::Malformed(String::from("Invalid literal or length code.")))
+		}
+	}
+}
+
+
+struct BitWriter {
+	output: Vec<u8>,
+	bit_buffer: u64,
+	bit_count: u32
+}
+impl BitWriter {
+	fn bits(&mut self, value: u32, count: u32) {
+		self.bit_buffer |= (value as u64) << self.bit_count;
+		self.bit_count += count;
+		while self.bit_count >= 8 {
+			self.output.push(self.bit_buffer as u8);
+			self.bit_buffer >>= 8;
+			self.bit_count -= 8;
+		}
+	}
+	
+	// Huffman codes are stored starting from their most significant bit.
+	fn code(&mut self, code: u16, length: u8) {
+		self.bits(code.reverse_bits() as u32 >> (16 - length as u32), length as u32);
+	}
+	
+	fn finish(mut self) -> Vec<u8> {
+		if self.bit_count > 0 {
+			self.output.push(self.bit_buffer as u8);
+		}
+		self.output
+	}
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+	Literal(u8),
+	Match { length: u16, distance: u16 }
+}
+
+const WINDOW_SIZE: usize = 32768;
+const HASH_SIZE: usize = 1 << 15;
+const MAX_CHAIN: usize = 128;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+// Greedy LZ77 with one step of lazy matching over hash chains.
+fn find_tokens(data: &[u8]) -> Vec<Token> {
+	let hash = |i: usize| ((data[i] as usize) << 10 ^ (data[i + 1] as usize) << 5 ^ data[i + 2] as usize) & (HASH_SIZE - 1);
+	let mut head = vec![usize::MAX; HASH_SIZE];
+	let mut previous = vec![usize::MAX; WINDOW_SIZE];
+	let mut tokens = Vec::with_capacity(data.len() / 2);
+	
+	let insert = |i: usize, head: &mut Vec<usize>, previous: &mut Vec<usize>| {
+		if i + MIN_MATCH <= data.len() {
+			let h = hash(i);
+			previous[i % WINDOW_SIZE] = head[h];
+			head[h] = i;
+		}
+	};
+	let longest_match = |i: usize, head: &Vec<usize>, previous: &Vec<usize>| -> (usize, usize) {
+		let (mut best_length, mut best_distance) = (0, 0);
+		if i + MIN_MATCH > data.len() {
+			return (0, 0);
+		}
+		let max_length = MAX_MATCH.min(data.len() - i);
+		let mut candidate = head[hash(i)];
+		let mut chain = 0;
+		while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+			if data[candidate + best_length] == data[i + best_length] {
+				let length = data[candidate..].iter().zip(&data[i..i + max_length]).take_while(|(a, b)| a == b).count();
+				if length > best_length {
+					best_length = length;
+					best_distance = i - candidate;
+					if length == max_length {
+						break;
+					}
+				}
+			}
+			let next = previous[candidate % WINDOW_SIZE];
+			if next == usize::MAX || next >= candidate {
+				break;
+			}
+			candidate = next;
+			chain += 1;
+		}
+		(best_length, best_distance)
+	};
+	
+	let mut i = 0;
+	while i < data.len() {
+		let (length, distance) = longest_match(i, &head, &previous);
+		if length >= MIN_MATCH {
+			insert(i, &mut head, &mut previous);
+			// Emit a literal instead if the match starting at the next byte is longer.
+			let (next_length, _) = longest_match(i + 1, &head, &previous);
+			if next_length > length {
+				tokens.push(Token::Literal(data[i]));
+				i += 1;
+				continue;
+			}
+			tokens.push(Token::Match { length: length as u16, distance: distance as u16 });
+			for j in i + 1..i + length {
+				insert(j, &mut head, &mut previous);
+			}
+			i += length;
+		} else {
+			insert(i, &mut head, &mut previous);
+			tokens.push(Token::Literal(data[i]));
+			i += 1;
+		}
+	}
+	tokens
+}
+
+fn length_symbol(length: u16) -> usize {
+	LENGTH_BASE.iter().rposition(|&base| base <= length).unwrap()
+}
+
+fn distance_symbol(distance: u16) -> usize {
+	DISTANCE_BASE.iter().rposition(|&base| base <= distance).unwrap()
+}
+
+// Huffman code lengths for the given symbol frequencies, no longer than `max_length`.
+fn code_lengths(frequencies: &[u32], max_length: u8) -> Vec<u8> {
+	let mut lengths = vec![0u8; frequencies.len()];
+	let mut used: Vec<usize> = (0..frequencies.len()).filter(|&symbol| frequencies[symbol] > 0).collect();
+	match used.len() {
+		0 => return lengths,
+		1 => {
+			lengths[used[0]] = 1;
+			return lengths;
+		}
+		_ => ()
+	}
+	
+	// Tree nodes are (frequency, id), leaves first, parents record their children to measure depths afterwards.
+	let mut parents = vec![usize::MAX; used.len() * 2];
+	let mut heap: BinaryHeap<std::cmp::Reverse<(u64, usize)>> = used.iter().enumerate().map(|(id, &symbol)| std::cmp::Reverse((frequencies[symbol] as u64, id))).collect();
+	let mut next_id = used.len();
+	while heap.len() > 1 {
+		let std::cmp::Reverse((frequency_a, a)) = heap.pop().unwrap();
+		let std::cmp::Reverse((frequency_b, b)) = heap.pop().unwrap();
+		parents[a] = next_id;
+		parents[b] = next_id;
+		heap.push(std::cmp::Reverse((frequency_a + frequency_b, next_id)));
+		next_id += 1;
+	}
+	let mut depths = vec![0usize; next_id];
+	for id in (0..next_id - 1).rev() {
+		depths[id] = depths[parents[id]] + 1;
+	}
+	
+	// Lengths past the maximum are cut down, then longer codes are traded for shorter ones until the code is complete again.
+	let mut counts = vec![0u32; max_length as usize + 1];
+	for id in 0..used.len() {
+		counts[depths[id].min(max_length as usize)] += 1;
+	}
+	let mut total: u64 = (1..=max_length as usize).map(|length| (counts[length] as u64) << (max_length as usize - length)).sum();
+	while total > 1 << max_length {
+		counts[max_length as usize] -= 1;
+		for length in (1..max_length as usize).rev() {
+			if counts[length] > 0 {
+				counts[length] -= 1;
+				counts[length + 1] += 2;
+				break;
+			}
+		}
+		total -= 1;
+	}
+	
+	// The most frequent symbols get the shortest codes.
+	used.sort_by_key(|&symbol| std::cmp::Reverse(frequencies[symbol]));
+	let mut symbols = used.into_iter();
+	for length in 1..=max_length {
+		for _ in 0..counts[length as usize] {
+			lengths[symbols.next().unwrap()] = length;
+		}
+	}
+	lengths
+}
+
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+	let mut counts = [0u16; 16];
+	for &length in lengths {
+		counts[length as usize] += 1;
+	}
+	counts[0] = 0;
+	let mut next_code = [0u16; 16];
+	for length in 1..16 {
+		next_code[length] = (next_code[length - 1] + counts[length - 1]) << 1;
+	}
+	lengths.iter().map(|&length| {
+		if length == 0 {
+			return 0;
+		}
+		let code = next_code[length as usize];
+		next_code[length as usize] += 1;
+		code
+	}).collect()
+}
+
+// Run length encodes code lengths with symbols 16, 17 and 18, returning (symbol, extra bits value).
+fn encode_code_lengths(lengths: &[u8]) -> Vec<(u8, u8)> {
+	let mut encoded = Vec::new();
+	let mut i = 0;
+	while i < lengths.len() {
+		let value = lengths[i];
+		let run = lengths[i..].iter().take_while(|&&length| length == value).count();
+		if value == 0 && run >= 3 {
+			let run = run.min(138);
+			encoded.push(if run >= 11 { (18, run as u8 - 11) } else { (17, run as u8 - 3) });
+			i += run;
+		} else if value != 0 && run >= 4 {
+			encoded.push((value, 0));
+			let run = (run - 1).min(6);
+			encoded.push((16, run as u8 - 3));
+			i += run + 1;
+		} else {
+			encoded.push((value, 0));
+			i += 1;
+		}
+	}
+	encoded
+}
+
+fn write_block(writer: &mut BitWriter, tokens: &[Token], last: bool) {
+	let mut literal_frequencies = [0u32; 286];
+	let mut distance_frequencies = [0u32; 30];
+	for token in tokens {
+		match *token {
+			Token::Literal(byte) => literal_frequencies[byte as usize] += 1,
+			Token::Match { length, distance } => {
+				literal_frequencies[257 + length_symbol(length)] += 1;
+				distance_frequencies[distance_symbol(distance)] += 1;
+			}
+		}
+	}
+	literal_frequencies[256] = 1;
+	
+	let mut literal_lengths = code_lengths(&literal_frequencies, 15);
+	let mut distance_lengths = code_lengths(&distance_frequencies, 15);
+	if distance_lengths.iter().all(|&length| length == 0) {
+		distance_lengths[0] = 1;
+	}
+	let literal_count = 257 + literal_lengths[257..].iter().rposition(|&length| length != 0).map_or(0, |position| position + 1);
+	let distance_count = 1 + distance_lengths.iter().rposition(|&length| length != 0).unwrap_or(0);
+	literal_lengths.truncate(literal_count);
+	distance_lengths.truncate(distance_count);
+	
+	let all_lengths: Vec<u8> = literal_lengths.iter().chain(&distance_lengths).copied().collect();
+	let encoded_lengths = encode_code_lengths(&all_lengths);
+	let mut code_length_frequencies = [0u32; 19];
+	for &(symbol, _) in &encoded_lengths {
+		code_length_frequencies[symbol as usize] += 1;
+	}
+	let code_length_lengths = code_lengths(&code_length_frequencies, 7);
+	let code_length_count = 4.max(1 + CODE_LENGTH_ORDER.iter().rposition(|&index| code_length_lengths[index] != 0).unwrap_or(0));
+	
+	// Compare against the fixed codes, which need no tree in the header.
+	let (fixed_literal_lengths, fixed_distance_lengths) = fixed_lengths();
+	let cost = |literal_lengths: &[u8], distance_lengths: &[u8]| -> u64 {
+		tokens.iter().map(|token| match *token {
+			Token::Literal(byte) => literal_lengths[byte as usize] as u64,
+			Token::Match { length, distance } => {
+				let (length_symbol, distance_symbol) = (length_symbol(length), distance_symbol(distance));
+				(literal_lengths[257 + length_symbol] + LENGTH_EXTRA[length_symbol] + distance_lengths[distance_symbol] + DISTANCE_EXTRA[distance_symbol]) as u64
+			}
+		}).sum::<u64>() + literal_lengths[256] as u64
+	};
+	let header_cost = 14 + 3 * code_length_count as u64 + encoded_lengths.iter().map(|&(symbol, _)| code_length_lengths[symbol as usize] as u64 + match symbol { 16 => 2, 17 => 3, 18 => 7, _ => 0 }).sum::<u64>();
+	let use_fixed = cost(&fixed_literal_lengths, &fixed_distance_lengths) <= header_cost + cost(&literal_lengths, &distance_lengths);
+	
+	writer.bits(last as u32, 1);
+	let (literal_lengths, distance_lengths) = if use_fixed {
+		writer.bits(1, 2);
+		(fixed_literal_lengths.to_vec(), fixed_distance_lengths.to_vec())
+	} else {
+		writer.bits(2, 2);
+		writer.bits(literal_count as u32 - 257, 5);
+		writer.bits(distance_count as u32 - 1, 5);
+		writer.bits(code_length_count as u32 - 4, 4);
+		for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+			writer.bits(code_length_lengths[index] as u32, 3);
+		}
+		let code_length_codes = canonical_codes(&code_length_lengths);
+		for &(symbol, extra) in &encoded_lengths {
+			writer.code(code_length_codes[symbol as usize], code_length_lengths[symbol as usize]);
+			match symbol {
+				16 => writer.bits(extra as u32, 2),
+				17 => writer.bits(extra as u32, 3),
+				18 => writer.bits(extra as u32, 7),
+				_ => ()
+			}
+		}
+		(literal_lengths, distance_lengths)
+	};
+	
+	let literal_codes = canonical_codes(&literal_lengths);
+	let distance_codes = canonical_codes(&distance_lengths);
+	for token in tokens {
+		match *token {
+			Token::Literal(byte) => writer.code(literal_codes[byte as usize], literal_lengths[byte as usize]),
+			Token::Match { length, distance } => {
+				let symbol = length_symbol(length);
+				writer.code(literal_codes[257 + symbol], literal_lengths[257 + symbol]);
+				writer.bits((length - LENGTH_BASE[symbol]) as u32, LENGTH_EXTRA[symbol] as u32);
+				let symbol = distance_symbol(distance);
+				writer.code(distance_codes[symbol], distance_lengths[symbol]);
+				writer.bits((distance - DISTANCE_BASE[symbol]) as u32, DISTANCE_EXTRA[symbol] as u32);
+			}
+		}
+	}
+	writer.code(literal_codes[256], literal_lengths[256]);
+}
+
+pub(super) fn deflate(data: &[u8]) -> Vec<u8> {
+	let mut writer = BitWriter { output: vec![0x78, 0x9C], bit_buffer: 0, bit_count: 0 };
+	let tokens = find_tokens(data);
+	if tokens.is_empty() {
+		write_block(&mut writer, &[], true);
+	}
+	let block_count = tokens.len().div_ceil(1 << 15);
+	for (index, block) in tokens.chunks(1 << 15).enumerate() {
+		write_block(&mut writer, block, index + 1 == block_count);
+	}
+	let mut output = writer.finish();
+	output.extend_from_slice(&adler32(data).to_be_bytes());
+	output
+}
@@ -1,8 +1,36 @@
 use core::result::Result;
-use std::{os::{raw::c_void, windows::ffi::OsStrExt}, ffi::OsStr};
-use windows::{core::{PCWSTR, Error, HSTRING}, Win32::{Foundation::{HWND, RECT, LPARAM, LRESULT, WPARAM, BOOL, FALSE}, UI::{WindowsAndMessaging::{self, CS_HREDRAW, CS_VREDRAW, WS_EX_TOPMOST, WS_OVERLAPPEDWINDOW, HICON, RegisterClassW, LoadCursorW, WNDCLASSW, IDC_ARROW, DefWindowProcW, GetWindowLongPtrW, SetWindowLongPtrW, WM_NCCREATE, CREATESTRUCTW, GWLP_USERDATA, TranslateMessage, DispatchMessageW, GetMessageW, PostQuitMessage, MSG, CreateWindowExW, CW_USEDEFAULT, SW_SHOW, ShowWindow, GetClientRect, WINDOW_EX_STYLE, CreateMenu, MF_STRING, AppendMenuW, SetMenu, MF_POPUP, AdjustWindowRectEx, SetTimer, KillTimer, GetMenu, HMENU, MF_SEPARATOR, CheckMenuItem, HiliteMenuItem, EnableMenuItem, MF_REMOVE, MF_ENABLED, MF_DISABLED, MF_HILITE, MF_UNHILITE, GetMenuItemInfoW, MENUITEMINFOW, SetMenuItemInfoW, MF_UNCHECKED, ModifyMenuW, MF_CHECKED, GetMenuItemCount, GetSubMenu, MENU_ITEM_TYPE, MIIM_TYPE, MFT_MENUBREAK, MFT_MENUBARBREAK, MFT_RIGHTJUSTIFY, DestroyMenu, DrawMenuBar}, Input::KeyboardAndMouse::{SetCapture, ReleaseCapture}}, System::{WinRT::{DispatcherQueueOptions, RoInitialize, DQTYPE_THREAD_CURRENT, DQTAT_COM_NONE, RO_INIT_SINGLETHREADED, CreateDispatcherQueueController}, LibraryLoader::GetModuleHandleW}, Graphics::Gdi::{PAINTSTRUCT, BeginPaint, EndPaint, SelectObject, DeleteObject, CreateCompatibleDC, BITMAPINFO, BITMAPINFOHEADER, RGBQUAD, BI_RGB, CreateDIBSection, DIB_RGB_COLORS, BitBlt, SRCCOPY, DeleteDC, HBRUSH, HBITMAP, GetDC, ReleaseDC, InvalidateRect}}, Foundation::AsyncActionCompletedHandler};
+use std::{os::{raw::c_void, windows::ffi::OsStrExt}, ffi::OsStr, cell::{Cell, RefCell}, collections::VecDeque, marker::PhantomData, any::Any, panic::AssertUnwindSafe};
+use windows::{core::{PCWSTR, PWSTR, Error, HSTRING}, Win32::{Foundation::{HWND, RECT, LPARAM, LRESULT, WPARAM, BOOL, FALSE, TRUE, POINT}, UI::{WindowsAndMessaging::{self, CS_HREDRAW, CS_VREDRAW, WS_EX_TOPMOST, WS_OVERLAPPEDWINDOW, HICON, RegisterClassW, LoadCursorW, WNDCLASSW, IDC_ARROW, DefWindowProcW, GetWindowLongPtrW, SetWindowLongPtrW, WM_NCCREATE, CREATESTRUCTW, GWLP_USERDATA, TranslateMessage, DispatchMessageW, GetMessageW, PostQuitMessage, MSG, CreateWindowExW, CW_USEDEFAULT, SW_SHOW, ShowWindow, GetClientRect, WINDOW_EX_STYLE, CreateMenu, MF_STRING, AppendMenuW, SetMenu, MF_POPUP, AdjustWindowRectEx, SetTimer, KillTimer, GetMenu, HMENU, MF_SEPARATOR, CheckMenuItem, HiliteMenuItem, EnableMenuItem, MF_REMOVE, MF_ENABLED, MF_DISABLED, MF_HILITE, MF_UNHILITE, GetMenuItemInfoW, MENUITEMINFOW, SetMenuItemInfoW, MF_UNCHECKED, ModifyMenuW, MF_CHECKED, GetMenuItemCount, GetSubMenu, MENU_ITEM_TYPE, MIIM_TYPE, MFT_MENUBREAK, MFT_MENUBARBREAK, MFT_RIGHTJUSTIFY, DrawMenuBar, MFT_RADIOCHECK, CheckMenuRadioItem, MF_BYPOSITION, MIIM_STATE, MIIM_ID, MIIM_FTYPE, MFS_CHECKED, MENU_ITEM_MASK, GetMenuItemID, MIIM_STRING, MIIM_SUBMENU, MFS_DISABLED, MFT_SEPARATOR, CreatePopupMenu, TrackPopupMenu, TRACK_POPUP_MENU_FLAGS, TPM_LEFTALIGN, TPM_TOPALIGN, TPM_RIGHTBUTTON, TPM_RETURNCMD, TPM_NONOTIFY, SetForegroundWindow, DestroyWindow, PostMessageW, WM_NULL, MF_SYSMENU, IsWindow, PeekMessageW, PM_REMOVE}, Input::KeyboardAndMouse::{SetCapture, ReleaseCapture}}, System::{WinRT::{DispatcherQueueOptions, RoInitialize, DQTYPE_THREAD_CURRENT, DQTAT_COM_NONE, RO_INIT_SINGLETHREADED, CreateDispatcherQueueController}, LibraryLoader::GetModuleHandleW}, Graphics::Gdi::{HBITMAP, PAINTSTRUCT, BeginPaint, EndPaint, SelectObject, CreateCompatibleDC, BitBlt, SRCCOPY, DeleteDC, HBRUSH, InvalidateRect, ClientToScreen}}, Foundation::AsyncActionCompletedHandler};
 
 mod tests;
+mod menu_spec;
+mod menu_file;
+mod menu_graphics;
+mod recording;
+mod clipboard;
+mod file_drop;
+mod dialogs;
+mod message_box;
+mod event_proxy;
+mod viewport;
+mod render;
+mod pixel_buffer;
+mod dib_section;
+pub mod image;
+mod tasks;
+
+pub use menu_spec::{MenuSpec, MenuEntry, MenuItemSpec};
+pub use menu_file::{MenuDefinition, MenuFile};
+pub use menu_graphics::MenuItemDrawState;
+pub use recording::{RecordingFormat, FrameTiming};
+pub use viewport::Viewport;
+pub use render::{TileRenderer, Tile, ProgressiveRender};
+pub use pixel_buffer::ResizeContents;
+pub use clipboard::{Clipboard, MemoryClipboard, SystemClipboard};
+pub use dialogs::{DialogProvider, NativeDialogs, ScriptedDialogs};
+pub use message_box::{MessageBoxButtons, MessageBoxIcon, MessageBoxButton};
+pub use event_proxy::EventProxy;
+pub use tasks::{sleep, Sleep, run_in_background, Background, channel, Sender, Receiver, Recv, AppAccess, UserEvents, RecvUserEvent};
 
 
 #[repr(C)]
@@ -28,6 +56,9 @@ pub trait SimpleWindowApp {
 	fn on_init(&mut self, handle: &WindowHandle) {}
 	fn on_paint(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect) {}
 	fn on_command(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, command_id: u16) {}
+	fn on_menu_opening(&mut self, handle: &WindowHandle, submenu: &MenuRef) {}
+	fn on_menu_hover(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, command_id: Option<u16>) {}
+	fn on_menu_closed(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect) {}
 	fn on_timer(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, timer_id: usize) {}
 	fn on_resize(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect) {}
 	fn on_resizing(&mut self, handle: &WindowHandle, client_rect: &mut Rect) {}
@@ -41,6 +72,11 @@ pub trait SimpleWindowApp {
 	fn on_key_down(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, key_code: u32) {}
 	fn on_key_up(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, key_code: u32) {}
 	fn on_scroll(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, scroll_distance: i16) {}
+	fn on_files_dropped(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, paths: &[std::path::PathBuf], mouse_x: i16, mouse_y: i16) {}
+	fn on_user_event(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, payload: Box<dyn std::any::Any + Send>) {}
+	// Called when the window is asked to close, returning false keeps it open.
+	fn on_close_requested(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect) -> bool { true }
+	// Not called after a callback panicked, the state may have been left halfway through a change.
 	fn on_exit(&mut self, handle: &WindowHandle) {}
 	fn on_error(&mut self, handle: &WindowHandle, pixel_buffer: &mut [u8], client_rect: &Rect, error_message: &str) {}
 }
@@ -53,23 +89,185 @@ fn internalize_id(id: u16) -> Result<u32, String> {
 	}
 }
 
+thread_local! {
+	// Per thread, so tests running in parallel don't see each other's menus.
+	static LIVE_MENUS: Cell<usize> = const { Cell::new(0) };
+}
+
+/// An owned menu that hasn't been attached to a window or a parent menu yet, it is destroyed when dropped. Edits that can remove
+/// entries go through `edit`, and the `MenuRef` it hands out can't be swapped into another menu.
+///
+/// ```compile_fail,E0596
+/// # use simple_windows::Menu;
+/// fn swap_menus(a: &mut Menu, b: &mut Menu) {
+///     std::mem::swap(&mut **a, &mut **b);
+/// }
+/// ```
 #[derive(Debug)]
 pub struct Menu {
-	hmenu: HMENU
+	hmenu: HMENU,
+	menu: MenuRef<'static>
 }
 impl Menu {
 	pub fn new() -> Result<Self, String> {
-		Ok(Self {
-			hmenu: unsafe { CreateMenu() }.map_err(|err| format!("Error creating menu: {err}"))?
-		})
+		Ok(Self::from_hmenu(unsafe { CreateMenu() }.map_err(|err| format!("Error creating menu: {err}"))?))
+	}
+	
+	pub fn new_popup() -> Result<Self, String> {
+		Ok(Self::from_hmenu(unsafe { CreatePopupMenu() }.map_err(|err| format!("Error creating popup menu: {err}"))?))
+	}
+	
+	// Number of owned menus that currently exist on this thread, for detecting leaks in tests.
+	pub fn live_count() -> usize {
+		LIVE_MENUS.get()
+	}
+	
+	fn from_hmenu(hmenu: HMENU) -> Self {
+		LIVE_MENUS.set(LIVE_MENUS.get() + 1);
+		Self { hmenu, menu: MenuRef::from_hmenu(hmenu) }
+	}
+	
+	// Borrows the menu for edits that can remove entries and for reaching its submenus.
+	pub fn edit(&mut self) -> MenuRef<'_> {
+		MenuRef::from_hmenu(self.hmenu)
+	}
+	
+	// Hands ownership of the handle to whatever it was just attached to.
+	fn into_hmenu(self) -> HMENU {
+		let hmenu = self.hmenu;
+		std::mem::forget(self);
+		LIVE_MENUS.set(LIVE_MENUS.get().saturating_sub(1));
+		hmenu
+	}
+}
+impl std::ops::Deref for Menu {
+	type Target = MenuRef<'static>;
+	
+	fn deref(&self) -> &MenuRef<'static> {
+		&self.menu
+	}
+}
+impl Drop for Menu {
+	fn drop(&mut self) {
+		menu_graphics::destroy_menu(self.hmenu);
+		LIVE_MENUS.set(LIVE_MENUS.get().saturating_sub(1));
+	}
+}
+
+thread_local! {
+	// Number of live `MenuBar`s of each window, a window's menu isn't destroyed while it has any.
+	static MENU_BAR_BORROWS: RefCell<Vec<(HWND, usize)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn menu_bar_borrows(hwnd: HWND) -> usize {
+	MENU_BAR_BORROWS.with_borrow(|borrows| borrows.iter().find(|(borrowed_hwnd, _)| *borrowed_hwnd == hwnd).map_or(0, |(_, count)| *count))
+}
+
+fn update_menu_bar_borrows(hwnd: HWND, change: isize) {
+	MENU_BAR_BORROWS.with_borrow_mut(|borrows| {
+		match borrows.iter().position(|(borrowed_hwnd, _)| *borrowed_hwnd == hwnd) {
+			Some(index) => borrows[index].1 = borrows[index].1.saturating_add_signed(change),
+			None => borrows.push((hwnd, change.max(0) as usize))
+		}
+		borrows.retain(|(_, count)| *count > 0);
+	});
+}
+
+/// A window's menu bar, borrowed from its `WindowHandle`. Menus borrowed from it can't outlive it, and it's only replaced or
+/// removed through `replace` and `remove`, which consume it. Those, `WindowHandle::set_menu` and edits that remove entries
+/// fail while another `MenuBar` of the window is alive, so the menu is never destroyed while borrowed.
+///
+/// ```compile_fail,E0505
+/// # use simple_windows::{WindowHandle, Menu};
+/// fn replace_while_borrowed(handle: &WindowHandle, menu: Menu) {
+///     let mut bar = handle.get_menu().unwrap();
+///     let mut items = bar.edit();
+///     let submenu = items.get_submenu(0).unwrap();
+///     bar.replace(menu).unwrap();
+///     submenu.add_item(1, "Destroyed").unwrap();
+/// }
+/// ```
+#[derive(Debug)]
+pub struct MenuBar<'a> {
+	hwnd: HWND,
+	menu: MenuRef<'a>
+}
+impl MenuBar<'_> {
+	// Borrows the menu bar for edits that can remove entries and for reaching its submenus.
+	pub fn edit(&mut self) -> MenuRef<'_> {
+		MenuRef { hmenu: self.menu.hmenu, window: Some(self.hwnd), owner: PhantomData }
+	}
+	
+	pub fn replace(self, menu: Menu) -> Result<(), String> {
+		if menu_bar_borrows(self.hwnd) > 1 {
+			return Err(String::from("Error setting new menu: The menu bar is borrowed elsewhere."));
+		}
+		replace_menu_bar(self.hwnd, Some(menu))
+	}
+	
+	pub fn remove(self) -> Result<(), String> {
+		if menu_bar_borrows(self.hwnd) > 1 {
+			return Err(String::from("Error removing menu: The menu bar is borrowed elsewhere."));
+		}
+		replace_menu_bar(self.hwnd, None)
+	}
+}
+impl<'a> std::ops::Deref for MenuBar<'a> {
+	type Target = MenuRef<'a>;
+	
+	fn deref(&self) -> &MenuRef<'a> {
+		&self.menu
+	}
+}
+impl Drop for MenuBar<'_> {
+	fn drop(&mut self) {
+		update_menu_bar_borrows(self.hwnd, -1);
+	}
+}
+
+fn replace_menu_bar(hwnd: HWND, menu: Option<Menu>) -> Result<(), String> {
+	let old_hmenu = unsafe { GetMenu(hwnd) };
+	match menu {
+		Some(menu) => {
+			unsafe { SetMenu(hwnd, menu.hmenu) }.map_err(|err| format!("Error setting new menu: {err}"))?;
+			menu.into_hmenu();
+		}
+		None => unsafe { SetMenu(hwnd, None) }.map_err(|err| format!("Error removing menu: {err}"))?
+	}
+	if !old_hmenu.is_invalid() {
+		menu_graphics::destroy_menu(old_hmenu);
+	}
+	Ok(())
+}
+
+// A menu owned by a window or a parent menu. It can't be cloned, so it never outlives the borrow it came from. Submenus
+// and edits that remove entries need it mutably, so a submenu can't be destroyed while another `MenuRef` to it exists.
+#[derive(Debug)]
+pub struct MenuRef<'a> {
+	hmenu: HMENU,
+	// The window whose menu bar this is or belongs to, which has to be redrawn after changes.
+	window: Option<HWND>,
+	owner: PhantomData<&'a Menu>
+}
+impl MenuRef<'_> {
+	fn from_hmenu(hmenu: HMENU) -> Self {
+		Self { hmenu, window: None, owner: PhantomData }
 	}
 	
-	pub fn get_submenu(&self, index: u32) -> Option<Menu> {
+	pub fn get_submenu(&mut self, index: u32) -> Option<MenuRef<'_>> {
 		let hmenu = unsafe { GetSubMenu(self.hmenu, index as i32) };
 		if hmenu.is_invalid() {
 			None
 		} else {
-			Some(Menu { hmenu })
+			Some(MenuRef { hmenu, window: self.window, owner: PhantomData })
+		}
+	}
+	
+	// Other `MenuBar`s of the same window can hold submenus that the edit would destroy.
+	pub(crate) fn check_exclusive(&self) -> Result<(), String> {
+		match self.window {
+			Some(hwnd) if menu_bar_borrows(hwnd) > 1 => Err(String::from("The menu bar is borrowed elsewhere.")),
+			_ => Ok(())
 		}
 	}
 	
@@ -82,19 +280,35 @@ impl Menu {
 	}
 	
 	pub fn add_submenu(&self, submenu: Menu, text: &str) -> Result<(), String> {
-		unsafe { AppendMenuW(self.hmenu, MF_POPUP, submenu.hmenu.0 as usize, &HSTRING::from(text)) }.map_err(|err| format!("Error adding submenu: {err}"))
+		unsafe { AppendMenuW(self.hmenu, MF_POPUP, submenu.hmenu.0 as usize, &HSTRING::from(text)) }.map_err(|err| format!("Error adding submenu: {err}"))?;
+		submenu.into_hmenu();
+		Ok(())
 	}
 	
 	pub fn add_separator(&self) -> Result<(), String> {
 		unsafe { AppendMenuW(self.hmenu, MF_SEPARATOR, 0, PCWSTR(std::ptr::null())) }.map_err(|err| format!("Error adding menu separator: {err}"))
 	}
 	
-	pub fn replace_item(&self, id: u16, new_id: u16, text: &str) -> Result<(), String> {
-		unsafe { ModifyMenuW(self.hmenu, internalize_id(id)?, MF_STRING, internalize_id(new_id)? as usize, &HSTRING::from(text)) }.map_err(|err| format!("Error editing menu item: {err}"))
+	pub fn replace_item(&mut self, id: u16, new_id: u16, text: &str) -> Result<(), String> {
+		self.check_exclusive().map_err(|err| format!("Error editing menu item: {err}"))?;
+		let (wid, new_wid) = (internalize_id(id)?, internalize_id(new_id)?);
+		let owner = find_item(self.hmenu, wid).map(|(hmenu, _)| hmenu);
+		unsafe { ModifyMenuW(self.hmenu, wid, MF_STRING, new_wid as usize, &HSTRING::from(text)) }.map_err(|err| format!("Error editing menu item: {err}"))?;
+		if let Some(hmenu) = owner {
+			menu_graphics::item_replaced(hmenu, wid, new_wid);
+		}
+		Ok(())
 	}
 	
-	pub fn remove_item(&self, id: u16) -> Result<(), String> {
-		unsafe { ModifyMenuW(self.hmenu, internalize_id(id)?, MF_REMOVE, 0, PCWSTR(std::ptr::null())) }.map_err(|err| format!("Error removing menu item: {err}"))
+	pub fn remove_item(&mut self, id: u16) -> Result<(), String> {
+		self.check_exclusive().map_err(|err| format!("Error removing menu item: {err}"))?;
+		let wid = internalize_id(id)?;
+		let owner = find_item(self.hmenu, wid).map(|(hmenu, _)| hmenu);
+		unsafe { ModifyMenuW(self.hmenu, wid, MF_REMOVE, 0, PCWSTR(std::ptr::null())) }.map_err(|err| format!("Error removing menu item: {err}"))?;
+		if let Some(hmenu) = owner {
+			menu_graphics::release_item_resources(hmenu, wid);
+		}
+		Ok(())
 	}
 	
 	pub fn set_item_check(&self, id: u16, checked: bool) -> Result<(), String> {
@@ -139,6 +353,67 @@ impl Menu {
 		self.set_type_flag(internalize_id(id)?, MFT_RIGHTJUSTIFY, state).map_err(|err| format!("Error setting menu item right justify: {err}"))
 	}
 	
+	pub fn add_radio_group(&self, ids: &[u16], labels: &[&str], selected: u16) -> Result<(), String> {
+		if ids.len() != labels.len() {
+			return Err(String::from("Error adding radio group: every ID needs exactly one label."));
+		}
+		if !ids.contains(&selected) {
+			return Err(format!("Error adding radio group: selected ID {selected} is not in the group."));
+		}
+		// Groups are only told apart by the items between them, so this one would merge with the last one.
+		let count = self.item_count();
+		if count > 0 && menu_item_info(self.hmenu, count - 1, MIIM_FTYPE).is_ok_and(|mii| mii.fType & MFT_RADIOCHECK == MFT_RADIOCHECK) {
+			return Err(String::from("Error adding radio group: the menu ends in another radio group, add an item or separator in between."));
+		}
+		for (&id, label) in ids.iter().zip(labels) {
+			self.add_item(id, label)?;
+			self.set_type_flag(internalize_id(id)?, MFT_RADIOCHECK, true).map_err(|err| format!("Error adding radio group: {err}"))?;
+		}
+		self.select_radio(selected)
+	}
+	
+	pub fn select_radio(&self, id: u16) -> Result<(), String> {
+		let (hmenu, first, last, position) = radio_group(self.hmenu, internalize_id(id)?).ok_or_else(|| format!("Error selecting radio item: {id} is not in a radio group."))?;
+		unsafe { CheckMenuRadioItem(hmenu, first, last, position, MF_BYPOSITION.0) }.map_err(|err| format!("Error selecting radio item: {err}"))
+	}
+	
+	pub fn selected_radio(&self, id: u16) -> Result<Option<u16>, String> {
+		let (hmenu, first, last, _) = radio_group(self.hmenu, internalize_id(id)?).ok_or_else(|| format!("Error reading radio group: {id} is not in a radio group."))?;
+		for position in first..=last {
+			let mii = menu_item_info(hmenu, position, MIIM_STATE | MIIM_ID).map_err(|err| format!("Error reading radio group: {err}"))?;
+			if mii.fState & MFS_CHECKED == MFS_CHECKED {
+				return Ok((mii.wID as u16).checked_sub(0xF001));
+			}
+		}
+		Ok(None)
+	}
+	
+	pub fn item_text(&self, id: u16) -> Result<String, String> {
+		let (hmenu, position) = find_item(self.hmenu, internalize_id(id)?).ok_or_else(|| format!("Error reading menu item: {id} is not in the menu."))?;
+		menu_item_text(hmenu, position).map_err(|err| format!("Error reading menu item text: {err}"))
+	}
+	
+	pub fn is_checked(&self, id: u16) -> Result<bool, String> {
+		let (hmenu, position) = find_item(self.hmenu, internalize_id(id)?).ok_or_else(|| format!("Error reading menu item: {id} is not in the menu."))?;
+		let mii = menu_item_info(hmenu, position, MIIM_STATE).map_err(|err| format!("Error reading menu item state: {err}"))?;
+		Ok(mii.fState & MFS_CHECKED == MFS_CHECKED)
+	}
+	
+	pub fn is_enabled(&self, id: u16) -> Result<bool, String> {
+		let (hmenu, position) = find_item(self.hmenu, internalize_id(id)?).ok_or_else(|| format!("Error reading menu item: {id} is not in the menu."))?;
+		let mii = menu_item_info(hmenu, position, MIIM_STATE).map_err(|err| format!("Error reading menu item state: {err}"))?;
+		Ok((mii.fState & MFS_DISABLED).0 == 0)
+	}
+	
+	pub fn find_by_id(&mut self, id: u16) -> Option<(MenuRef<'_>, u32)> {
+		let (hmenu, position) = find_item(self.hmenu, internalize_id(id).ok()?)?;
+		Some((MenuRef { hmenu, window: self.window, owner: PhantomData }, position))
+	}
+	
+	pub fn items(&self) -> MenuItems<'_> {
+		MenuItems { menu: MenuRef::from_hmenu(self.hmenu), position: 0 }
+	}
+	
 	fn set_type_flag(&self, wid: u32, flag: MENU_ITEM_TYPE, state: bool) -> windows::core::Result<()> {
 		let mut mii = MENUITEMINFOW {
 			cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
@@ -156,6 +431,113 @@ impl Menu {
 }
 
 
+fn menu_item_info(hmenu: HMENU, position: u32, mask: MENU_ITEM_MASK) -> windows::core::Result<MENUITEMINFOW> {
+	let mut mii = MENUITEMINFOW {
+		cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+		fMask: mask,
+		..Default::default()
+	};
+	unsafe { GetMenuItemInfoW(hmenu, position, TRUE, &mut mii as *mut MENUITEMINFOW) }?;
+	Ok(mii)
+}
+fn menu_item_text(hmenu: HMENU, position: u32) -> windows::core::Result<String> {
+	let mut mii = menu_item_info(hmenu, position, MIIM_STRING)?;
+	let mut text = vec![0u16; mii.cch as usize + 1];
+	mii.cch += 1;
+	mii.dwTypeData = PWSTR(text.as_mut_ptr());
+	unsafe { GetMenuItemInfoW(hmenu, position, TRUE, &mut mii as *mut MENUITEMINFOW) }?;
+	Ok(String::from_utf16_lossy(&text[..mii.cch as usize]))
+}
+
+// Searches the menu and its submenus for an item, returning the menu that directly contains it and its position there.
+fn find_item(hmenu: HMENU, wid: u32) -> Option<(HMENU, u32)> {
+	for position in 0..unsafe { GetMenuItemCount(hmenu) }.max(0) {
+		if unsafe { GetMenuItemID(hmenu, position) } == wid {
+			return Some((hmenu, position as u32));
+		}
+		let submenu = unsafe { GetSubMenu(hmenu, position) };
+		if !submenu.is_invalid() {
+			if let Some(found) = find_item(submenu, wid) {
+				return Some(found);
+			}
+		}
+	}
+	None
+}
+
+// A radio group is a run of consecutive radio items, adding groups rejects two in a row. Returns the containing menu, the first and last position of the run and the item's own position.
+fn radio_group(hmenu: HMENU, wid: u32) -> Option<(HMENU, u32, u32, u32)> {
+	let is_radio = |hmenu: HMENU, position: u32| menu_item_info(hmenu, position, MIIM_FTYPE).is_ok_and(|mii| mii.fType & MFT_RADIOCHECK == MFT_RADIOCHECK);
+	
+	let (hmenu, position) = find_item(hmenu, wid)?;
+	if !is_radio(hmenu, position) {
+		return None;
+	}
+	let count = unsafe { GetMenuItemCount(hmenu) } as u32;
+	let mut first = position;
+	while first > 0 && is_radio(hmenu, first - 1) {
+		first -= 1;
+	}
+	let mut last = position;
+	while last + 1 < count && is_radio(hmenu, last + 1) {
+		last += 1;
+	}
+	Some((hmenu, first, last, position))
+}
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MenuItemKind {
+	String,
+	Separator,
+	Submenu
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct MenuItem {
+	pub position: u32,
+	pub kind: MenuItemKind,
+	pub id: Option<u16>,
+	pub text: String
+}
+
+#[derive(Debug)]
+pub struct MenuItems<'a> {
+	menu: MenuRef<'a>,
+	position: u32
+}
+impl Iterator for MenuItems<'_> {
+	type Item = MenuItem;
+	
+	fn next(&mut self) -> Option<MenuItem> {
+		if self.position >= self.menu.item_count() {
+			return None;
+		}
+		let position = self.position;
+		self.position += 1;
+		
+		let mii = menu_item_info(self.menu.hmenu, position, MIIM_FTYPE | MIIM_ID | MIIM_SUBMENU).ok()?;
+		let kind = if mii.fType & MFT_SEPARATOR == MFT_SEPARATOR {
+			MenuItemKind::Separator
+		} else if !mii.hSubMenu.is_invalid() {
+			MenuItemKind::Submenu
+		} else {
+			MenuItemKind::String
+		};
+		Some(MenuItem {
+			position,
+			kind,
+			id: match kind {
+				MenuItemKind::String => (mii.wID as u16).checked_sub(0xF001),
+				_ => None
+			},
+			text: match kind {
+				MenuItemKind::Separator => String::new(),
+				_ => menu_item_text(self.menu.hmenu, position).unwrap_or_default()
+			}
+		})
+	}
+}
 
 #[derive(Debug)]
 pub struct WindowHandle { hwnd: HWND }
@@ -169,40 +551,97 @@ impl WindowHandle {
 		unsafe { InvalidateRect(self.hwnd, None, false) };
 	}
 	
-	pub fn get_menu(&self) -> Option<Menu> {
+	pub fn get_menu(&self) -> Option<MenuBar<'_>> {
 		let hmenu = unsafe { GetMenu(self.hwnd) };
 		if hmenu.is_invalid() {
-			None
-		} else {
-			Some(Menu { hmenu })
+			return None;
 		}
+		update_menu_bar_borrows(self.hwnd, 1);
+		Some(MenuBar { hwnd: self.hwnd, menu: MenuRef { hmenu, window: Some(self.hwnd), owner: PhantomData } })
 	}
 	
+	// Fails while a `MenuBar` from `get_menu` is alive, use `MenuBar::replace` then.
 	pub fn set_menu(&self, menu: Menu) -> Result<(), String> {
-		if let Some(menu) = self.get_menu() {
-			unsafe { DestroyMenu(menu.hmenu) }.unwrap_or(());
+		if menu_bar_borrows(self.hwnd) > 0 {
+			return Err(String::from("Error setting new menu: The menu bar is borrowed."));
 		}
-		unsafe { SetMenu(self.hwnd, menu.hmenu) }.map_err(|err| format!("Error setting new menu: {err}"))
+		replace_menu_bar(self.hwnd, Some(menu))
 	}
 	
-	pub fn remove_menu(&self) {
-		if let Some(menu) = self.get_menu() {
-			unsafe { DestroyMenu(menu.hmenu) }.unwrap_or(());
+	pub fn remove_menu(&self) -> Result<(), String> {
+		if menu_bar_borrows(self.hwnd) > 0 {
+			return Err(String::from("Error removing menu: The menu bar is borrowed."));
 		}
+		if unsafe { GetMenu(self.hwnd) }.is_invalid() {
+			return Ok(());
+		}
+		replace_menu_bar(self.hwnd, None)
 	}
 	
 	pub fn redraw_menu(&self) -> Result<(), String> {
 		unsafe { DrawMenuBar(self.hwnd) }.map_err(|err| format!("Error drawing menu bar: {err}"))
 	}
+	
+	// The menu should be a popup menu from `Menu::new_popup` or `MenuSpec::build_popup`, TrackPopupMenu is only documented for those.
+	pub fn show_context_menu(&self, menu: &MenuRef, x: i16, y: i16) -> Result<(), String> {
+		self.track_popup_menu(menu, x, y, context_menu_flags(false)).map(|_| ())
+	}
+	
+	pub fn track_context_menu(&self, menu: &MenuRef, x: i16, y: i16) -> Result<Option<u16>, String> {
+		let wid = self.track_popup_menu(menu, x, y, context_menu_flags(true))?;
+		Ok((wid as u16).checked_sub(0xF001))
+	}
+	
+	fn track_popup_menu(&self, menu: &MenuRef, x: i16, y: i16, flags: TRACK_POPUP_MENU_FLAGS) -> Result<i32, String> {
+		let mut origin = POINT::default();
+		unsafe {
+			ClientToScreen(self.hwnd, &mut origin);
+			SetForegroundWindow(self.hwnd);
+		}
+		let point = client_to_screen(origin, x, y);
+		let result = unsafe { TrackPopupMenu(menu.hmenu, flags, point.x, point.y, 0, self.hwnd, None) };
+		// Without a message after the menu, it doesn't close when the user clicks outside it (KB135788).
+		let _ = unsafe { PostMessageW(self.hwnd, WM_NULL, WPARAM(0), LPARAM(0)) };
+		if flags & TPM_RETURNCMD == TPM_RETURNCMD {
+			Ok(result.0)
+		} else if result.as_bool() {
+			Ok(0)
+		} else {
+			Err(format!("Error showing context menu: {}", Error::from_win32()))
+		}
+	}
+}
+
+// With `returns_command` the chosen item's ID is returned instead of sent as WM_COMMAND.
+pub(crate) fn context_menu_flags(returns_command: bool) -> TRACK_POPUP_MENU_FLAGS {
+	let flags = TPM_LEFTALIGN | TPM_TOPALIGN | TPM_RIGHTBUTTON;
+	match returns_command {
+		true => flags | TPM_RETURNCMD | TPM_NONOTIFY,
+		false => flags
+	}
+}
+
+// `origin` is where the client area's top left corner is on the screen.
+pub(crate) fn client_to_screen(origin: POINT, x: i16, y: i16) -> POINT {
+	POINT { x: origin.x + x as i32, y: origin.y + y as i32 }
 }
 
 
 struct App {
 	window_handle: WindowHandle,
 	client_rect: Rect,
-	bitmap: Option<HBITMAP>,
-	pixel_buffer: std::mem::ManuallyDrop<Box<[u8]>>,
-	user_state: Box<dyn SimpleWindowApp>
+	pixel_buffer: pixel_buffer::PixelBuffer,
+	user_state: Box<dyn SimpleWindowApp>,
+	// For `AppAccess` to check the type it casts the state to.
+	user_state_type: std::any::TypeId,
+	// Set once a callback panicked, after that the app gets no more messages.
+	panic: Option<String>,
+	// Callbacks running for the window, more than one while a modal loop inside a callback dispatches messages.
+	dispatch_depth: usize,
+	// Set while a callback or `AppAccess::with` has the state, so the other can't borrow it as well.
+	state_borrowed: bool,
+	// Messages that arrived while the state was borrowed, handled once it's free again.
+	deferred_messages: VecDeque<(u32, WPARAM, LPARAM)>
 }
 
 unsafe extern "system" fn wnd_proc(window: HWND, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
@@ -210,38 +649,171 @@ unsafe extern "system" fn wnd_proc(window: HWND, message: u32, wparam: WPARAM, l
 		let cs = lparam.0 as *const CREATESTRUCTW;
 		let app_ptr = (*cs).lpCreateParams as *mut App;
 		(*app_ptr).window_handle.hwnd = window;
-
+		
 		SetWindowLongPtrW(window, GWLP_USERDATA, app_ptr as isize);
 	} else {
-		let app_ptr = GetWindowLongPtrW(window, GWLP_USERDATA) as *mut c_void;
+		let app_ptr = GetWindowLongPtrW(window, GWLP_USERDATA) as *mut App;
 		if !app_ptr.is_null() {
-			return handle_message(app_ptr, message, wparam, lparam);
+			return dispatch_message(window, app_ptr, message, wparam, lparam);
+		}
+	}
+	DefWindowProcW(window, message, wparam, lparam)
+}
+
+// Also used for deferred messages, which can come after the window is gone and can't be looked up through it anymore.
+unsafe fn dispatch_message(window: HWND, app_ptr: *mut App, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+	if (*app_ptr).panic.is_some() {
+		match message {
+			event_proxy::WM_USER_EVENT | recording::WM_RECORDING_ERROR | WindowsAndMessaging::WM_DROPFILES => {
+				discard_message(message, wparam, lparam);
+				return LRESULT(0);
+			}
+			WindowsAndMessaging::WM_DESTROY => destroy_menu_bar(window),
+			_ => {}
+		}
+		return DefWindowProcW(window, message, wparam, lparam);
+	}
+	// Tasks only borrow the state through `AppAccess::with`.
+	if (*app_ptr).state_borrowed && message != tasks::WM_WAKE_TASK {
+		return handle_nested_message(window, app_ptr, message, wparam, lparam);
+	}
+	
+	// Unwinding out of the window procedure is undefined behaviour, a panicking callback closes the window instead.
+	let outer_borrow = (*app_ptr).state_borrowed;
+	(*app_ptr).state_borrowed = message != tasks::WM_WAKE_TASK;
+	(*app_ptr).dispatch_depth += 1;
+	let result = std::panic::catch_unwind(AssertUnwindSafe(|| handle_message(app_ptr as *mut c_void, message, wparam, lparam)));
+	(*app_ptr).dispatch_depth -= 1;
+	(*app_ptr).state_borrowed = outer_borrow;
+	match result {
+		Ok(result) if (*app_ptr).panic.is_none() => {
+			handle_deferred_messages(window, app_ptr);
+			return result;
+		}
+		Ok(_) => {}
+		Err(payload) => (*app_ptr).panic = Some(panic_message(payload))
+	}
+	for (message, wparam, lparam) in (*app_ptr).deferred_messages.drain(..) {
+		discard_message(message, wparam, lparam);
+	}
+	// Quitting ends the modal loops of the callbacks further out, the window is only destroyed once the outermost one has returned.
+	PostQuitMessage(0);
+	if (*app_ptr).dispatch_depth == 0 {
+		let _ = DestroyWindow(window);
+	}
+	LRESULT(0)
+}
+
+// A callback further out has the state while its modal loop dispatches messages, so they can't reach it. Painting shows the
+// bitmap as it is, and the messages that need a callback wait for the state. The rest only get the default handling.
+unsafe fn handle_nested_message(window: HWND, app_ptr: *mut App, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
+	match message {
+		WindowsAndMessaging::WM_ACTIVATE | WindowsAndMessaging::WM_NCACTIVATE | WindowsAndMessaging::WM_CAPTURECHANGED => {
+			update_capture(window, message, wparam);
+		}
+		WindowsAndMessaging::WM_MEASUREITEM if menu_graphics::measure_item(lparam) => {
+			return LRESULT(1);
+		}
+		WindowsAndMessaging::WM_DRAWITEM if menu_graphics::draw_item(lparam) => {
+			return LRESULT(1);
+		}
+		WindowsAndMessaging::WM_PAINT => {
+			// Fields are reached through the pointer one by one, the callback still has the pixels.
+			let _ = paint_window(window, (*app_ptr).pixel_buffer.bitmap(), &(*app_ptr).client_rect);
+			return LRESULT(0);
+		}
+		// The menu bar has to be taken off before the window is gone, only `on_exit` waits.
+		WindowsAndMessaging::WM_DESTROY => {
+			destroy_menu_bar(window);
+			(*app_ptr).deferred_messages.push_back((message, wparam, lparam));
+			return LRESULT(0);
+		}
+		_ if is_deferred_while_borrowed(message) => {
+			(*app_ptr).deferred_messages.push_back((message, wparam, lparam));
+			return LRESULT(0);
 		}
+		_ => {}
 	}
 	DefWindowProcW(window, message, wparam, lparam)
 }
 
+// Messages whose callback can still run late. Timers fire again, input and menu notifications are only meaningful right away.
+pub(crate) fn is_deferred_while_borrowed(message: u32) -> bool {
+	matches!(message, WindowsAndMessaging::WM_COMMAND | WindowsAndMessaging::WM_SIZE | WindowsAndMessaging::WM_CLOSE | WindowsAndMessaging::WM_DROPFILES | event_proxy::WM_USER_EVENT | recording::WM_RECORDING_ERROR)
+}
+
+unsafe fn handle_deferred_messages(window: HWND, app_ptr: *mut App) {
+	while !(*app_ptr).state_borrowed && (*app_ptr).panic.is_none() {
+		let Some((message, wparam, lparam)) = (*app_ptr).deferred_messages.pop_front() else {
+			break;
+		};
+		dispatch_message(window, app_ptr, message, wparam, lparam);
+	}
+}
+
+fn update_capture(hwnd: HWND, message: u32, wparam: WPARAM) {
+	match message {
+		WindowsAndMessaging::WM_ACTIVATE if wparam.0 as u32 & 0xFFFF != WindowsAndMessaging::WA_INACTIVE => unsafe { SetCapture(hwnd); },
+		WindowsAndMessaging::WM_NCACTIVATE if wparam.0 as u32 != WindowsAndMessaging::WA_INACTIVE => unsafe { SetCapture(hwnd); },
+		_ => {
+			let _ = unsafe { ReleaseCapture() };
+		}
+	}
+}
+
+fn paint_window(hwnd: HWND, bitmap: Option<HBITMAP>, client_rect: &Rect) -> Result<(), Error> {
+	unsafe {
+		let mut ps = PAINTSTRUCT::default();
+		let hdc = BeginPaint(hwnd, &mut ps);
+		
+		let mut result = Ok(());
+		if let Some(bitmap) = bitmap {
+			let memory_dc = CreateCompatibleDC(hdc);
+			SelectObject(memory_dc, bitmap);
+			result = BitBlt(hdc, 0, 0, client_rect.width(), client_rect.height(), memory_dc, 0, 0, SRCCOPY);
+			DeleteDC(memory_dc);
+		}
+		
+		EndPaint(hwnd, &mut ps);
+		result
+	}
+}
+
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+	match payload.downcast::<String>() {
+		Ok(message) => *message,
+		Err(payload) => match payload.downcast::<&str>() {
+			Ok(message) => String::from(*message),
+			Err(_) => String::from("Unknown panic.")
+		}
+	}
+}
+
+// The system menu's items aren't ours, their IDs would be mistaken for command IDs.
+pub(crate) fn is_system_menu_popup(lparam: LPARAM) -> bool {
+	(lparam.0 >> 16) as u16 != 0
+}
+
+// Decodes WM_MENUSELECT into the command ID to report, if any. Returns None when nothing should be reported,
+// that is when the menu is closing, which WM_EXITMENULOOP reports instead, or when it's the system menu.
+pub(crate) fn menu_hover(wparam: WPARAM, lparam: LPARAM) -> Option<Option<u16>> {
+	let flags = (wparam.0 >> 16) as u32 & 0xFFFF;
+	if (flags == 0xFFFF && lparam.0 == 0) || flags & MF_SYSMENU.0 != 0 {
+		return None;
+	}
+	match flags & (MF_POPUP.0 | MF_SEPARATOR.0) {
+		0 => Some((wparam.0 as u16).checked_sub(0xF001)),
+		_ => Some(None)
+	}
+}
 
 fn handle_message(app_ptr: *mut c_void, message: u32, wparam: WPARAM, lparam: LPARAM) -> LRESULT {
 	let app = unsafe { &mut *(app_ptr as *mut App) };
 	
 	match message {
-		WindowsAndMessaging::WM_ACTIVATE => {
-			if wparam.0 as u32 & 0xFFFF == WindowsAndMessaging::WA_INACTIVE {
-				unsafe { ReleaseCapture() }.unwrap();
-			} else {
-				unsafe { SetCapture(app.window_handle.hwnd) };
-			}
-		}
-		WindowsAndMessaging::WM_CAPTURECHANGED => {
-			unsafe { ReleaseCapture() }.unwrap();
-		}
-		WindowsAndMessaging::WM_NCACTIVATE => {
-			if wparam.0 as u32 == WindowsAndMessaging::WA_INACTIVE {
-				unsafe { ReleaseCapture() }.unwrap();
-			} else {
-				unsafe { SetCapture(app.window_handle.hwnd) };
-			}
+		WindowsAndMessaging::WM_ACTIVATE | WindowsAndMessaging::WM_NCACTIVATE | WindowsAndMessaging::WM_CAPTURECHANGED => {
+			update_capture(app.window_handle.hwnd, message, wparam);
 		}
 		WindowsAndMessaging::WM_COMMAND => {
 			match (wparam.0 as u16).checked_sub(0xF001) {
@@ -249,6 +821,26 @@ fn handle_message(app_ptr: *mut c_void, message: u32, wparam: WPARAM, lparam: LP
 				None => ()
 			}
 		}
+		WindowsAndMessaging::WM_MEASUREITEM if menu_graphics::measure_item(lparam) => {
+			return LRESULT(1);
+		}
+		WindowsAndMessaging::WM_DRAWITEM if menu_graphics::draw_item(lparam) => {
+			return LRESULT(1);
+		}
+		WindowsAndMessaging::WM_INITMENUPOPUP if !is_system_menu_popup(lparam) => {
+			// Counts as a borrow of the menu bar, so the opening menu isn't destroyed by the callback.
+			update_menu_bar_borrows(app.window_handle.hwnd, 1);
+			app.user_state.on_menu_opening(&app.window_handle, &MenuRef::from_hmenu(HMENU(wparam.0 as isize)));
+			update_menu_bar_borrows(app.window_handle.hwnd, -1);
+		}
+		WindowsAndMessaging::WM_MENUSELECT => {
+			if let Some(command_id) = menu_hover(wparam, lparam) {
+				app.user_state.on_menu_hover(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, command_id);
+			}
+		}
+		WindowsAndMessaging::WM_EXITMENULOOP => {
+			app.user_state.on_menu_closed(&app.window_handle, &mut app.pixel_buffer, &app.client_rect);
+		}
 		WindowsAndMessaging::WM_TIMER => {
 			unsafe { KillTimer(app.window_handle.hwnd, wparam.0) }.unwrap_or_else(|e| app.user_state.on_error(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &format!("Error stopping timer {}: {}", wparam.0, e)));
 			app.user_state.on_timer(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, wparam.0);
@@ -257,45 +849,21 @@ fn handle_message(app_ptr: *mut c_void, message: u32, wparam: WPARAM, lparam: LP
 			let mut rect = Rect::default();
 			if let Err(e) = unsafe { GetClientRect(app.window_handle.hwnd, &mut rect as *mut Rect as *mut RECT) } {
 				app.user_state.on_error(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &format!("Error getting window size: {e}"));
-				
+			
 			} else if rect.width() > 0 && rect.height() > 0 {
+				let old_rect = app.client_rect;
 				app.client_rect = rect;
+				let settings = pixel_buffer::settings(app.window_handle.hwnd);
+				app.pixel_buffer.set_back_buffered(settings.back_buffer);
 				
-				let buffer_size = (app.client_rect.width() * app.client_rect.height() * 4) as usize;
-				
-				if let Some(bitmap) = app.bitmap {
-					unsafe { DeleteObject(bitmap) };
-				}
-				
-				let bmi = BITMAPINFO {
-					bmiHeader: BITMAPINFOHEADER {
-						biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
-						biWidth: app.client_rect.width() as i32,
-						biHeight: -(app.client_rect.height() as i32),
-						biPlanes: 1,
-						biBitCount: 32,
-						biCompression: BI_RGB.0,
-						biSizeImage: 0,
-						biXPelsPerMeter: 0,
-						biYPelsPerMeter: 0,
-						biClrUsed: 0,
-						biClrImportant: 0
-					},
-					bmiColors: [RGBQUAD::default(); 1]
+				// Without a bitmap the callbacks get an empty buffer until the next resize.
+				let (front, error) = match dib_section::DibSection::new(app.window_handle.hwnd, app.client_rect.width(), app.client_rect.height()) {
+					Ok(front) => (front, None),
+					Err(err) => (dib_section::DibSection::empty(), Some(err))
 				};
-				
-				let mut pixel_data_pointer: *mut c_void = std::ptr::null_mut();
-				unsafe {
-					let dc = GetDC(app.window_handle.hwnd);
-					app.bitmap = match CreateDIBSection(dc, &bmi, DIB_RGB_COLORS, &mut pixel_data_pointer, None, 0) {
-						Ok(bitmap) => Some(bitmap),
-						Err(e) => {
-							app.user_state.on_error(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &format!("Error reallocating bitmap: {e}"));
-							None
-						}
-					};
-					ReleaseDC(app.window_handle.hwnd, dc);
-					app.pixel_buffer = std::mem::ManuallyDrop::new(Vec::from_raw_parts(pixel_data_pointer as *mut u8, buffer_size, buffer_size).into_boxed_slice());
+				app.pixel_buffer.replace_front(front, &old_rect, &app.client_rect, settings.resize_contents);
+				if let Some(err) = error {
+					app.user_state.on_error(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &err);
 				}
 				
 				app.user_state.on_resize(&app.window_handle, &mut app.pixel_buffer, &app.client_rect);
@@ -341,24 +909,40 @@ fn handle_message(app_ptr: *mut c_void, message: u32, wparam: WPARAM, lparam: LP
 			app.user_state.on_scroll(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, (wparam.0 >> 16) as i16);
 		}
 		WindowsAndMessaging::WM_PAINT => {
+			app.pixel_buffer.set_back_buffered(pixel_buffer::settings(app.window_handle.hwnd).back_buffer);
 			app.user_state.on_paint(&app.window_handle, &mut app.pixel_buffer, &app.client_rect);
+			app.pixel_buffer.present();
+			recording::capture_frame(app.window_handle.hwnd, &app.pixel_buffer, &app.client_rect);
 			
-			unsafe {
-				let mut ps = PAINTSTRUCT::default();
-				let hdc = BeginPaint(app.window_handle.hwnd, &mut ps);
-				
-				if let Some(bitmap) = app.bitmap {
-					let memory_dc = CreateCompatibleDC(hdc);
-					SelectObject(memory_dc, bitmap);
-					BitBlt(hdc, 0, 0, app.client_rect.width(), app.client_rect.height(), memory_dc, 0, 0, SRCCOPY).unwrap_or_else(|e| app.user_state.on_error(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &format!("Couldn't draw pixel buffer: {e}")));
-					DeleteDC(memory_dc);
-				}
-				
-				EndPaint(app.window_handle.hwnd, &mut ps);
+			paint_window(app.window_handle.hwnd, app.pixel_buffer.bitmap(), &app.client_rect).unwrap_or_else(|e| app.user_state.on_error(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &format!("Couldn't draw pixel buffer: {e}")));
+		}
+		WindowsAndMessaging::WM_DROPFILES => {
+			let (paths, mouse_x, mouse_y) = file_drop::take_dropped_files(wparam);
+			app.user_state.on_files_dropped(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &paths, mouse_x, mouse_y);
+			return LRESULT(0);
+		}
+		event_proxy::WM_USER_EVENT => {
+			if let Some(payload) = tasks::deliver_user_event(app.window_handle.hwnd, event_proxy::take_payload(lparam)) {
+				app.user_state.on_user_event(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, payload);
 			}
+			return LRESULT(0);
+		}
+		tasks::WM_WAKE_TASK => {
+			tasks::poll_task(wparam.0, app);
+			return LRESULT(0);
+		}
+		recording::WM_RECORDING_ERROR => {
+			let error_message = unsafe { Box::from_raw(lparam.0 as *mut String) };
+			app.user_state.on_error(&app.window_handle, &mut app.pixel_buffer, &app.client_rect, &error_message);
+			return LRESULT(0);
+		}
+		// Letting WM_CLOSE through to DefWindowProcW destroys the window.
+		WindowsAndMessaging::WM_CLOSE if !app.user_state.on_close_requested(&app.window_handle, &mut app.pixel_buffer, &app.client_rect) => {
+			return LRESULT(0);
 		}
 		WindowsAndMessaging::WM_DESTROY => {
 			app.user_state.on_exit(&app.window_handle);
+			destroy_menu_bar(app.window_handle.hwnd);
 			
 			unsafe { PostQuitMessage(0) };
 			return LRESULT(0);
@@ -370,8 +954,27 @@ fn handle_message(app_ptr: *mut c_void, message: u32, wparam: WPARAM, lparam: LP
 
 
 
+// Destroying the window would destroy its menu bar without releasing the resources of its items, so it's taken off first.
+pub(crate) fn destroy_menu_bar(hwnd: HWND) {
+	let menu = unsafe { GetMenu(hwnd) };
+	if !menu.is_invalid() && unsafe { SetMenu(hwnd, None) }.is_ok() {
+		menu_graphics::destroy_menu(menu);
+	}
+}
+
+// Frees what a message posted to the window owns, for messages that can't be handled because the window is gone.
+fn discard_message(message: u32, wparam: WPARAM, lparam: LPARAM) {
+	match message {
+		event_proxy::WM_USER_EVENT => drop(event_proxy::take_payload(lparam)),
+		recording::WM_RECORDING_ERROR => drop(unsafe { Box::from_raw(lparam.0 as *mut String) }),
+		WindowsAndMessaging::WM_DROPFILES => file_drop::release_dropped_files(wparam),
+		_ => {}
+	}
+}
+
+
 pub fn run_window_process(window_id: &str, window_width: u32, window_height: u32, window_title: &str, always_on_top: bool, app_state: impl SimpleWindowApp + 'static) -> Result<i32, String> {
-	
+
 	unsafe { RoInitialize(RO_INIT_SINGLETHREADED) }.map_err(|e| format!("Error initializing window: {e}"))?;
 	let options = DispatcherQueueOptions {
 		dwSize: std::mem::size_of::<DispatcherQueueOptions>() as u32,
@@ -413,9 +1016,13 @@ pub fn run_window_process(window_id: &str, window_width: u32, window_height: u32
 	let mut app = App {
 		window_handle: WindowHandle{ hwnd: HWND(0) },
 		client_rect: Rect::default(),
-		bitmap: None,
-		pixel_buffer: core::mem::ManuallyDrop::default(),
-		user_state: Box::new(app_state)
+		pixel_buffer: pixel_buffer::PixelBuffer::default(),
+		user_state_type: Any::type_id(&app_state),
+		user_state: Box::new(app_state),
+		panic: None,
+		dispatch_depth: 0,
+		state_borrowed: false,
+		deferred_messages: VecDeque::new()
 	};
 	
 	let window = unsafe { CreateWindowExW(
@@ -439,15 +1046,37 @@ pub fn run_window_process(window_id: &str, window_width: u32, window_height: u32
 	
 	let menu = unsafe { CreateMenu() }.map_err(|err| format!("Error initializing menu: {err}"))?;
 	unsafe { SetMenu(window, menu) }.map_err(|err| format!("Error initializing menu: {err}"))?;
+	file_drop::accept_files(window);
 	
-	app.user_state.on_init(&WindowHandle { hwnd: window });
-	
-	unsafe { ShowWindow(window, SW_SHOW) };
+	// A panic shuts the window down the same way as one in the window procedure, also when it happened in a modal loop inside `on_init`.
+	app.dispatch_depth = 1;
+	app.state_borrowed = true;
+	let init = std::panic::catch_unwind(AssertUnwindSafe(|| app.user_state.on_init(&WindowHandle { hwnd: window })));
+	app.dispatch_depth = 0;
+	app.state_borrowed = false;
+	if let Err(payload) = init {
+		app.panic = Some(panic_message(payload));
+	}
+	if app.panic.is_none() {
+		unsafe { handle_deferred_messages(window, &mut app) };
+	}
+	if app.panic.is_none() {
+		unsafe { ShowWindow(window, SW_SHOW) };
+	} else {
+		unsafe {
+			let _ = DestroyWindow(window);
+			PostQuitMessage(0);
+		}
+	}
 	
 	
 	let mut message = MSG::default();
 	unsafe {
 		while GetMessageW(&mut message, None, 0, 0).into() {
+			if message.hwnd == window && !IsWindow(window).as_bool() {
+				discard_message(message.message, message.wParam, message.lParam);
+				continue;
+			}
 			TranslateMessage(&message);
 			DispatchMessageW(&message);
 		}
@@ -466,14 +1095,35 @@ pub fn run_window_process(window_id: &str, window_width: u32, window_height: u32
 	let mut message = MSG::default();
 	unsafe {
 		while GetMessageW(&mut message, None, 0, 0).into() {
+			if message.hwnd == window && !IsWindow(window).as_bool() {
+				discard_message(message.message, message.wParam, message.lParam);
+				continue;
+			}
 			TranslateMessage(&message);
 			DispatchMessageW(&message);
 		}
 	}
 	
-	if let Some(menu) = app.window_handle.get_menu() {
-		unsafe{ DestroyMenu(menu.hmenu) }.unwrap_or(());
+	for (message, wparam, lparam) in app.deferred_messages.drain(..) {
+		discard_message(message, wparam, lparam);
 	}
 	
-	Ok(message.wParam.0 as i32)
+	// Whatever was posted too late for either loop.
+	let mut pending = MSG::default();
+	while unsafe { PeekMessageW(&mut pending, None, recording::WM_RECORDING_ERROR, event_proxy::WM_USER_EVENT, PM_REMOVE) }.as_bool() {
+		match pending.hwnd == window {
+			true => discard_message(pending.message, pending.wParam, pending.lParam),
+			false => unsafe { DispatchMessageW(&pending); }
+		}
+	}
+	
+	recording::finish_recordings(app.window_handle.hwnd);
+	dialogs::forget_provider(app.window_handle.hwnd);
+	tasks::drop_tasks(app.window_handle.hwnd);
+	pixel_buffer::forget_settings(app.window_handle.hwnd);
+	
+	match app.panic {
+		Some(panic) => Err(format!("Error running window: Callback panicked: {panic}")),
+		None => Ok(message.wParam.0 as i32)
+	}
 }
\ No newline at end of file
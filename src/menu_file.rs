@@ -0,0 +1,259 @@
+use std::{collections::HashMap, path::{Path, PathBuf}, time::SystemTime};
+
+use crate::{Menu, MenuRef, MenuSpec, MenuEntry, MenuItemSpec, internalize_id};
+
+
+// Menu definition format, one statement per line, `#` starts a comment:
+//
+//   command open = 1
+//   command zoom_in = 20
+//
+//   submenu "&File"
+//   	item open "&Open..." shortcut "Ctrl+O"
+//   	item 2 "Autosave" checked
+//   	separator
+//   	radio zoom_in "Zoom in" selected
+//   	radio 21 "Zoom out"
+//   end
+//
+// Items refer to either a declared command name or a numeric ID. Consecutive `radio` lines form one group.
+// Item flags are `checkable`, `checked`, `disabled` and `shortcut "<text>"`, radio items also accept `selected`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MenuDefinition {
+	pub spec: MenuSpec,
+	pub commands: HashMap<String, u16>
+}
+impl MenuDefinition {
+	pub fn parse(source: &str) -> Result<Self, String> {
+		Parser { commands: HashMap::new() }.parse(source)
+	}
+	
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+		let path = path.as_ref();
+		let source = std::fs::read_to_string(path).map_err(|err| format!("Error reading menu file {}: {err}", path.display()))?;
+		Self::parse(&source).map_err(|err| format!("{}: {err}", path.display()))
+	}
+	
+	pub fn command_id(&self, name: &str) -> Option<u16> {
+		self.commands.get(name).copied()
+	}
+	
+	pub fn command_name(&self, id: u16) -> Option<&str> {
+		self.commands.iter().find(|(_, command_id)| **command_id == id).map(|(name, _)| name.as_str())
+	}
+}
+
+
+// Keeps a menu definition file in sync with a live menu, reapplying the file whenever it changes on disk.
+#[derive(Debug)]
+pub struct MenuFile {
+	path: PathBuf,
+	modified: Option<SystemTime>,
+	definition: MenuDefinition
+}
+impl MenuFile {
+	pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+		let path = path.as_ref().to_path_buf();
+		let modified = modified_time(&path);
+		let definition = MenuDefinition::load(&path)?;
+		Ok(Self { path, modified, definition })
+	}
+	
+	pub fn definition(&self) -> &MenuDefinition {
+		&self.definition
+	}
+	
+	pub fn build(&self) -> Result<Menu, String> {
+		self.definition.spec.build()
+	}
+	
+	// `menu` must have been built from this file. Returns true if the file changed and the menu was updated.
+	// A file that fails to parse or a menu that doesn't match the loaded definition leaves both the menu and the definition untouched.
+	pub fn reload_if_changed(&mut self, menu: &mut MenuRef) -> Result<bool, String> {
+		let modified = modified_time(&self.path);
+		if modified == self.modified {
+			return Ok(false);
+		}
+		self.modified = modified;
+		
+		let definition = MenuDefinition::load(&self.path)?;
+		definition.spec.apply(menu, &self.definition.spec)?;
+		self.definition = definition;
+		Ok(true)
+	}
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+	std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+
+struct Parser {
+	commands: HashMap<String, u16>
+}
+impl Parser {
+	fn parse(mut self, source: &str) -> Result<MenuDefinition, String> {
+		// The root menu is the bottom of the stack, each open `submenu` pushes its text, line and entries.
+		let mut stack: Vec<(String, usize, Vec<MenuEntry>)> = vec![(String::new(), 0, Vec::new())];
+		
+		for (index, line) in source.lines().enumerate() {
+			let line_number = index + 1;
+			let tokens = tokenize(line).map_err(|err| format!("Error on line {line_number}: {err}"))?;
+			let Some((keyword, arguments)) = tokens.split_first() else { continue };
+			
+			let entries = &mut stack.last_mut().unwrap().2;
+			match (keyword.text(), arguments) {
+				("command", [name, Token::Word(equals), id]) if equals == "=" => {
+					let name = name.word().ok_or_else(|| format!("Error on line {line_number}: Command names can't be quoted."))?;
+					let id = parse_id(id).map_err(|err| format!("Error on line {line_number}: {err}"))?;
+					if self.commands.insert(String::from(name), id).is_some() {
+						return Err(format!("Error on line {line_number}: Command \"{name}\" is already defined."));
+					}
+				}
+				("command", _) => return Err(format!("Error on line {line_number}: Expected `command <name> = <id>`.")),
+				("item", [id, text, flags @ ..]) => {
+					let item = self.item(id, text, flags, false).map_err(|err| format!("Error on line {line_number}: {err}"))?;
+					entries.push(MenuEntry::Item(item.0));
+				}
+				("radio", [id, text, flags @ ..]) => {
+					let (item, selected) = self.item(id, text, flags, true).map_err(|err| format!("Error on line {line_number}: {err}"))?;
+					let item_id = item.id;
+					match entries.last_mut() {
+						Some(MenuEntry::RadioGroup { items, selected: group_selected }) => {
+							if selected {
+								if group_selected.is_some() {
+									return Err(format!("Error on line {line_number}: Radio group already has a selected item."));
+								}
+								*group_selected = Some(item_id);
+							}
+							items.push(item);
+						}
+						_ => entries.push(MenuEntry::RadioGroup { items: vec![item], selected: selected.then_some(item_id) })
+					}
+				}
+				("item" | "radio", _) => return Err(format!("Error on line {line_number}: Expected `{} <command> \"<text>\"`.", keyword.text())),
+				("separator", []) => entries.push(MenuEntry::Separator),
+				("submenu", [Token::Quoted(text)]) => stack.push((text.clone(), line_number, Vec::new())),
+				("submenu", _) => return Err(format!("Error on line {line_number}: Expected `submenu \"<text>\"`.")),
+				("end", []) => {
+					if stack.len() == 1 {
+						return Err(format!("Error on line {line_number}: `end` without a matching `submenu`."));
+					}
+					let (text, _, entries) = stack.pop().unwrap();
+					stack.last_mut().unwrap().2.push(MenuEntry::Submenu { text, enabled: true, spec: MenuSpec { entries } });
+				}
+				_ => return Err(format!("Error on line {line_number}: Unexpected `{}`.", keyword.text()))
+			}
+		}
+		
+		if stack.len() > 1 {
+			let (text, line_number, _) = stack.pop().unwrap();
+			return Err(format!("Error on line {line_number}: Submenu \"{text}\" is missing its `end`."));
+		}
+		
+		Ok(MenuDefinition {
+			spec: MenuSpec { entries: stack.pop().unwrap().2 },
+			commands: self.commands
+		})
+	}
+	
+	fn item(&self, id: &Token, text: &Token, flags: &[Token], radio: bool) -> Result<(MenuItemSpec, bool), String> {
+		let id = match id {
+			Token::Word(word) if word.starts_with(|c: char| c.is_ascii_digit()) => parse_id(id)?,
+			Token::Word(word) => *self.commands.get(word).ok_or_else(|| format!("Unknown command \"{word}\"."))?,
+			Token::Quoted(_) => return Err(String::from("Expected a command name or ID before the item text."))
+		};
+		let Token::Quoted(text) = text else {
+			return Err(String::from("Item text must be quoted."));
+		};
+		
+		let mut item = MenuItemSpec::new(id, text);
+		let mut selected = false;
+		let mut flags = flags.iter();
+		while let Some(flag) = flags.next() {
+			match flag.word() {
+				Some("shortcut") => match flags.next() {
+					Some(Token::Quoted(shortcut)) => item.shortcut = Some(shortcut.clone()),
+					_ => return Err(String::from("Expected quoted text after `shortcut`."))
+				}
+				Some("disabled") => item.enabled = false,
+				Some("checkable") if !radio => item.checkable = true,
+				Some("checked") if !radio => item = item.checkable(true),
+				Some("selected") if radio => selected = true,
+				_ => return Err(format!("Unknown item flag `{}`.", flag.text()))
+			}
+		}
+		Ok((item, selected))
+	}
+}
+
+fn parse_id(token: &Token) -> Result<u16, String> {
+	let id = token.word().and_then(|word| word.parse::<u16>().ok()).ok_or_else(|| format!("\"{}\" is not a valid ID.", token.text()))?;
+	internalize_id(id)?;
+	Ok(id)
+}
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+	Word(String),
+	Quoted(String)
+}
+impl Token {
+	fn word(&self) -> Option<&str> {
+		match self {
+			Token::Word(word) => Some(word),
+			Token::Quoted(_) => None
+		}
+	}
+	
+	fn text(&self) -> &str {
+		match self {
+			Token::Word(text) | Token::Quoted(text) => text
+		}
+	}
+}
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+	let mut tokens = Vec::new();
+	let mut chars = line.chars().peekable();
+	
+	while let Some(&c) = chars.peek() {
+		if c == '#' {
+			break;
+		} else if c.is_whitespace() {
+			chars.next();
+		} else if c == '=' {
+			chars.next();
+			tokens.push(Token::Word(String::from("=")));
+		} else if c == '"' {
+			chars.next();
+			let mut text = String::new();
+			loop {
+				match chars.next() {
+					Some('"') => break,
+					Some('\\') => match chars.next() {
+						Some('n') => text.push('\n'),
+						Some('t') => text.push('\t'),
+						Some(escaped) => text.push(escaped),
+						None => return Err(String::from("Unterminated string."))
+					}
+					Some(c) => text.push(c),
+					None => return Err(String::from("Unterminated string."))
+				}
+			}
+			tokens.push(Token::Quoted(text));
+		} else {
+			let mut word = String::new();
+			while let Some(&c) = chars.peek() {
+				if c.is_whitespace() || c == '"' || c == '=' || c == '#' {
+					break;
+				}
+				word.push(c);
+				chars.next();
+			}
+			tokens.push(Token::Word(word));
+		}
+	}
+	Ok(tokens)
+}
@@ -0,0 +1,325 @@
+use std::{cell::RefCell, os::raw::c_void};
+use windows::Win32::{Foundation::{LPARAM, TRUE}, UI::{WindowsAndMessaging::{HMENU, MENUITEMINFOW, GetMenuItemInfoW, SetMenuItemInfoW, GetMenuItemCount, GetSubMenu, DestroyMenu, DeleteMenu, RemoveMenu, GetMenuItemID, MF_BYPOSITION, MIIM_BITMAP, MIIM_CHECKMARKS, MIIM_DATA, MIIM_FTYPE, MFT_OWNERDRAW}, Controls::{MEASUREITEMSTRUCT, DRAWITEMSTRUCT, ODT_MENU, ODS_SELECTED, ODS_CHECKED, ODS_DISABLED, ODS_GRAYED}}, Graphics::Gdi::{BITMAPINFO, BITMAPINFOHEADER, BI_RGB, CreateDIBSection, DIB_RGB_COLORS, DeleteObject, HBITMAP, CreateCompatibleDC, SelectObject, BitBlt, SRCCOPY, DeleteDC}};
+
+use crate::{MenuRef, Rect, internalize_id, find_item};
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub struct MenuItemDrawState {
+	pub selected: bool,
+	pub checked: bool,
+	pub disabled: bool
+}
+
+type MenuItemPainter = Box<dyn FnMut(&mut [u8], &Rect, MenuItemDrawState)>;
+
+struct OwnerDraw {
+	key: usize,
+	width: u32,
+	height: u32,
+	// Taken out while it runs, so the painter itself can use menu functions.
+	painter: Option<MenuItemPainter>
+}
+
+// Bitmaps and painters attached to one menu item. Menus don't free these themselves, so they are released when the item's menu is destroyed.
+struct ItemResources {
+	hmenu: HMENU,
+	wid: u32,
+	item_bitmap: Option<HBITMAP>,
+	check_bitmaps: Option<(HBITMAP, HBITMAP)>,
+	owner_draw: Option<OwnerDraw>
+}
+impl ItemResources {
+	fn release(self) {
+		for bitmap in self.item_bitmap.into_iter().chain(self.check_bitmaps.into_iter().flat_map(|(checked, unchecked)| [checked, unchecked])) {
+			unsafe { DeleteObject(bitmap) };
+		}
+	}
+}
+
+thread_local! {
+	static ITEM_RESOURCES: RefCell<Vec<ItemResources>> = const { RefCell::new(Vec::new()) };
+	static NEXT_OWNER_DRAW_KEY: RefCell<usize> = const { RefCell::new(1) };
+}
+
+fn with_item_resources<T>(hmenu: HMENU, wid: u32, f: impl FnOnce(&mut ItemResources) -> T) -> T {
+	ITEM_RESOURCES.with_borrow_mut(|resources| {
+		let index = match resources.iter().position(|item| item.hmenu == hmenu && item.wid == wid) {
+			Some(index) => index,
+			None => {
+				resources.push(ItemResources { hmenu, wid, item_bitmap: None, check_bitmaps: None, owner_draw: None });
+				resources.len() - 1
+			}
+		};
+		f(&mut resources[index])
+	})
+}
+
+pub(crate) fn release_item_resources(hmenu: HMENU, wid: u32) {
+	let released: Vec<ItemResources> = ITEM_RESOURCES.with_borrow_mut(|resources| {
+		let (released, kept) = std::mem::take(resources).into_iter().partition(|item| item.hmenu == hmenu && item.wid == wid);
+		*resources = kept;
+		released
+	});
+	released.into_iter().for_each(ItemResources::release);
+}
+
+#[cfg(test)]
+pub(crate) fn has_item_resources(hmenu: HMENU) -> bool {
+	ITEM_RESOURCES.with_borrow(|resources| resources.iter().any(|item| item.hmenu == hmenu))
+}
+
+// Deletes the item at the position along with its submenu, and releases the resources of everything deleted.
+pub(crate) fn delete_item(hmenu: HMENU, position: u32) -> windows::core::Result<()> {
+	let submenu = unsafe { GetSubMenu(hmenu, position as i32) };
+	if submenu.is_invalid() {
+		let wid = unsafe { GetMenuItemID(hmenu, position as i32) };
+		unsafe { DeleteMenu(hmenu, position, MF_BYPOSITION) }?;
+		release_item_resources(hmenu, wid);
+	} else {
+		// Detached rather than deleted, so `destroy_menu` can find the submenu's items.
+		unsafe { RemoveMenu(hmenu, position, MF_BYPOSITION) }?;
+		destroy_menu(submenu);
+	}
+	Ok(())
+}
+
+// Replacing an item with a string turns off owner drawing, so its painter is dropped. Its bitmaps stay with it under the new ID.
+pub(crate) fn item_replaced(hmenu: HMENU, wid: u32, new_wid: u32) {
+	let owner_draw = ITEM_RESOURCES.with_borrow_mut(|resources| resources.iter_mut()
+		.find(|item| item.hmenu == hmenu && item.wid == wid)
+		.and_then(|item| {
+			item.wid = new_wid;
+			item.owner_draw.take()
+		})
+	);
+	drop(owner_draw);
+}
+
+// Destroys a menu with its submenus and releases the resources of all their items.
+pub(crate) fn destroy_menu(hmenu: HMENU) {
+	let mut menus = vec![hmenu];
+	let mut index = 0;
+	while index < menus.len() {
+		for position in 0..unsafe { GetMenuItemCount(menus[index]) }.max(0) {
+			let submenu = unsafe { GetSubMenu(menus[index], position) };
+			if !submenu.is_invalid() {
+				menus.push(submenu);
+			}
+		}
+		index += 1;
+	}
+	
+	unsafe { DestroyMenu(hmenu) }.unwrap_or(());
+	
+	let released: Vec<ItemResources> = ITEM_RESOURCES.with_borrow_mut(|resources| {
+		let (released, kept) = std::mem::take(resources).into_iter().partition(|item| menus.contains(&item.hmenu));
+		*resources = kept;
+		released
+	});
+	released.into_iter().for_each(ItemResources::release);
+}
+
+
+// Creates a top-down 32 bit bitmap and returns it with its pixels, which use the same BGRA layout as the window's pixel buffer.
+fn create_bitmap(width: u32, height: u32) -> windows::core::Result<(HBITMAP, *mut u8)> {
+	let bmi = BITMAPINFO {
+		bmiHeader: BITMAPINFOHEADER {
+			biSize: std::mem::size_of::<BITMAPINFOHEADER>() as u32,
+			biWidth: width as i32,
+			biHeight: -(height as i32),
+			biPlanes: 1,
+			biBitCount: 32,
+			biCompression: BI_RGB.0,
+			..Default::default()
+		},
+		..Default::default()
+	};
+	let mut pixel_data_pointer: *mut c_void = std::ptr::null_mut();
+	let bitmap = unsafe { CreateDIBSection(None, &bmi, DIB_RGB_COLORS, &mut pixel_data_pointer, None, 0) }?;
+	Ok((bitmap, pixel_data_pointer as *mut u8))
+}
+
+// Menus draw 32 bit item bitmaps with premultiplied alpha.
+fn create_rgba_bitmap(width: u32, height: u32, rgba: &[u8]) -> Result<HBITMAP, String> {
+	if rgba.len() != (width * height * 4) as usize {
+		return Err(format!("Error creating menu bitmap: expected {} bytes for {width}x{height} pixels, got {}.", width * height * 4, rgba.len()));
+	}
+	let (bitmap, pixels) = create_bitmap(width, height).map_err(|err| format!("Error creating menu bitmap: {err}"))?;
+	if !pixels.is_null() {
+		let pixels = unsafe { std::slice::from_raw_parts_mut(pixels, rgba.len()) };
+		for (pixel, source) in pixels.chunks_exact_mut(4).zip(rgba.chunks_exact(4)) {
+			let alpha = source[3] as u32;
+			pixel[0] = (source[2] as u32 * alpha / 255) as u8;
+			pixel[1] = (source[1] as u32 * alpha / 255) as u8;
+			pixel[2] = (source[0] as u32 * alpha / 255) as u8;
+			pixel[3] = source[3];
+		}
+	}
+	Ok(bitmap)
+}
+
+
+impl MenuRef<'_> {
+	pub fn set_item_bitmap(&self, id: u16, width: u32, height: u32, rgba: &[u8]) -> Result<(), String> {
+		let (hmenu, position) = find_item(self.hmenu, internalize_id(id)?).ok_or_else(|| format!("Error setting menu item bitmap: {id} is not in the menu."))?;
+		let bitmap = create_rgba_bitmap(width, height, rgba)?;
+		
+		let mii = MENUITEMINFOW {
+			cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+			fMask: MIIM_BITMAP,
+			hbmpItem: bitmap,
+			..Default::default()
+		};
+		if let Err(err) = unsafe { SetMenuItemInfoW(hmenu, position, TRUE, &mii) } {
+			unsafe { DeleteObject(bitmap) };
+			return Err(format!("Error setting menu item bitmap: {err}"));
+		}
+		if let Some(old_bitmap) = with_item_resources(hmenu, internalize_id(id)?, |item| item.item_bitmap.replace(bitmap)) {
+			unsafe { DeleteObject(old_bitmap) };
+		}
+		Ok(())
+	}
+	
+	pub fn set_item_check_bitmaps(&self, id: u16, width: u32, height: u32, checked_rgba: &[u8], unchecked_rgba: &[u8]) -> Result<(), String> {
+		let (hmenu, position) = find_item(self.hmenu, internalize_id(id)?).ok_or_else(|| format!("Error setting menu check bitmaps: {id} is not in the menu."))?;
+		let checked = create_rgba_bitmap(width, height, checked_rgba)?;
+		let unchecked = match create_rgba_bitmap(width, height, unchecked_rgba) {
+			Ok(unchecked) => unchecked,
+			Err(err) => {
+				unsafe { DeleteObject(checked) };
+				return Err(err);
+			}
+		};
+		
+		let mii = MENUITEMINFOW {
+			cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+			fMask: MIIM_CHECKMARKS,
+			hbmpChecked: checked,
+			hbmpUnchecked: unchecked,
+			..Default::default()
+		};
+		if let Err(err) = unsafe { SetMenuItemInfoW(hmenu, position, TRUE, &mii) } {
+			unsafe {
+				DeleteObject(checked);
+				DeleteObject(unchecked);
+			}
+			return Err(format!("Error setting menu check bitmaps: {err}"));
+		}
+		if let Some((old_checked, old_unchecked)) = with_item_resources(hmenu, internalize_id(id)?, |item| item.check_bitmaps.replace((checked, unchecked))) {
+			unsafe {
+				DeleteObject(old_checked);
+				DeleteObject(old_unchecked);
+			}
+		}
+		Ok(())
+	}
+	
+	// The painter draws the item into a `width` x `height` BGRA buffer that starts out with the menu's own background.
+	pub fn set_item_owner_draw(&self, id: u16, width: u32, height: u32, painter: impl FnMut(&mut [u8], &Rect, MenuItemDrawState) + 'static) -> Result<(), String> {
+		let wid = internalize_id(id)?;
+		let (hmenu, position) = find_item(self.hmenu, wid).ok_or_else(|| format!("Error setting owner drawn menu item: {id} is not in the menu."))?;
+		
+		let mut mii = MENUITEMINFOW {
+			cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+			fMask: MIIM_FTYPE,
+			..Default::default()
+		};
+		unsafe { GetMenuItemInfoW(hmenu, position, TRUE, &mut mii) }.map_err(|err| format!("Error setting owner drawn menu item: {err}"))?;
+		
+		let key = NEXT_OWNER_DRAW_KEY.with_borrow_mut(|next| {
+			*next += 1;
+			*next - 1
+		});
+		mii.fMask = MIIM_FTYPE | MIIM_DATA;
+		mii.fType |= MFT_OWNERDRAW;
+		mii.dwItemData = key;
+		unsafe { SetMenuItemInfoW(hmenu, position, TRUE, &mii) }.map_err(|err| format!("Error setting owner drawn menu item: {err}"))?;
+		
+		with_item_resources(hmenu, wid, |item| item.owner_draw = Some(OwnerDraw { key, width, height, painter: Some(Box::new(painter)) }));
+		Ok(())
+	}
+}
+
+
+// Answers WM_MEASUREITEM for owner drawn menu items, returns false if the item isn't one of ours.
+pub(crate) fn measure_item(lparam: LPARAM) -> bool {
+	let mis = unsafe { &mut *(lparam.0 as *mut MEASUREITEMSTRUCT) };
+	if mis.CtlType != ODT_MENU {
+		return false;
+	}
+	let size = ITEM_RESOURCES.with_borrow(|resources| resources.iter()
+		.filter_map(|item| item.owner_draw.as_ref())
+		.find(|owner_draw| owner_draw.key == mis.itemData)
+		.map(|owner_draw| (owner_draw.width, owner_draw.height))
+	);
+	match size {
+		Some((width, height)) => {
+			mis.itemWidth = width;
+			mis.itemHeight = height;
+			true
+		}
+		None => false
+	}
+}
+
+// Answers WM_DRAWITEM for owner drawn menu items, returns false if the item isn't one of ours.
+pub(crate) fn draw_item(lparam: LPARAM) -> bool {
+	let dis = unsafe { &*(lparam.0 as *const DRAWITEMSTRUCT) };
+	if dis.CtlType != ODT_MENU {
+		return false;
+	}
+	let Some(mut painter) = take_painter(dis.itemData) else {
+		return false;
+	};
+	
+	let rect = Rect {
+		left: 0,
+		top: 0,
+		right: dis.rcItem.right - dis.rcItem.left,
+		bottom: dis.rcItem.bottom - dis.rcItem.top
+	};
+	let state = MenuItemDrawState {
+		selected: dis.itemState.0 & ODS_SELECTED.0 != 0,
+		checked: dis.itemState.0 & ODS_CHECKED.0 != 0,
+		disabled: dis.itemState.0 & (ODS_DISABLED.0 | ODS_GRAYED.0) != 0
+	};
+	
+	if rect.width() > 0 && rect.height() > 0 {
+		unsafe {
+			let memory_dc = CreateCompatibleDC(dis.hDC);
+			if let Ok((bitmap, pixels)) = create_bitmap(rect.width() as u32, rect.height() as u32) {
+				let previous = SelectObject(memory_dc, bitmap);
+				BitBlt(memory_dc, 0, 0, rect.width(), rect.height(), dis.hDC, dis.rcItem.left, dis.rcItem.top, SRCCOPY).unwrap_or(());
+				
+				if !pixels.is_null() {
+					painter(std::slice::from_raw_parts_mut(pixels, (rect.width() * rect.height() * 4) as usize), &rect, state);
+				}
+				
+				BitBlt(dis.hDC, dis.rcItem.left, dis.rcItem.top, rect.width(), rect.height(), memory_dc, 0, 0, SRCCOPY).unwrap_or(());
+				SelectObject(memory_dc, previous);
+				DeleteObject(bitmap);
+			}
+			DeleteDC(memory_dc);
+		}
+	}
+	
+	restore_painter(dis.itemData, painter);
+	true
+}
+
+fn take_painter(key: usize) -> Option<MenuItemPainter> {
+	ITEM_RESOURCES.with_borrow_mut(|resources| resources.iter_mut()
+		.filter_map(|item| item.owner_draw.as_mut())
+		.find(|owner_draw| owner_draw.key == key)
+		.and_then(|owner_draw| owner_draw.painter.take())
+	)
+}
+
+fn restore_painter(key: usize, painter: MenuItemPainter) {
+	ITEM_RESOURCES.with_borrow_mut(|resources| {
+		if let Some(owner_draw) = resources.iter_mut().filter_map(|item| item.owner_draw.as_mut()).find(|owner_draw| owner_draw.key == key) {
+			owner_draw.painter = Some(painter);
+		}
+	});
+}
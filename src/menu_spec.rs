@@ -0,0 +1,371 @@
+use windows::{core::PWSTR, Win32::{Foundation::TRUE, UI::WindowsAndMessaging::{HMENU, DrawMenuBar, MENUITEMINFOW, InsertMenuItemW, GetMenuItemInfoW, SetMenuItemInfoW, GetSubMenu, GetMenuItemCount, GetMenuItemID, MIIM_FTYPE, MIIM_STATE, MIIM_ID, MIIM_STRING, MIIM_SUBMENU, MFT_STRING, MFT_SEPARATOR, MFT_RADIOCHECK, MFS_CHECKED, MFS_DISABLED, MENU_ITEM_STATE}}};
+
+use crate::{Menu, MenuRef, internalize_id, menu_graphics};
+
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MenuItemSpec {
+	pub id: u16,
+	pub text: String,
+	pub shortcut: Option<String>,
+	pub checkable: bool,
+	pub checked: bool,
+	pub enabled: bool
+}
+impl MenuItemSpec {
+	pub fn new(id: u16, text: &str) -> Self {
+		Self {
+			id,
+			text: String::from(text),
+			shortcut: None,
+			checkable: false,
+			checked: false,
+			enabled: true
+		}
+	}
+	
+	pub fn shortcut(mut self, shortcut: &str) -> Self {
+		self.shortcut = Some(String::from(shortcut));
+		self
+	}
+	
+	pub fn checkable(mut self, checked: bool) -> Self {
+		self.checkable = true;
+		self.checked = checked;
+		self
+	}
+	
+	pub fn enabled(mut self, enabled: bool) -> Self {
+		self.enabled = enabled;
+		self
+	}
+	
+	fn label(&self) -> String {
+		match &self.shortcut {
+			Some(shortcut) => format!("{}\t{}", self.text, shortcut),
+			None => self.text.clone()
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MenuEntry {
+	Item(MenuItemSpec),
+	Separator,
+	Submenu { text: String, enabled: bool, spec: MenuSpec },
+	RadioGroup { items: Vec<MenuItemSpec>, selected: Option<u16> }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MenuSpec {
+	pub entries: Vec<MenuEntry>
+}
+impl MenuSpec {
+	pub fn new() -> Self {
+		Self::default()
+	}
+	
+	pub fn item(self, id: u16, text: &str) -> Self {
+		self.entry(MenuEntry::Item(MenuItemSpec::new(id, text)))
+	}
+	
+	pub fn item_spec(self, item: MenuItemSpec) -> Self {
+		self.entry(MenuEntry::Item(item))
+	}
+	
+	pub fn separator(self) -> Self {
+		self.entry(MenuEntry::Separator)
+	}
+	
+	pub fn submenu(self, text: &str, spec: MenuSpec) -> Self {
+		self.entry(MenuEntry::Submenu { text: String::from(text), enabled: true, spec })
+	}
+	
+	pub fn radio_group(self, items: Vec<MenuItemSpec>, selected: Option<u16>) -> Self {
+		self.entry(MenuEntry::RadioGroup { items, selected })
+	}
+	
+	pub fn entry(mut self, entry: MenuEntry) -> Self {
+		self.entries.push(entry);
+		self
+	}
+	
+	// Builds a menu bar, for `WindowHandle::set_menu`.
+	pub fn build(&self) -> Result<Menu, String> {
+		check_spec(self).map_err(|err| format!("Error building menu: {err}"))?;
+		self.build_into(Menu::new()?)
+	}
+	
+	// Builds a popup menu, for submenus and context menus.
+	pub fn build_popup(&self) -> Result<Menu, String> {
+		check_spec(self).map_err(|err| format!("Error building menu: {err}"))?;
+		self.build_into(Menu::new_popup()?)
+	}
+	
+	fn build_into(&self, menu: Menu) -> Result<Menu, String> {
+		for (position, entry) in self.flatten().iter().enumerate() {
+			insert_entry(menu.hmenu, position as u32, entry)?;
+		}
+		Ok(menu)
+	}
+	
+	/// Brings a live menu that was built from `previous` in line with this spec, touching only the entries and attributes that changed.
+	/// Entries can be removed, so the menu is borrowed mutably and nothing borrowed from it survives.
+	///
+	/// ```compile_fail,E0499
+	/// # use simple_windows::{Menu, MenuSpec};
+	/// fn apply_while_borrowed(menu: &mut Menu, new: &MenuSpec, old: &MenuSpec) {
+	///     let mut items = menu.edit();
+	///     let submenu = items.get_submenu(0).unwrap();
+	///     new.apply(&mut items, old).unwrap();
+	///     submenu.add_item(1, "Removed").unwrap();
+	/// }
+	/// ```
+	pub fn apply(&self, menu: &mut MenuRef, previous: &MenuSpec) -> Result<(), String> {
+		menu.check_exclusive().map_err(|err| format!("Error applying menu spec: {err}"))?;
+		// Everything that can be checked is checked before the first edit, so a failure leaves the menu as it was.
+		if !matches_menu(menu.hmenu, previous) {
+			return Err(String::from("Error applying menu spec: the menu does not match the previous spec."));
+		}
+		check_spec(self).map_err(|err| format!("Error applying menu spec: {err}"))?;
+		let edits = plan_edits(self, previous);
+		apply_edits(menu.hmenu, &edits, self, previous)?;
+		// A window's menu bar isn't redrawn by changes to its items.
+		if let (Some(hwnd), false) = (menu.window, edits.is_empty()) {
+			unsafe { DrawMenuBar(hwnd) }.map_err(|err| format!("Error drawing menu bar: {err}"))?;
+		}
+		Ok(())
+	}
+	
+	fn flatten(&self) -> Vec<FlatEntry<'_>> {
+		let mut flat = Vec::new();
+		for entry in &self.entries {
+			match entry {
+				MenuEntry::Item(item) => flat.push(FlatEntry::Item { item, radio: false, checked: item.checkable && item.checked }),
+				MenuEntry::Separator => flat.push(FlatEntry::Separator),
+				MenuEntry::Submenu { text, enabled, spec } => flat.push(FlatEntry::Submenu { text, enabled: *enabled, spec }),
+				MenuEntry::RadioGroup { items, selected } => {
+					for item in items {
+						flat.push(FlatEntry::Item { item, radio: true, checked: *selected == Some(item.id) });
+					}
+				}
+			}
+		}
+		flat
+	}
+}
+
+
+// Radio groups are expanded into their member items, so positions line up with the positions in the live menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlatEntry<'a> {
+	Item { item: &'a MenuItemSpec, radio: bool, checked: bool },
+	Separator,
+	Submenu { text: &'a str, enabled: bool, spec: &'a MenuSpec }
+}
+
+fn item_state(checked: bool, enabled: bool) -> MENU_ITEM_STATE {
+	let mut state = MENU_ITEM_STATE(0);
+	if checked { state |= MFS_CHECKED; }
+	if !enabled { state |= MFS_DISABLED; }
+	state
+}
+
+fn insert_entry(hmenu: HMENU, position: u32, entry: &FlatEntry) -> Result<(), String> {
+	let mut mii = MENUITEMINFOW {
+		cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+		..Default::default()
+	};
+	let mut label: Vec<u16>;
+	let mut submenu = None;
+	
+	match *entry {
+		FlatEntry::Item { item, radio, checked } => {
+			label = item.label().encode_utf16().chain(Some(0)).collect();
+			mii.fMask = MIIM_FTYPE | MIIM_STATE | MIIM_ID | MIIM_STRING;
+			mii.fType = if radio { MFT_STRING | MFT_RADIOCHECK } else { MFT_STRING };
+			mii.fState = item_state(checked, item.enabled);
+			mii.wID = internalize_id(item.id)?;
+			mii.dwTypeData = PWSTR(label.as_mut_ptr());
+		}
+		FlatEntry::Separator => {
+			mii.fMask = MIIM_FTYPE;
+			mii.fType = MFT_SEPARATOR;
+		}
+		FlatEntry::Submenu { text, enabled, spec } => {
+			let built = spec.build_into(Menu::new_popup()?)?;
+			label = text.encode_utf16().chain(Some(0)).collect();
+			mii.fMask = MIIM_FTYPE | MIIM_STATE | MIIM_STRING | MIIM_SUBMENU;
+			mii.fType = MFT_STRING;
+			mii.fState = item_state(false, enabled);
+			mii.hSubMenu = built.hmenu;
+			mii.dwTypeData = PWSTR(label.as_mut_ptr());
+			submenu = Some(built);
+		}
+	}
+	
+	unsafe { InsertMenuItemW(hmenu, position, TRUE, &mii) }.map_err(|err| format!("Error inserting menu item: {err}"))?;
+	if let Some(submenu) = submenu {
+		submenu.into_hmenu();
+	}
+	Ok(())
+}
+
+fn update_entry(hmenu: HMENU, position: u32, new: &FlatEntry, old: &FlatEntry) -> Result<(), String> {
+	let mut mii = MENUITEMINFOW {
+		cbSize: std::mem::size_of::<MENUITEMINFOW>() as u32,
+		fMask: MIIM_FTYPE | MIIM_STATE,
+		..Default::default()
+	};
+	unsafe { GetMenuItemInfoW(hmenu, position, TRUE, &mut mii) }.map_err(|err| format!("Error reading menu item: {err}"))?;
+	
+	let (new_label, new_checked, new_enabled, new_radio) = match *new {
+		FlatEntry::Item { item, radio, checked } => (item.label(), Some(checked), item.enabled, radio),
+		FlatEntry::Submenu { text, enabled, .. } => (String::from(text), None, enabled, false),
+		FlatEntry::Separator => return Ok(())
+	};
+	let (old_label, old_checked, old_enabled, old_radio) = match *old {
+		FlatEntry::Item { item, radio, checked } => (item.label(), Some(checked), item.enabled, radio),
+		FlatEntry::Submenu { text, enabled, .. } => (String::from(text), None, enabled, false),
+		FlatEntry::Separator => return Ok(())
+	};
+	
+	let mut label: Vec<u16> = new_label.encode_utf16().chain(Some(0)).collect();
+	if new_label != old_label {
+		mii.fMask |= MIIM_STRING;
+		mii.dwTypeData = PWSTR(label.as_mut_ptr());
+	}
+	// Only attributes that differ between the two specs are written, so state changed at runtime survives otherwise.
+	if new_checked != old_checked {
+		match new_checked {
+			Some(true) => mii.fState |= MFS_CHECKED,
+			_ => mii.fState &= !MFS_CHECKED
+		}
+	}
+	if new_enabled != old_enabled {
+		match new_enabled {
+			true => mii.fState &= !MFS_DISABLED,
+			false => mii.fState |= MFS_DISABLED
+		}
+	}
+	if new_radio != old_radio {
+		match new_radio {
+			true => mii.fType |= MFT_RADIOCHECK,
+			false => mii.fType &= !MFT_RADIOCHECK
+		}
+	}
+	
+	unsafe { SetMenuItemInfoW(hmenu, position, TRUE, &mii) }.map_err(|err| format!("Error updating menu item: {err}"))
+}
+
+// Compares the whole live tree with the spec, by the kind and ID of every entry.
+fn matches_menu(hmenu: HMENU, spec: &MenuSpec) -> bool {
+	let entries = spec.flatten();
+	if unsafe { GetMenuItemCount(hmenu) } != entries.len() as i32 {
+		return false;
+	}
+	entries.iter().enumerate().all(|(position, entry)| {
+		let submenu = unsafe { GetSubMenu(hmenu, position as i32) };
+		let id = unsafe { GetMenuItemID(hmenu, position as i32) };
+		match entry {
+			FlatEntry::Item { item, .. } => submenu.is_invalid() && internalize_id(item.id) == Ok(id),
+			// Separators are inserted without an ID.
+			FlatEntry::Separator => submenu.is_invalid() && id == 0,
+			FlatEntry::Submenu { spec, .. } => !submenu.is_invalid() && matches_menu(submenu, spec)
+		}
+	})
+}
+
+// Finds what would only fail part way through building or applying the spec.
+pub(crate) fn check_spec(spec: &MenuSpec) -> Result<(), String> {
+	let mut after_radio_group = false;
+	for entry in &spec.entries {
+		match entry {
+			MenuEntry::Item(item) => {
+				internalize_id(item.id)?;
+			}
+			MenuEntry::Separator => {}
+			MenuEntry::Submenu { spec, .. } => check_spec(spec)?,
+			MenuEntry::RadioGroup { items, .. } if items.is_empty() => continue,
+			// The live menu only tells groups apart by the items between them.
+			MenuEntry::RadioGroup { .. } if after_radio_group => return Err(String::from("Two radio groups follow each other, they need an item or separator between them.")),
+			MenuEntry::RadioGroup { items, .. } => {
+				for item in items {
+					internalize_id(item.id)?;
+				}
+			}
+		}
+		after_radio_group = matches!(entry, MenuEntry::RadioGroup { .. });
+	}
+	Ok(())
+}
+
+fn same_kind(new: &FlatEntry, old: &FlatEntry) -> bool {
+	match (new, old) {
+		(FlatEntry::Item { item: new_item, .. }, FlatEntry::Item { item: old_item, .. }) => new_item.id == old_item.id,
+		(FlatEntry::Separator, FlatEntry::Separator) => true,
+		(FlatEntry::Submenu { .. }, FlatEntry::Submenu { .. }) => true,
+		_ => false
+	}
+}
+
+
+// One step of bringing a live menu in line with a spec. Positions are the ones in the live menu at the time the step runs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum MenuEdit {
+	// Changes the label or state of the item in place.
+	Update(u32),
+	Replace(u32),
+	Insert(u32),
+	Remove(u32),
+	// Edits to the submenu at the position.
+	Submenu(u32, Vec<MenuEdit>)
+}
+
+// Matches entries by position, an entry that changed kind or ID is replaced and the rest are updated where they differ.
+pub(crate) fn plan_edits(new: &MenuSpec, old: &MenuSpec) -> Vec<MenuEdit> {
+	let (new, old) = (new.flatten(), old.flatten());
+	let mut edits = Vec::new();
+	for (position, new_entry) in new.iter().enumerate() {
+		let position = position as u32;
+		match (new_entry, old.get(position as usize)) {
+			(_, Some(old_entry)) if new_entry == old_entry => {}
+			(FlatEntry::Submenu { text, enabled, spec }, Some(FlatEntry::Submenu { text: old_text, enabled: old_enabled, spec: old_spec })) => {
+				if (text, enabled) != (old_text, old_enabled) {
+					edits.push(MenuEdit::Update(position));
+				}
+				let submenu_edits = plan_edits(spec, old_spec);
+				if !submenu_edits.is_empty() {
+					edits.push(MenuEdit::Submenu(position, submenu_edits));
+				}
+			}
+			(_, Some(old_entry)) if same_kind(new_entry, old_entry) => edits.push(MenuEdit::Update(position)),
+			(_, Some(_)) => edits.push(MenuEdit::Replace(position)),
+			(_, None) => edits.push(MenuEdit::Insert(position))
+		}
+	}
+	// From the end, so the positions of the remaining items don't shift.
+	edits.extend((new.len()..old.len()).rev().map(|position| MenuEdit::Remove(position as u32)));
+	edits
+}
+
+fn apply_edits(hmenu: HMENU, edits: &[MenuEdit], new: &MenuSpec, old: &MenuSpec) -> Result<(), String> {
+	let (new, old) = (new.flatten(), old.flatten());
+	for edit in edits {
+		match edit {
+			MenuEdit::Update(position) => update_entry(hmenu, *position, &new[*position as usize], &old[*position as usize])?,
+			MenuEdit::Replace(position) => {
+				menu_graphics::delete_item(hmenu, *position).map_err(|err| format!("Error removing menu item: {err}"))?;
+				insert_entry(hmenu, *position, &new[*position as usize])?;
+			}
+			MenuEdit::Insert(position) => insert_entry(hmenu, *position, &new[*position as usize])?,
+			MenuEdit::Remove(position) => menu_graphics::delete_item(hmenu, *position).map_err(|err| format!("Error removing menu item: {err}"))?,
+			MenuEdit::Submenu(position, edits) => {
+				if let (FlatEntry::Submenu { spec: new_spec, .. }, FlatEntry::Submenu { spec: old_spec, .. }) = (&new[*position as usize], &old[*position as usize]) {
+					apply_edits(unsafe { GetSubMenu(hmenu, *position as i32) }, edits, new_spec, old_spec)?;
+				}
+			}
+		}
+	}
+	Ok(())
+}
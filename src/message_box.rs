@@ -0,0 +1,78 @@
+use windows::{core::{Error, HSTRING}, Win32::{Foundation::{WPARAM, LPARAM}, UI::WindowsAndMessaging::{self, MessageBoxW, PostMessageW, MESSAGEBOX_STYLE, MESSAGEBOX_RESULT, MB_OK, MB_OKCANCEL, MB_YESNO, MB_YESNOCANCEL, MB_RETRYCANCEL, MB_ICONINFORMATION, MB_ICONWARNING, MB_ICONERROR, MB_ICONQUESTION, IDOK, IDCANCEL, IDYES, IDNO, IDRETRY}}};
+
+use crate::WindowHandle;
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum MessageBoxButtons {
+	#[default]
+	Ok,
+	OkCancel,
+	YesNo,
+	YesNoCancel,
+	RetryCancel
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum MessageBoxIcon {
+	#[default]
+	None,
+	Information,
+	Warning,
+	Error,
+	Question
+}
+
+// The button that closed the message box. Closing it with Escape or the title bar counts as Cancel, or as Ok if there is no Cancel button.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MessageBoxButton {
+	Ok,
+	Cancel,
+	Yes,
+	No,
+	Retry
+}
+
+impl WindowHandle {
+	// Blocks until a button is pressed, the window can't be used meanwhile but keeps painting and running timers.
+	pub fn message_box(&self, title: &str, text: &str, buttons: MessageBoxButtons, icon: MessageBoxIcon) -> Result<MessageBoxButton, String> {
+		let result = unsafe { MessageBoxW(self.hwnd, &HSTRING::from(text), &HSTRING::from(title), message_box_style(buttons, icon)) };
+		pressed_button(result).ok_or_else(|| format!("Error showing message box: {}", Error::from_win32()))
+	}
+	
+	// Asks the window to close the same way its close button does, so `on_close_requested` can still keep it open.
+	pub fn close(&self) {
+		let _ = unsafe { PostMessageW(self.hwnd, WindowsAndMessaging::WM_CLOSE, WPARAM(0), LPARAM(0)) };
+	}
+}
+
+
+pub(crate) fn message_box_style(buttons: MessageBoxButtons, icon: MessageBoxIcon) -> MESSAGEBOX_STYLE {
+	let buttons_style = match buttons {
+		MessageBoxButtons::Ok => MB_OK,
+		MessageBoxButtons::OkCancel => MB_OKCANCEL,
+		MessageBoxButtons::YesNo => MB_YESNO,
+		MessageBoxButtons::YesNoCancel => MB_YESNOCANCEL,
+		MessageBoxButtons::RetryCancel => MB_RETRYCANCEL
+	};
+	let icon_style = match icon {
+		MessageBoxIcon::None => MESSAGEBOX_STYLE(0),
+		MessageBoxIcon::Information => MB_ICONINFORMATION,
+		MessageBoxIcon::Warning => MB_ICONWARNING,
+		MessageBoxIcon::Error => MB_ICONERROR,
+		MessageBoxIcon::Question => MB_ICONQUESTION
+	};
+	buttons_style | icon_style
+}
+
+// None means MessageBoxW failed.
+pub(crate) fn pressed_button(result: MESSAGEBOX_RESULT) -> Option<MessageBoxButton> {
+	match result {
+		IDOK => Some(MessageBoxButton::Ok),
+		IDCANCEL => Some(MessageBoxButton::Cancel),
+		IDYES => Some(MessageBoxButton::Yes),
+		IDNO => Some(MessageBoxButton::No),
+		IDRETRY => Some(MessageBoxButton::Retry),
+		_ => None
+	}
+}
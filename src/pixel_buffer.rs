@@ -0,0 +1,143 @@
+use std::{cell::RefCell, ops::{Deref, DerefMut}};
+use windows::Win32::{Foundation::HWND, Graphics::Gdi::HBITMAP};
+
+use crate::{WindowHandle, Rect, dib_section::DibSection};
+
+
+// What happens to the pixels when the window is resized and the buffer reallocated.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum ResizeContents {
+	// The new buffer starts out black, `on_resize` repaints it.
+	#[default]
+	Discard,
+	KeepTopLeft,
+	KeepCentered
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub(crate) struct BufferSettings {
+	pub(crate) resize_contents: ResizeContents,
+	pub(crate) back_buffer: bool
+}
+
+thread_local! {
+	static SETTINGS: RefCell<Vec<(HWND, BufferSettings)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn update_settings(hwnd: HWND, update: impl FnOnce(&mut BufferSettings)) {
+	SETTINGS.with_borrow_mut(|settings| {
+		let index = match settings.iter().position(|(settings_hwnd, _)| *settings_hwnd == hwnd) {
+			Some(index) => index,
+			None => {
+				settings.push((hwnd, BufferSettings::default()));
+				settings.len() - 1
+			}
+		};
+		update(&mut settings[index].1);
+	});
+}
+
+pub(crate) fn settings(hwnd: HWND) -> BufferSettings {
+	SETTINGS.with_borrow(|settings| settings.iter().find(|(settings_hwnd, _)| *settings_hwnd == hwnd).map(|(_, settings)| *settings).unwrap_or_default())
+}
+
+pub(crate) fn forget_settings(hwnd: HWND) {
+	SETTINGS.with_borrow_mut(|settings| settings.retain(|(settings_hwnd, _)| *settings_hwnd != hwnd));
+}
+
+impl WindowHandle {
+	pub fn set_resize_contents(&self, resize_contents: ResizeContents) {
+		update_settings(self.hwnd, |settings| settings.resize_contents = resize_contents);
+	}
+	
+	// With a back buffer the callbacks draw into separate memory, which is copied to the window's bitmap when `on_paint` returns.
+	// The bitmap then only ever holds complete frames, whatever other callbacks draw in between. Takes effect from the next paint or resize.
+	pub fn set_back_buffer(&self, enabled: bool) {
+		update_settings(self.hwnd, |settings| settings.back_buffer = enabled);
+	}
+	
+	pub fn has_back_buffer(&self) -> bool {
+		settings(self.hwnd).back_buffer
+	}
+}
+
+
+// The buffer the callbacks draw into. The front is the bitmap's memory, shown in the window, and the back buffer is separate memory of the same size.
+#[derive(Default)]
+pub(crate) struct PixelBuffer {
+	front: DibSection,
+	back: Option<Box<[u8]>>
+}
+impl Deref for PixelBuffer {
+	type Target = [u8];
+	
+	fn deref(&self) -> &[u8] {
+		self.back.as_deref().unwrap_or(self.front.pixels())
+	}
+}
+impl DerefMut for PixelBuffer {
+	fn deref_mut(&mut self) -> &mut [u8] {
+		match &mut self.back {
+			Some(back) => back,
+			None => self.front.pixels_mut()
+		}
+	}
+}
+impl PixelBuffer {
+	// Switching either way keeps what was drawn so far.
+	pub(crate) fn set_back_buffered(&mut self, enabled: bool) {
+		match (enabled, self.back.take()) {
+			(true, None) => self.back = Some(self.front.pixels().to_vec().into_boxed_slice()),
+			(false, Some(back)) => self.front.pixels_mut().copy_from_slice(&back),
+			(_, back) => self.back = back
+		}
+	}
+	
+	// Shows the back buffer's frame.
+	pub(crate) fn present(&mut self) {
+		if let Some(back) = &self.back {
+			self.front.pixels_mut().copy_from_slice(back);
+		}
+	}
+	
+	pub(crate) fn bitmap(&self) -> Option<HBITMAP> {
+		self.front.bitmap()
+	}
+	
+	// Takes on a newly allocated bitmap for the front, the old one is deleted.
+	pub(crate) fn replace_front(&mut self, mut front: DibSection, old_rect: &Rect, new_rect: &Rect, resize_contents: ResizeContents) {
+		copy_contents(self.front.pixels(), old_rect, front.pixels_mut(), new_rect, resize_contents);
+		if let Some(back) = &mut self.back {
+			let mut new_back = vec![0; front.pixels().len()].into_boxed_slice();
+			copy_contents(back, old_rect, &mut new_back, new_rect, resize_contents);
+			*back = new_back;
+		}
+		self.front = front;
+	}
+}
+
+// Copies the overlap of two buffers of different sizes, aligned by their top left corners or their centers.
+pub(crate) fn copy_contents(old: &[u8], old_rect: &Rect, new: &mut [u8], new_rect: &Rect, resize_contents: ResizeContents) {
+	let (old_width, old_height) = (old_rect.width().max(0) as usize, old_rect.height().max(0) as usize);
+	let (new_width, new_height) = (new_rect.width().max(0) as usize, new_rect.height().max(0) as usize);
+	if old.len() < 4 * old_width * old_height || new.len() < 4 * new_width * new_height {
+		return;
+	}
+	// Where the old top left corner ends up in the new buffer, negative when it's cut off.
+	let (offset_x, offset_y) = match resize_contents {
+		ResizeContents::Discard => return,
+		ResizeContents::KeepTopLeft => (0, 0),
+		ResizeContents::KeepCentered => ((new_width as i64 - old_width as i64) / 2, (new_height as i64 - old_height as i64) / 2)
+	};
+	let columns = (offset_x + old_width as i64).min(new_width as i64) - offset_x.max(0);
+	if columns <= 0 {
+		return;
+	}
+	let (old_x, new_x) = ((-offset_x).max(0) as usize, offset_x.max(0) as usize);
+	for new_y in offset_y.max(0)..(offset_y + old_height as i64).min(new_height as i64) {
+		let old_y = (new_y - offset_y) as usize;
+		let source = 4 * (old_y * old_width + old_x);
+		let destination = 4 * (new_y as usize * new_width + new_x);
+		new[destination..destination + 4 * columns as usize].copy_from_slice(&old[source..source + 4 * columns as usize]);
+	}
+}
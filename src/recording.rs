@@ -0,0 +1,224 @@
+use std::{cell::RefCell, fs::File, io::BufWriter, path::Path, sync::{Arc, atomic::{AtomicUsize, Ordering}, mpsc::{self, Receiver, Sender}}, thread::JoinHandle, time::{Duration, Instant}};
+use windows::Win32::{Foundation::{HWND, WPARAM, LPARAM}, UI::WindowsAndMessaging::{PostMessageW, WM_APP}};
+
+use crate::{WindowHandle, Rect, image::{Image, ImageError, GifEncoder, Y4mEncoder}};
+
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RecordingFormat {
+	Gif,
+	Y4m
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FrameTiming {
+	EveryPaint,
+	FixedRate(u32)
+}
+
+// Posted by a writer thread that failed, the LPARAM owns a boxed error message.
+pub(crate) const WM_RECORDING_ERROR: u32 = WM_APP + 1;
+
+// Frames copied but not yet written. When the writer falls behind further frames are skipped, so painting never waits on it.
+const MAX_QUEUED_FRAMES: usize = 4;
+
+// Y4M needs a constant frame rate even when frames come with every paint.
+const DEFAULT_FRAMES_PER_SECOND: u32 = 30;
+
+enum RecorderMessage {
+	Frame(Image, Instant),
+	Stop(Instant)
+}
+
+struct Recording {
+	hwnd: HWND,
+	sender: Sender<RecorderMessage>,
+	queued: Arc<AtomicUsize>,
+	interval: Duration,
+	last_frame: Option<Instant>
+}
+
+thread_local! {
+	static RECORDINGS: RefCell<Vec<Recording>> = const { RefCell::new(Vec::new()) };
+	// Writer threads, including ones still finishing a stopped recording. They are joined when their window closes.
+	static WRITERS: RefCell<Vec<(HWND, JoinHandle<()>)>> = const { RefCell::new(Vec::new()) };
+}
+
+impl WindowHandle {
+	// Frames are taken from the pixel buffer after `on_paint`, encoding and writing happens on a separate thread.
+	pub fn start_recording(&self, path: impl AsRef<Path>, format: RecordingFormat, timing: FrameTiming) -> Result<(), String> {
+		if self.is_recording() {
+			return Err(String::from("Error starting recording: Window is already being recorded."));
+		}
+		let (interval, frames_per_second) = match timing {
+			FrameTiming::EveryPaint => (Duration::ZERO, DEFAULT_FRAMES_PER_SECOND),
+			FrameTiming::FixedRate(0) => return Err(String::from("Error starting recording: Frame rate can't be 0.")),
+			FrameTiming::FixedRate(frames_per_second) => (Duration::from_secs(1) / frames_per_second, frames_per_second)
+		};
+		let path = path.as_ref().to_path_buf();
+		let file = File::create(&path).map_err(|err| format!("Error starting recording: {}: {err}", path.display()))?;
+		
+		let (sender, receiver) = mpsc::channel();
+		let queued = Arc::new(AtomicUsize::new(0));
+		let writer_queued = queued.clone();
+		let hwnd = self.hwnd;
+		let writer = std::thread::Builder::new().name(String::from("recording")).spawn(move || {
+			if let Err(err) = write_recording(file, format, frames_per_second, receiver, writer_queued) {
+				let message = Box::into_raw(Box::new(format!("Error recording to {}: {err}", path.display())));
+				if unsafe { PostMessageW(hwnd, WM_RECORDING_ERROR, WPARAM(0), LPARAM(message as isize)) }.is_err() {
+					drop(unsafe { Box::from_raw(message) });
+				}
+			}
+		}).map_err(|err| format!("Error starting recording: {err}"))?;
+		
+		WRITERS.with_borrow_mut(|writers| writers.push((self.hwnd, writer)));
+		RECORDINGS.with_borrow_mut(|recordings| recordings.push(Recording { hwnd: self.hwnd, sender, queued, interval, last_frame: None }));
+		Ok(())
+	}
+	
+	// Returns right away, the writer thread finishes the file in the background.
+	pub fn stop_recording(&self) {
+		let stopped = RECORDINGS.with_borrow_mut(|recordings| {
+			let index = recordings.iter().position(|recording| recording.hwnd == self.hwnd)?;
+			Some(recordings.remove(index))
+		});
+		if let Some(recording) = stopped {
+			let _ = recording.sender.send(RecorderMessage::Stop(Instant::now()));
+		}
+	}
+	
+	pub fn is_recording(&self) -> bool {
+		RECORDINGS.with_borrow(|recordings| recordings.iter().any(|recording| recording.hwnd == self.hwnd))
+	}
+}
+
+pub(crate) fn capture_frame(hwnd: HWND, pixel_buffer: &[u8], client_rect: &Rect) {
+	RECORDINGS.with_borrow_mut(|recordings| {
+		let Some(index) = recordings.iter().position(|recording| recording.hwnd == hwnd) else {
+			return;
+		};
+		let recording = &mut recordings[index];
+		let now = Instant::now();
+		if recording.last_frame.is_some_and(|last_frame| now - last_frame < recording.interval) {
+			return;
+		}
+		if client_rect.width() <= 0 || client_rect.height() <= 0 || recording.queued.load(Ordering::Relaxed) >= MAX_QUEUED_FRAMES {
+			return;
+		}
+		
+		recording.queued.fetch_add(1, Ordering::Relaxed);
+		match recording.sender.send(RecorderMessage::Frame(Image::from_frame(pixel_buffer, client_rect), now)) {
+			Ok(()) => recording.last_frame = Some(now),
+			// The writer stopped after an error, which it reports itself.
+			Err(_) => drop(recordings.remove(index))
+		}
+	});
+}
+
+// Stops the window's recording and waits for its writers, so files are complete once the window process returns.
+pub(crate) fn finish_recordings(hwnd: HWND) {
+	WindowHandle { hwnd }.stop_recording();
+	let writers: Vec<(HWND, JoinHandle<()>)> = WRITERS.with_borrow_mut(|writers| {
+		let (finished, kept) = std::mem::take(writers).into_iter().partition(|(writer_hwnd, _)| *writer_hwnd == hwnd);
+		*writers = kept;
+		finished
+	});
+	for (_, writer) in writers {
+		let _ = writer.join();
+	}
+}
+
+
+enum Encoder {
+	Gif(GifEncoder<BufWriter<File>>),
+	Y4m(Y4mEncoder<BufWriter<File>>)
+}
+impl Encoder {
+	fn size(&self) -> (u32, u32) {
+		match self {
+			Encoder::Gif(encoder) => (encoder.width() as u32, encoder.height() as u32),
+			Encoder::Y4m(encoder) => (encoder.width(), encoder.height())
+		}
+	}
+	
+	// Writes a frame that lasts `count` ticks, hundredths of a second for GIF and frames for Y4M.
+	fn write(&mut self, image: Image, count: u64) -> Result<(), ImageError> {
+		// The window may have been resized since the first frame, later frames are cropped or padded to its size.
+		let (width, height) = self.size();
+		let image = if (image.width(), image.height()) == (width, height) {
+			image
+		} else {
+			let mut fitted = Image::new(width, height)?;
+			image.draw(fitted.pixels_mut(), &Rect { left: 0, top: 0, right: width as i32, bottom: height as i32 }, 0, 0);
+			fitted
+		};
+		match self {
+			Encoder::Gif(encoder) => encoder.add_frame(&image, Duration::from_millis(10 * count)),
+			Encoder::Y4m(encoder) => (0..count).try_for_each(|_| encoder.add_frame(&image))
+		}
+	}
+	
+	fn finish(self) -> Result<(), ImageError> {
+		match self {
+			Encoder::Gif(encoder) => encoder.finish().map(drop),
+			Encoder::Y4m(encoder) => encoder.finish().map(drop)
+		}
+	}
+}
+
+// Each frame is held until the next one arrives, since only then is its duration known.
+fn write_recording(file: File, format: RecordingFormat, frames_per_second: u32, receiver: Receiver<RecorderMessage>, queued: Arc<AtomicUsize>) -> Result<(), ImageError> {
+	let mut file = Some(BufWriter::new(file));
+	let mut encoder: Option<Encoder> = None;
+	let mut start = None;
+	let mut pending: Option<(Image, Instant)> = None;
+	let minimum_count = match format {
+		RecordingFormat::Gif => 2,
+		RecordingFormat::Y4m => 1
+	};
+	
+	loop {
+		let (frame, time) = match receiver.recv() {
+			Ok(RecorderMessage::Frame(image, time)) => {
+				queued.fetch_sub(1, Ordering::Relaxed);
+				(Some(image), time)
+			}
+			Ok(RecorderMessage::Stop(time)) => (None, time),
+			Err(_) => (None, Instant::now())
+		};
+		// Ticks are counted from the start of the recording so rounding errors don't add up.
+		let start = *start.get_or_insert(time);
+		let ticks = |time: Instant| {
+			let seconds = time.saturating_duration_since(start).as_secs_f64();
+			match format {
+				RecordingFormat::Gif => (seconds * 100.0).round() as u64,
+				RecordingFormat::Y4m => (seconds * frames_per_second as f64).round() as u64
+			}
+		};
+		
+		match (pending.take(), frame) {
+			// Too soon after the previous frame to be shown on its own, the newer frame takes its place.
+			(Some((_, since)), Some(image)) if ticks(time).saturating_sub(ticks(since)) < minimum_count => pending = Some((image, since)),
+			(previous, frame) => {
+				if let Some((image, since)) = previous {
+					let encoder = match &mut encoder {
+						Some(encoder) => encoder,
+						None => {
+							let file = file.take().unwrap();
+							encoder.insert(match format {
+								RecordingFormat::Gif => Encoder::Gif(GifEncoder::new(file, image.width().min(u16::MAX as u32) as u16, image.height().min(u16::MAX as u32) as u16)?),
+								RecordingFormat::Y4m => Encoder::Y4m(Y4mEncoder::new(file, image.width(), image.height(), frames_per_second)?)
+							})
+						}
+					};
+					encoder.write(image, ticks(time).saturating_sub(ticks(since)).max(minimum_count))?;
+				}
+				match frame {
+					Some(image) => pending = Some((image, time)),
+					None => break
+				}
+			}
+		}
+	}
+	encoder.map_or(Ok(()), Encoder::finish)
+}
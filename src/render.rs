@@ -0,0 +1,231 @@
+use std::{sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, thread::JoinHandle};
+
+use crate::{Rect, WindowHandle, EventProxy};
+
+
+// A rectangle of the pixel buffer that one thread renders, its rows are slices of the buffer.
+pub struct Tile<'a> {
+	rect: Rect,
+	rows: Vec<&'a mut [u8]>
+}
+impl<'a> Tile<'a> {
+	// In client coordinates.
+	pub fn rect(&self) -> Rect {
+		self.rect
+	}
+	
+	// Rows from the tile's top, each starts at its left edge.
+	pub fn rows_mut(&mut self) -> &mut [&'a mut [u8]] {
+		&mut self.rows
+	}
+	
+	// Fills the tile with the colors `shade` returns for each pixel. With a `block` above 1 it's only called for the top left pixel of each
+	// `block` by `block` square, whose color fills the square. The squares line up across tiles.
+	pub fn shade(&mut self, block: u32, shade: &(impl Fn(i32, i32) -> [u8; 4] + ?Sized)) {
+		let block = block.max(1) as i32;
+		let (left, right) = (self.rect.left, self.rect.right);
+		// Left edge of the first square, which may start in an earlier tile.
+		let first = left - left.rem_euclid(block);
+		let mut colors: Vec<[u8; 4]> = Vec::new();
+		let mut colors_row = None;
+		for (y, row) in (self.rect.top..).zip(self.rows.iter_mut()) {
+			let block_y = y - y.rem_euclid(block);
+			if colors_row != Some(block_y) {
+				colors.clear();
+				colors.extend((first..right).step_by(block as usize).map(|block_x| shade(block_x, block_y)));
+				colors_row = Some(block_y);
+			}
+			for (i, pixel) in row.chunks_exact_mut(4).enumerate() {
+				pixel.copy_from_slice(&colors[((left + i as i32 - first) / block) as usize]);
+			}
+		}
+	}
+}
+
+// Splits the buffer into tiles, row by row. Tiles at the right and bottom edges are smaller.
+fn split_tiles(pixel_buffer: &mut [u8], width: usize, height: usize, tile_size: usize) -> Vec<Tile<'_>> {
+	let stride = 4 * width;
+	let mut tiles: Vec<Tile> = Vec::new();
+	if stride == 0 || height == 0 {
+		return tiles;
+	}
+	for (band_index, band) in pixel_buffer[..stride * height].chunks_mut(stride * tile_size).enumerate() {
+		let top = band_index * tile_size;
+		let bottom = top + band.len() / stride;
+		let first = tiles.len();
+		for (row_index, row) in band.chunks_mut(stride).enumerate() {
+			for (column_index, segment) in row.chunks_mut(4 * tile_size).enumerate() {
+				if row_index == 0 {
+					let left = column_index * tile_size;
+					let rect = Rect { left: left as i32, top: top as i32, right: (left + segment.len() / 4) as i32, bottom: bottom as i32 };
+					tiles.push(Tile { rect, rows: Vec::new() });
+				}
+				tiles[first + column_index].rows.push(segment);
+			}
+		}
+	}
+	tiles
+}
+
+fn buffer_size(pixel_buffer: &[u8], client_rect: &Rect) -> (usize, usize) {
+	let width = client_rect.width().max(0) as usize;
+	let height = (client_rect.height().max(0) as usize).min(pixel_buffer.len() / (4 * width).max(1));
+	(width, height)
+}
+
+
+// Renders the pixel buffer in tiles spread over several threads.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TileRenderer {
+	tile_size: u32,
+	threads: usize
+}
+impl Default for TileRenderer {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl TileRenderer {
+	// Uses a thread per processor.
+	pub fn new() -> Self {
+		Self {
+			tile_size: 64,
+			threads: std::thread::available_parallelism().map_or(1, |threads| threads.get())
+		}
+	}
+	
+	pub fn tile_size(mut self, tile_size: u32) -> Self {
+		self.tile_size = tile_size.max(1);
+		self
+	}
+	
+	pub fn threads(mut self, threads: usize) -> Self {
+		self.threads = threads.max(1);
+		self
+	}
+	
+	// Returns once every tile is rendered.
+	pub fn render_tiles(&self, pixel_buffer: &mut [u8], client_rect: &Rect, render: impl Fn(&mut Tile) + Sync) {
+		let (width, height) = buffer_size(pixel_buffer, client_rect);
+		let tiles = Mutex::new(split_tiles(pixel_buffer, width, height, self.tile_size as usize).into_iter());
+		std::thread::scope(|scope| {
+			for _ in 0..self.threads {
+				scope.spawn(|| loop {
+					let Some(mut tile) = tiles.lock().unwrap_or_else(|err| err.into_inner()).next() else {
+						break;
+					};
+					render(&mut tile);
+				});
+			}
+		});
+	}
+	
+	// Colors each pixel, or each `block` by `block` square, with what `shade` returns for it in the buffer's blue, green, red, alpha order.
+	pub fn render_pixels(&self, pixel_buffer: &mut [u8], client_rect: &Rect, block: u32, shade: impl Fn(i32, i32) -> [u8; 4] + Sync) {
+		self.render_tiles(pixel_buffer, client_rect, |tile| tile.shade(block, &shade));
+	}
+	
+	// Renders in the background, once per entry of `blocks`, so coarse passes like `[8, 1]` show something quickly. The window is asked to redraw
+	// as tiles complete, call `ProgressiveRender::present` in `on_paint` to copy them into the pixel buffer. Dropping the render cancels it.
+	pub fn start_progressive(&self, handle: &WindowHandle, client_rect: &Rect, blocks: &[u32], shade: impl Fn(i32, i32) -> [u8; 4] + Send + Sync + 'static) -> ProgressiveRender {
+		let (width, height) = (client_rect.width().max(0) as usize, client_rect.height().max(0) as usize);
+		let tile_size = self.tile_size as usize;
+		let tiles: Vec<Rect> = (0..height).step_by(tile_size).flat_map(|top| (0..width).step_by(tile_size).map(move |left| Rect {
+			left: left as i32,
+			top: top as i32,
+			right: (left + tile_size).min(width) as i32,
+			bottom: (top + tile_size).min(height) as i32
+		})).collect();
+		// Every tile of a pass is started before the next pass.
+		let jobs: Vec<(usize, usize, u32)> = blocks.iter().enumerate().flat_map(|(pass, &block)| (0..tiles.len()).map(move |tile| (pass, tile, block))).collect();
+		
+		let shared = Arc::new(ProgressiveShared {
+			jobs: Mutex::new(jobs.into_iter()),
+			finished: Mutex::new(Vec::new()),
+			cancelled: AtomicBool::new(false)
+		});
+		let shade: Arc<dyn Fn(i32, i32) -> [u8; 4] + Send + Sync> = Arc::new(shade);
+		let workers = (0..self.threads).filter_map(|_| {
+			let (shared, shade, proxy, tiles) = (shared.clone(), shade.clone(), handle.event_proxy(), tiles.clone());
+			std::thread::Builder::new().name(String::from("tile renderer")).spawn(move || render_progressive(&shared, shade.as_ref(), proxy, &tiles)).ok()
+		}).collect();
+		
+		ProgressiveRender { size: (width, height), passes: blocks.len(), applied: vec![None; tiles.len()], shared, workers }
+	}
+}
+
+
+struct FinishedTile {
+	index: usize,
+	pass: usize,
+	rect: Rect,
+	pixels: Vec<u8>
+}
+
+struct ProgressiveShared {
+	jobs: Mutex<std::vec::IntoIter<(usize, usize, u32)>>,
+	finished: Mutex<Vec<FinishedTile>>,
+	cancelled: AtomicBool
+}
+
+fn render_progressive(shared: &ProgressiveShared, shade: &(dyn Fn(i32, i32) -> [u8; 4] + Send + Sync), proxy: EventProxy, tiles: &[Rect]) {
+	while !shared.cancelled.load(Ordering::Relaxed) {
+		let Some((pass, index, block)) = shared.jobs.lock().unwrap_or_else(|err| err.into_inner()).next() else {
+			break;
+		};
+		let rect = tiles[index];
+		let mut pixels = vec![0; 4 * (rect.width() * rect.height()) as usize];
+		Tile { rect, rows: pixels.chunks_mut(4 * rect.width() as usize).collect() }.shade(block, shade);
+		shared.finished.lock().unwrap_or_else(|err| err.into_inner()).push(FinishedTile { index, pass, rect, pixels });
+		proxy.request_redraw();
+	}
+}
+
+pub struct ProgressiveRender {
+	size: (usize, usize),
+	passes: usize,
+	// The latest pass copied into the pixel buffer for each tile.
+	applied: Vec<Option<usize>>,
+	shared: Arc<ProgressiveShared>,
+	workers: Vec<JoinHandle<()>>
+}
+impl ProgressiveRender {
+	// Copies the tiles completed since the last call into the buffer and returns whether every pass is done.
+	// The tiles keep the size the render was started with, restart it after the window is resized.
+	pub fn present(&mut self, pixel_buffer: &mut [u8], client_rect: &Rect) -> bool {
+		let (width, height) = buffer_size(pixel_buffer, client_rect);
+		let finished = std::mem::take(&mut *self.shared.finished.lock().unwrap_or_else(|err| err.into_inner()));
+		for tile in finished {
+			// A slow coarse tile can finish after a finer one.
+			if self.applied[tile.index].is_some_and(|pass| pass > tile.pass) {
+				continue;
+			}
+			self.applied[tile.index] = Some(tile.pass);
+			let left = tile.rect.left as usize;
+			let columns = (tile.rect.right as usize).min(width).saturating_sub(left);
+			if columns == 0 {
+				continue;
+			}
+			for (y, row) in (tile.rect.top as usize..(tile.rect.bottom as usize).min(height)).zip(tile.pixels.chunks_exact(4 * tile.rect.width() as usize)) {
+				pixel_buffer[4 * (y * width + left)..4 * (y * width + left + columns)].copy_from_slice(&row[..4 * columns]);
+			}
+		}
+		self.is_done()
+	}
+	
+	pub fn is_done(&self) -> bool {
+		self.applied.iter().all(|pass| *pass == self.passes.checked_sub(1))
+	}
+	
+	pub fn size(&self) -> (usize, usize) {
+		self.size
+	}
+}
+impl Drop for ProgressiveRender {
+	fn drop(&mut self) {
+		self.shared.cancelled.store(true, Ordering::Relaxed);
+		for worker in self.workers.drain(..) {
+			let _ = worker.join();
+		}
+	}
+}
@@ -0,0 +1,408 @@
+use std::{any::{Any, TypeId}, cell::{Cell, RefCell}, collections::{HashMap, VecDeque}, future::Future, marker::PhantomData, panic::AssertUnwindSafe, pin::Pin, rc::Rc, sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}}, task::{Context, Poll, Wake, Waker}, time::{Duration, Instant}};
+use windows::Win32::{Foundation::{HWND, WPARAM, LPARAM}, UI::WindowsAndMessaging::{PostMessageW, SetTimer, KillTimer, WM_APP}};
+
+use crate::{WindowHandle, SimpleWindowApp, App, Rect};
+
+
+// Posted to poll a task again, the WPARAM holds the task's id.
+pub(crate) const WM_WAKE_TASK: u32 = WM_APP + 3;
+
+// Wakers can be used from any thread, waking posts a message to the task's window.
+struct TaskWaker {
+	hwnd: HWND,
+	id: usize,
+	scheduled: AtomicBool
+}
+impl Wake for TaskWaker {
+	fn wake(self: Arc<Self>) {
+		self.wake_by_ref();
+	}
+	
+	fn wake_by_ref(self: &Arc<Self>) {
+		if !self.scheduled.swap(true, Ordering::AcqRel) {
+			self.post();
+		}
+	}
+}
+impl TaskWaker {
+	// Fails only once the window is gone, and its tasks with it.
+	fn post(&self) {
+		let _ = unsafe { PostMessageW(self.hwnd, WM_WAKE_TASK, WPARAM(self.id), LPARAM(0)) };
+	}
+}
+
+struct Task {
+	waker: Arc<TaskWaker>,
+	future: Pin<Box<dyn Future<Output = ()>>>
+}
+
+thread_local! {
+	static TASKS: RefCell<HashMap<usize, Task>> = RefCell::new(HashMap::new());
+	static NEXT_TASK_ID: Cell<usize> = const { Cell::new(0) };
+	// Wakers of pending `Sleep`s by the id of their timer.
+	static SLEEPERS: RefCell<HashMap<usize, Waker>> = RefCell::new(HashMap::new());
+	// The app of the task being polled, for `AppAccess`.
+	static POLLING_APP: Cell<Option<*mut App>> = const { Cell::new(None) };
+	// Queues of the windows whose `EventProxy` payloads go to a task instead of `on_user_event`.
+	static USER_EVENTS: RefCell<Vec<(HWND, Rc<RefCell<UserEventQueue>>)>> = const { RefCell::new(Vec::new()) };
+}
+
+impl WindowHandle {
+	// Runs the future on the window's thread, in between its messages. The future reaches the app's state through `app_access`.
+	// Tasks that haven't finished when the window closes are dropped.
+	pub fn spawn_local(&self, future: impl Future<Output = ()> + 'static) {
+		let id = NEXT_TASK_ID.get();
+		NEXT_TASK_ID.set(id.wrapping_add(1));
+		let waker = Arc::new(TaskWaker { hwnd: self.hwnd, id, scheduled: AtomicBool::new(false) });
+		TASKS.with_borrow_mut(|tasks| tasks.insert(id, Task { waker: waker.clone(), future: Box::pin(future) }));
+		waker.wake_by_ref();
+	}
+	
+	// Lets tasks use the app's state while they run, `T` is the type the window was started with.
+	pub fn app_access<T: SimpleWindowApp + 'static>(&self) -> AppAccess<T> {
+		AppAccess { hwnd: self.hwnd, app_type: PhantomData }
+	}
+	
+	// Takes the window's `EventProxy` payloads away from `on_user_event` for as long as the receiver exists. There can only be one per window.
+	pub fn user_events(&self) -> Result<UserEvents, String> {
+		USER_EVENTS.with_borrow_mut(|receivers| {
+			if receivers.iter().any(|(hwnd, _)| *hwnd == self.hwnd) {
+				return Err(String::from("Error receiving user events: The window already has a receiver."));
+			}
+			let queue = Rc::new(RefCell::new(UserEventQueue { payloads: VecDeque::new(), waker: None }));
+			receivers.push((self.hwnd, queue.clone()));
+			Ok(UserEvents { hwnd: self.hwnd, queue })
+		})
+	}
+}
+
+// The task is taken out of the registry while it's polled, so it can spawn tasks and run modal loops that poll others.
+pub(crate) fn poll_task(id: usize, app: *mut App) {
+	let Some(mut task) = TASKS.with_borrow_mut(|tasks| tasks.remove(&id)) else {
+		return;
+	};
+	task.waker.scheduled.store(false, Ordering::Release);
+	let waker = Waker::from(task.waker.clone());
+	if polling(app, || task.future.as_mut().poll(&mut Context::from_waker(&waker))).is_ready() {
+		return;
+	}
+	// A wake message handled during the poll found no task, so it's sent again.
+	if task.waker.scheduled.load(Ordering::Acquire) {
+		task.waker.post();
+	}
+	TASKS.with_borrow_mut(|tasks| tasks.insert(id, task));
+}
+
+// Makes `app` the one `AppAccess` reaches while `f` runs. The app of an outer poll is put back also when `f` panics and takes its window down.
+pub(crate) fn polling<R>(app: *mut App, f: impl FnOnce() -> R) -> R {
+	struct OuterApp(Option<*mut App>);
+	impl Drop for OuterApp {
+		fn drop(&mut self) {
+			POLLING_APP.set(self.0);
+		}
+	}
+	
+	let _outer_app = OuterApp(POLLING_APP.replace(Some(app)));
+	f()
+}
+
+pub(crate) fn drop_tasks(hwnd: HWND) {
+	let dropped: Vec<Task> = TASKS.with_borrow_mut(|tasks| {
+		let ids: Vec<usize> = tasks.iter().filter(|(_, task)| task.waker.hwnd == hwnd).map(|(id, _)| *id).collect();
+		ids.iter().filter_map(|id| tasks.remove(id)).collect()
+	});
+	drop(dropped);
+}
+
+
+// Borrows the app's state from inside a task. It can be kept across awaits, only the borrow in `with` can't.
+pub struct AppAccess<T> {
+	hwnd: HWND,
+	app_type: PhantomData<fn() -> T>
+}
+impl<T> Clone for AppAccess<T> {
+	fn clone(&self) -> Self {
+		*self
+	}
+}
+impl<T> Copy for AppAccess<T> {}
+impl<T: SimpleWindowApp + 'static> AppAccess<T> {
+	// Fails outside of the window's tasks, for the wrong type and while the state is already borrowed. That is from a callback
+	// whose modal loop polled the task, or from another `with` whose `f` ran a modal loop.
+	pub fn with<R>(&self, f: impl FnOnce(&mut T, &WindowHandle, &mut [u8], &Rect) -> R) -> Result<R, String> {
+		let Some(app) = POLLING_APP.get() else {
+			return Err(String::from("Error accessing app state: Only a running task can access it."));
+		};
+		// Fields are reached through the pointer one by one, the window procedure further out still refers to the app.
+		if unsafe { (*app).window_handle.hwnd } != self.hwnd {
+			return Err(String::from("Error accessing app state: The task belongs to another window."));
+		}
+		if unsafe { (*app).user_state_type } != TypeId::of::<T>() {
+			return Err(format!("Error accessing app state: The app is not a {}.", std::any::type_name::<T>()));
+		}
+		if unsafe { (*app).state_borrowed } {
+			return Err(String::from("Error accessing app state: It is already borrowed."));
+		}
+		unsafe { (*app).state_borrowed = true };
+		// The type was checked above, so the pointer's vtable can be dropped.
+		let state = unsafe { &mut *(&mut *(*app).user_state as *mut dyn SimpleWindowApp as *mut T) };
+		let result = std::panic::catch_unwind(AssertUnwindSafe(|| unsafe { f(state, &(*app).window_handle, &mut (*app).pixel_buffer, &(*app).client_rect) }));
+		unsafe { (*app).state_borrowed = false };
+		match result {
+			Ok(result) => Ok(result),
+			Err(payload) => std::panic::resume_unwind(payload)
+		}
+	}
+}
+
+
+struct UserEventQueue {
+	payloads: VecDeque<Box<dyn Any + Send>>,
+	waker: Option<Waker>
+}
+
+// Hands the payload back when no task receives the window's events.
+pub(crate) fn deliver_user_event(hwnd: HWND, payload: Box<dyn Any + Send>) -> Option<Box<dyn Any + Send>> {
+	let Some(queue) = USER_EVENTS.with_borrow(|receivers| receivers.iter().find(|(receiver_hwnd, _)| *receiver_hwnd == hwnd).map(|(_, queue)| queue.clone())) else {
+		return Some(payload);
+	};
+	let waker = {
+		let mut queue = queue.borrow_mut();
+		queue.payloads.push_back(payload);
+		queue.waker.take()
+	};
+	if let Some(waker) = waker {
+		waker.wake();
+	}
+	None
+}
+
+pub struct UserEvents {
+	hwnd: HWND,
+	queue: Rc<RefCell<UserEventQueue>>
+}
+impl UserEvents {
+	// Completes with the next payload sent through the window's `EventProxy`.
+	pub fn recv(&mut self) -> RecvUserEvent<'_> {
+		RecvUserEvent { events: self }
+	}
+	
+	pub fn try_recv(&mut self) -> Option<Box<dyn Any + Send>> {
+		self.queue.borrow_mut().payloads.pop_front()
+	}
+}
+impl Drop for UserEvents {
+	// Payloads still queued are dropped, later ones go to `on_user_event` again.
+	fn drop(&mut self) {
+		USER_EVENTS.with_borrow_mut(|receivers| receivers.retain(|(hwnd, queue)| *hwnd != self.hwnd || !Rc::ptr_eq(queue, &self.queue)));
+	}
+}
+
+pub struct RecvUserEvent<'a> {
+	events: &'a mut UserEvents
+}
+impl Future for RecvUserEvent<'_> {
+	type Output = Box<dyn Any + Send>;
+	
+	fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Box<dyn Any + Send>> {
+		let mut queue = self.events.queue.borrow_mut();
+		match queue.payloads.pop_front() {
+			Some(payload) => Poll::Ready(payload),
+			None => {
+				queue.waker = Some(context.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
+
+
+// Completes after `duration`. Only for tasks, it relies on the thread's message loop.
+pub fn sleep(duration: Duration) -> Sleep {
+	Sleep { deadline: Instant::now() + duration, timer: None }
+}
+
+pub struct Sleep {
+	deadline: Instant,
+	timer: Option<usize>
+}
+impl Future for Sleep {
+	type Output = ();
+	
+	fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+		let remaining = self.deadline.saturating_duration_since(Instant::now());
+		if remaining.is_zero() {
+			self.cancel();
+			return Poll::Ready(());
+		}
+		let registered = self.timer.is_some_and(|timer| SLEEPERS.with_borrow_mut(|sleepers| sleepers.get_mut(&timer).map(|waker| waker.clone_from(context.waker())).is_some()));
+		if !registered {
+			// Timers can fire a little early, the remaining time is rounded up and checked again.
+			let milliseconds = remaining.as_nanos().div_ceil(1_000_000).min(u32::MAX as u128) as u32;
+			let timer = unsafe { SetTimer(HWND(0), 0, milliseconds, Some(wake_sleeper)) };
+			if timer == 0 {
+				context.waker().wake_by_ref();
+				return Poll::Pending;
+			}
+			SLEEPERS.with_borrow_mut(|sleepers| sleepers.insert(timer, context.waker().clone()));
+			self.timer = Some(timer);
+		}
+		Poll::Pending
+	}
+}
+impl Sleep {
+	// Timer ids are reused, so only a timer that hasn't fired yet is killed.
+	fn cancel(&mut self) {
+		if let Some(timer) = self.timer.take() {
+			if SLEEPERS.with_borrow_mut(|sleepers| sleepers.remove(&timer)).is_some() {
+				let _ = unsafe { KillTimer(HWND(0), timer) };
+			}
+		}
+	}
+}
+impl Drop for Sleep {
+	fn drop(&mut self) {
+		self.cancel();
+	}
+}
+
+unsafe extern "system" fn wake_sleeper(_hwnd: HWND, _message: u32, timer: usize, _time: u32) {
+	let _ = unsafe { KillTimer(HWND(0), timer) };
+	if let Some(waker) = SLEEPERS.with_borrow_mut(|sleepers| sleepers.remove(&timer)) {
+		waker.wake();
+	}
+}
+
+
+struct BackgroundState<T> {
+	result: Option<Result<T, String>>,
+	waker: Option<Waker>
+}
+
+// Runs blocking work such as file I/O on its own thread and completes with its result. A panic in the work becomes an error.
+pub fn run_in_background<T: Send + 'static>(work: impl FnOnce() -> T + Send + 'static) -> Background<T> {
+	let state = Arc::new(Mutex::new(BackgroundState { result: None, waker: None }));
+	let worker_state = state.clone();
+	let finish = move |result: Result<T, String>| {
+		let mut state = worker_state.lock().unwrap_or_else(|err| err.into_inner());
+		state.result = Some(result);
+		if let Some(waker) = state.waker.take() {
+			waker.wake();
+		}
+	};
+	let spawned = std::thread::Builder::new().name(String::from("background")).spawn({
+		let finish = finish.clone();
+		move || finish(std::panic::catch_unwind(AssertUnwindSafe(work)).map_err(|_| String::from("Error running background work: Worker thread panicked.")))
+	});
+	if let Err(err) = spawned {
+		finish(Err(format!("Error running background work: {err}")));
+	}
+	Background { state }
+}
+
+pub struct Background<T> {
+	state: Arc<Mutex<BackgroundState<T>>>
+}
+impl<T> Future for Background<T> {
+	type Output = Result<T, String>;
+	
+	fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Result<T, String>> {
+		let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+		match state.result.take() {
+			Some(result) => Poll::Ready(result),
+			None => {
+				state.waker = Some(context.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
+
+
+struct ChannelState<T> {
+	queue: VecDeque<T>,
+	waker: Option<Waker>,
+	senders: usize,
+	receiving: bool
+}
+
+// Carries values from any thread to a task, such as progress from a worker.
+pub fn channel<T: Send>() -> (Sender<T>, Receiver<T>) {
+	let state = Arc::new(Mutex::new(ChannelState { queue: VecDeque::new(), waker: None, senders: 1, receiving: true }));
+	(Sender { state: state.clone() }, Receiver { state })
+}
+
+pub struct Sender<T> {
+	state: Arc<Mutex<ChannelState<T>>>
+}
+impl<T> Sender<T> {
+	// Fails once the receiver is dropped.
+	pub fn send(&self, value: T) -> Result<(), String> {
+		let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+		if !state.receiving {
+			return Err(String::from("Error sending to task: Receiver was dropped."));
+		}
+		state.queue.push_back(value);
+		if let Some(waker) = state.waker.take() {
+			waker.wake();
+		}
+		Ok(())
+	}
+}
+impl<T> Clone for Sender<T> {
+	fn clone(&self) -> Self {
+		self.state.lock().unwrap_or_else(|err| err.into_inner()).senders += 1;
+		Self { state: self.state.clone() }
+	}
+}
+impl<T> Drop for Sender<T> {
+	fn drop(&mut self) {
+		let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+		state.senders -= 1;
+		if state.senders == 0 {
+			if let Some(waker) = state.waker.take() {
+				waker.wake();
+			}
+		}
+	}
+}
+
+pub struct Receiver<T> {
+	state: Arc<Mutex<ChannelState<T>>>
+}
+impl<T> Receiver<T> {
+	// Completes with the next value, or None once every sender is dropped and the values are used up.
+	pub fn recv(&mut self) -> Recv<'_, T> {
+		Recv { receiver: self }
+	}
+	
+	pub fn try_recv(&mut self) -> Option<T> {
+		self.state.lock().unwrap_or_else(|err| err.into_inner()).queue.pop_front()
+	}
+}
+impl<T> Drop for Receiver<T> {
+	fn drop(&mut self) {
+		let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+		state.receiving = false;
+		state.queue.clear();
+	}
+}
+
+pub struct Recv<'a, T> {
+	receiver: &'a mut Receiver<T>
+}
+impl<T> Future for Recv<'_, T> {
+	type Output = Option<T>;
+	
+	fn poll(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<Option<T>> {
+		let mut state = self.receiver.state.lock().unwrap_or_else(|err| err.into_inner());
+		match state.queue.pop_front() {
+			Some(value) => Poll::Ready(Some(value)),
+			None if state.senders == 0 => Poll::Ready(None),
+			None => {
+				state.waker = Some(context.waker().clone());
+				Poll::Pending
+			}
+		}
+	}
+}
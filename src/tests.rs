@@ -66,4 +66,750 @@ fn it_works() {
 		println!("{message}");
 	}
 	
-}
\ No newline at end of file
+}
+
+#[test]
+fn menu_definition_parses() {
+	let definition = MenuDefinition::parse("
+		command open = 1
+		command zoom_in = 20 # comment
+		
+		submenu \"&File\"
+			item open \"&Open...\" shortcut \"Ctrl+O\"
+			item 2 \"Autosave\" checked
+			separator
+			radio zoom_in \"Zoom in\" selected
+			radio 21 \"Zoom out\" disabled
+		end
+	").unwrap();
+	
+	assert_eq!(definition.command_id("zoom_in"), Some(20));
+	assert_eq!(definition.spec, MenuSpec::new().submenu("&File", MenuSpec::new()
+		.item_spec(MenuItemSpec::new(1, "&Open...").shortcut("Ctrl+O"))
+		.item_spec(MenuItemSpec::new(2, "Autosave").checkable(true))
+		.separator()
+		.radio_group(vec![MenuItemSpec::new(20, "Zoom in"), MenuItemSpec::new(21, "Zoom out").enabled(false)], Some(20))
+	));
+}
+
+#[test]
+fn menu_definition_reports_line_numbers() {
+	assert_eq!(MenuDefinition::parse("submenu \"File\"\n\titem missing \"Open\"\nend").unwrap_err(), "Error on line 2: Unknown command \"missing\".");
+	assert_eq!(MenuDefinition::parse("\ncommand big = 4095").unwrap_err(), "Error on line 2: ID 4095 is not allowed, 4094 is the maximum value.");
+	assert_eq!(MenuDefinition::parse("submenu \"File\"\n\titem 1 \"Open\"").unwrap_err(), "Error on line 1: Submenu \"File\" is missing its `end`.");
+}
+
+#[test]
+fn menus_are_not_leaked() {
+	let before = Menu::live_count();
+	let menu = MenuSpec::new().item(1, "One").submenu("Sub", MenuSpec::new().item(2, "Two")).build().unwrap();
+	assert_eq!(Menu::live_count(), before + 1);
+	
+	let submenu = Menu::new().unwrap();
+	submenu.add_item(3, "Three").unwrap();
+	menu.add_submenu(submenu, "Other").unwrap();
+	assert_eq!(Menu::live_count(), before + 1);
+	
+	drop(menu);
+	assert_eq!(Menu::live_count(), before);
+}
+
+#[test]
+fn disabled_items_read_back_as_disabled() {
+	let menu = MenuSpec::new().item(1, "One").item(2, "Two").build().unwrap();
+	menu.set_item_enable(1, false).unwrap();
+	assert!(!menu.is_enabled(1).unwrap());
+	assert!(menu.is_enabled(2).unwrap());
+	menu.set_item_enable(1, true).unwrap();
+	assert!(menu.is_enabled(1).unwrap());
+}
+
+#[test]
+fn destroying_the_menu_bar_releases_item_resources() {
+	use windows::{core::w, Win32::{Foundation::HWND, UI::WindowsAndMessaging::{CreateWindowExW, DestroyWindow, GetMenu, SetMenu, WINDOW_EX_STYLE, WS_OVERLAPPEDWINDOW}}};
+	let hwnd = unsafe { CreateWindowExW(WINDOW_EX_STYLE(0), w!("STATIC"), None, WS_OVERLAPPEDWINDOW, 0, 0, 100, 100, None, None, None, None) };
+	assert_ne!(hwnd, HWND(0));
+	
+	let menu = MenuSpec::new().item(1, "One").submenu("Sub", MenuSpec::new().item(2, "Two")).build().unwrap();
+	menu.set_item_bitmap(1, 1, 1, &[255, 0, 0, 255]).unwrap();
+	menu.set_item_owner_draw(2, 10, 10, |_, _, _| {}).unwrap();
+	let (hmenu, submenu) = (menu.hmenu, unsafe { windows::Win32::UI::WindowsAndMessaging::GetSubMenu(menu.hmenu, 1) });
+	unsafe { SetMenu(hwnd, menu.into_hmenu()) }.unwrap();
+	assert!(menu_graphics::has_item_resources(hmenu) && menu_graphics::has_item_resources(submenu));
+	
+	destroy_menu_bar(hwnd);
+	assert!(unsafe { GetMenu(hwnd) }.is_invalid());
+	assert!(!menu_graphics::has_item_resources(hmenu) && !menu_graphics::has_item_resources(submenu));
+	unsafe { DestroyWindow(hwnd) }.unwrap();
+}
+
+#[test]
+fn menu_bar_borrows_are_counted() {
+	use windows::Win32::Foundation::HWND;
+	let (hwnd, other) = (HWND(1), HWND(2));
+	update_menu_bar_borrows(hwnd, 1);
+	update_menu_bar_borrows(hwnd, 1);
+	assert_eq!((menu_bar_borrows(hwnd), menu_bar_borrows(other)), (2, 0));
+	update_menu_bar_borrows(hwnd, -1);
+	update_menu_bar_borrows(hwnd, -1);
+	assert_eq!(menu_bar_borrows(hwnd), 0);
+	assert!(MENU_BAR_BORROWS.with_borrow(|borrows| borrows.is_empty()));
+	
+	// Edits that remove entries need the only borrow of the window's menu bar.
+	let bar = MenuRef { hmenu: windows::Win32::UI::WindowsAndMessaging::HMENU(0), window: Some(hwnd), owner: std::marker::PhantomData };
+	update_menu_bar_borrows(hwnd, 1);
+	assert!(bar.check_exclusive().is_ok());
+	update_menu_bar_borrows(hwnd, 1);
+	assert!(bar.check_exclusive().is_err());
+	update_menu_bar_borrows(hwnd, -2);
+	assert!(MenuRef::from_hmenu(bar.hmenu).check_exclusive().is_ok());
+}
+
+#[test]
+fn menu_spec_apply_checks_the_whole_menu_first() {
+	let old = MenuSpec::new().item(1, "One").submenu("Sub", MenuSpec::new().item(2, "Two").item(3, "Three"));
+	let new = MenuSpec::new().item(1, "Uno").submenu("Sub", MenuSpec::new().item(2, "Dos"));
+	let mut menu = old.build().unwrap();
+	
+	// The submenu no longer matches `old`, so nothing is edited, not even the top level label.
+	menu.edit().get_submenu(1).unwrap().remove_item(3).unwrap();
+	assert!(new.apply(&mut menu.edit(), &old).is_err());
+	assert_eq!(menu.item_text(1).unwrap(), "One");
+	
+	let fixed = MenuSpec::new().item(1, "One").submenu("Sub", MenuSpec::new().item(2, "Two"));
+	new.apply(&mut menu.edit(), &fixed).unwrap();
+	assert_eq!(menu.item_text(1).unwrap(), "Uno");
+	assert_eq!(menu.item_text(2).unwrap(), "Dos");
+	
+	// IDs are checked before the first edit too.
+	assert!(MenuSpec::new().item(1, "One").item(4095, "Too large").apply(&mut menu.edit(), &new).is_err());
+	assert_eq!(menu.item_text(1).unwrap(), "Uno");
+}
+
+#[test]
+fn adjacent_radio_groups_are_rejected() {
+	let group = |first: u16| vec![MenuItemSpec::new(first, "A"), MenuItemSpec::new(first + 1, "B")];
+	let adjacent = MenuSpec::new().radio_group(group(1), Some(1)).radio_group(group(3), Some(3));
+	assert!(menu_spec::check_spec(&adjacent).is_err());
+	assert!(menu_spec::check_spec(&MenuSpec::new().radio_group(group(1), Some(1)).radio_group(vec![], None).radio_group(group(3), None)).is_err());
+	assert!(menu_spec::check_spec(&MenuSpec::new().submenu("Sub", adjacent)).is_err());
+	assert!(menu_spec::check_spec(&MenuSpec::new().radio_group(group(1), Some(1)).separator().radio_group(group(3), Some(3))).is_ok());
+	assert!(menu_spec::check_spec(&MenuSpec::new().radio_group(group(1), Some(1)).item(5, "C").radio_group(group(3), Some(3))).is_ok());
+}
+
+#[test]
+fn separated_radio_groups_stay_apart() {
+	let menu = Menu::new().unwrap();
+	menu.add_radio_group(&[1, 2], &["A", "B"], 1).unwrap();
+	assert!(menu.add_radio_group(&[3, 4], &["C", "D"], 3).is_err());
+	menu.add_separator().unwrap();
+	menu.add_radio_group(&[3, 4], &["C", "D"], 3).unwrap();
+	
+	menu.select_radio(2).unwrap();
+	assert_eq!(menu.selected_radio(1).unwrap(), Some(2));
+	assert_eq!(menu.selected_radio(4).unwrap(), Some(3));
+	
+	let adjacent = MenuSpec::new().radio_group(vec![MenuItemSpec::new(1, "A")], Some(1)).radio_group(vec![MenuItemSpec::new(2, "B")], Some(2));
+	assert!(adjacent.build().is_err());
+}
+
+#[test]
+fn menu_spec_plans_edits() {
+	use menu_spec::{plan_edits, MenuEdit};
+	let old = MenuSpec::new().item(1, "One").item(2, "Two").submenu("Sub", MenuSpec::new().item(3, "Three"));
+	assert_eq!(plan_edits(&old, &old), vec![]);
+	
+	let inserted = old.clone().item(4, "Four");
+	assert_eq!(plan_edits(&inserted, &old), vec![MenuEdit::Insert(3)]);
+	
+	let removed = MenuSpec::new().item(1, "One").submenu("Sub", MenuSpec::new().item(3, "Three"));
+	assert_eq!(plan_edits(&removed, &old), vec![MenuEdit::Replace(1), MenuEdit::Remove(2)]);
+	
+	let reordered = MenuSpec::new().item(2, "Two").item(1, "One").submenu("Sub", MenuSpec::new().item(3, "Three"));
+	assert_eq!(plan_edits(&reordered, &old), vec![MenuEdit::Replace(0), MenuEdit::Replace(1)]);
+	
+	let relabeled = MenuSpec::new().item(1, "Uno").item(2, "Two").submenu("Sub", MenuSpec::new().item(3, "Tres"));
+	assert_eq!(plan_edits(&relabeled, &old), vec![MenuEdit::Update(0), MenuEdit::Submenu(2, vec![MenuEdit::Update(0)])]);
+	
+	let renamed_submenu = MenuSpec::new().item(1, "One").item(2, "Two").submenu("Other", MenuSpec::new().item(3, "Three"));
+	assert_eq!(plan_edits(&renamed_submenu, &old), vec![MenuEdit::Update(2)]);
+	
+	let disabled = MenuSpec::new().item_spec(MenuItemSpec::new(1, "One").enabled(false)).item(2, "Two").submenu("Sub", MenuSpec::new().item(3, "Three"));
+	assert_eq!(plan_edits(&disabled, &old), vec![MenuEdit::Update(0)]);
+	
+	assert!(menu_spec::check_spec(&MenuSpec::new().submenu("Sub", MenuSpec::new().item(4095, "Too large"))).is_err());
+	assert!(menu_spec::check_spec(&relabeled).is_ok());
+}
+
+#[test]
+fn context_menus_use_screen_coordinates() {
+	use windows::Win32::{Foundation::POINT, UI::WindowsAndMessaging::{TPM_RETURNCMD, TPM_NONOTIFY, TPM_RIGHTBUTTON}};
+	let point = client_to_screen(POINT { x: 100, y: 50 }, 10, -5);
+	assert_eq!((point.x, point.y), (110, 45));
+	
+	let showing = context_menu_flags(false);
+	assert_eq!(showing & TPM_RIGHTBUTTON, TPM_RIGHTBUTTON);
+	assert_eq!(showing & (TPM_RETURNCMD | TPM_NONOTIFY), Default::default());
+	let tracking = context_menu_flags(true);
+	assert_eq!(tracking & (TPM_RETURNCMD | TPM_NONOTIFY), TPM_RETURNCMD | TPM_NONOTIFY);
+}
+
+#[test]
+fn decodes_menu_notifications() {
+	use windows::Win32::Foundation::{WPARAM, LPARAM};
+	let select = |id: u32, flags: u32, hmenu: isize| menu_hover(WPARAM((flags << 16 | id) as usize), LPARAM(hmenu));
+	assert_eq!(select(0xF001 + 7, 0, 0x100), Some(Some(7)));
+	// Separators, popups and items without one of our IDs hover without a command.
+	assert_eq!(select(0, 0x800, 0x100), Some(None));
+	assert_eq!(select(2, 0x10, 0x100), Some(None));
+	assert_eq!(select(100, 0, 0x100), Some(None));
+	// Closing, and SC_CLOSE in the system menu.
+	assert_eq!(select(0, 0xFFFF, 0), None);
+	assert_eq!(select(0xF060, 0x2000, 0x100), None);
+	
+	assert!(!is_system_menu_popup(LPARAM(2)));
+	assert!(is_system_menu_popup(LPARAM(1 << 16)));
+}
+
+#[test]
+fn decodes_bmp() {
+	// 2x2, 24 bit, bottom-up rows padded to 4 bytes.
+	let mut bmp = b"BM\0\0\0\0\0\0\0\0\x36\0\0\0".to_vec();
+	bmp.extend_from_slice(&[40, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 24, 0]);
+	bmp.extend_from_slice(&[0; 24]);
+	bmp.extend_from_slice(&[0, 0, 255, 0, 255, 0, 0, 0, 255, 0, 0, 255, 255, 255, 0, 0]);
+	
+	let image = image::Image::decode(&bmp).unwrap();
+	assert_eq!((image.width(), image.height()), (2, 2));
+	assert_eq!(image.get_pixel(0, 0), Some([255, 0, 0, 255]));
+	assert_eq!(image.get_pixel(1, 0), Some([255, 255, 255, 255]));
+	assert_eq!(image.get_pixel(0, 1), Some([0, 0, 255, 255]));
+	assert_eq!(image.get_pixel(1, 1), Some([0, 255, 0, 255]));
+	
+	assert_eq!(image::Image::decode(&bmp[..60]), Err(image::ImageError::UnexpectedEnd));
+}
+
+#[test]
+fn decodes_netpbm() {
+	let ppm = image::Image::decode(b"P3\n# comment\n2 1\n255\n255 0 0  0 0 255\n").unwrap();
+	assert_eq!(ppm.pixels(), &[0, 0, 255, 255, 255, 0, 0, 255]);
+	
+	let pbm = image::Image::decode(b"P1 3 1 010").unwrap();
+	assert_eq!(pbm.pixels(), &[255, 255, 255, 255, 0, 0, 0, 255, 255, 255, 255, 255]);
+	
+	let pgm = image::Image::decode(b"P5 2 1 65535\n\xFF\xFF\x00\x00").unwrap();
+	assert_eq!(pgm.pixels(), &[255, 255, 255, 255, 0, 0, 0, 255]);
+	
+	assert!(matches!(image::Image::decode(b"P2 1 1 10 11"), Err(image::ImageError::Malformed(_))));
+	assert_eq!(image::Image::decode(b"P6 2 2 255\n\0\0\0"), Err(image::ImageError::UnexpectedEnd));
+}
+
+#[test]
+fn decodes_tga() {
+	// 3x1 run length encoded true color, top to bottom: a run of two red pixels and one raw blue pixel.
+	let mut tga = vec![0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0, 1, 0, 24, 0x20];
+	tga.extend_from_slice(&[0x81, 0, 0, 255, 0x00, 255, 0, 0]);
+	
+	let image = image::Image::decode_tga(&tga).unwrap();
+	assert_eq!(image.pixels(), &[0, 0, 255, 255, 0, 0, 255, 255, 255, 0, 0, 255]);
+	assert_eq!(image::Image::decode_tga(&tga[..21]), Err(image::ImageError::UnexpectedEnd));
+	
+	// A bare 16384x16384 header fails before anything is allocated for it.
+	let header = [0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x00, 0x40, 0x00, 0x40, 32, 0];
+	assert_eq!(image::Image::decode_tga(&header), Err(image::ImageError::UnexpectedEnd));
+}
+
+#[test]
+fn decodes_png() {
+	// 3x3 interlaced, 2 bit palette with a half transparent first entry, written by zlib rather than our encoder.
+	let png = [
+		0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x03,
+		0x02, 0x03, 0x00, 0x00, 0x01, 0x5c, 0x41, 0x6d, 0xba, 0x00, 0x00, 0x00, 0x0c, 0x50, 0x4c, 0x54, 0x45, 0xff, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00,
+		0x00, 0xff, 0xff, 0xff, 0xff, 0xfb, 0x00, 0x60, 0xf6, 0x00, 0x00, 0x00, 0x01, 0x74, 0x52, 0x4e, 0x53, 0x80, 0xad, 0x5e, 0x5b, 0x46, 0x00, 0x00,
+		0x00, 0x12, 0x49, 0x44, 0x41, 0x54, 0x78, 0xda, 0x63, 0x60, 0x60, 0x68, 0x00, 0x42, 0x07, 0x86, 0x03, 0x0c, 0x47, 0x00, 0x0c, 0x50, 0x02, 0xc5,
+		0x86, 0x2a, 0xa0, 0xa9, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82
+	];
+	let palette = [[0, 0, 255, 128], [0, 255, 0, 255], [255, 0, 0, 255], [255, 255, 255, 255]];
+	let indices = [[0, 1, 2], [3, 0, 1], [2, 3, 0]];
+	
+	let image = image::Image::decode(&png).unwrap();
+	assert_eq!((image.width(), image.height()), (3, 3));
+	for (y, row) in indices.iter().enumerate() {
+		for (x, &index) in row.iter().enumerate() {
+			assert_eq!(image.get_pixel(x as u32, y as u32), Some(palette[index]));
+		}
+	}
+	
+	let mut corrupted = png.to_vec();
+	corrupted[90] ^= 1;
+	assert!(matches!(image::Image::decode_png(&corrupted), Err(image::ImageError::Malformed(_))));
+}
+
+#[test]
+fn png_round_trips() {
+	// Smooth gradients compress well, the noisy band forces literals and long distances.
+	let (width, height) = (300, 200);
+	let mut pixels = Vec::new();
+	let mut seed = 12345u32;
+	for y in 0..height {
+		for x in 0..width {
+			seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+			let noise = (seed >> 16) as u8;
+			if (80..100).contains(&y) {
+				pixels.extend_from_slice(&[noise, noise.wrapping_mul(3), x as u8, (y * 2) as u8]);
+			} else {
+				pixels.extend_from_slice(&[x as u8, y as u8, (x + y) as u8, 255]);
+			}
+		}
+	}
+	let image = image::Image::from_bgra(width, height, pixels).unwrap();
+	
+	let encoded = image.encode_png(image::PngFormat::Rgba8).unwrap();
+	assert_eq!(image::Image::decode(&encoded).unwrap(), image);
+	
+	let opaque = image::Image::decode(&image.encode_png(image::PngFormat::Rgb8).unwrap()).unwrap();
+	assert_eq!(opaque.get_pixel(10, 90).map(|pixel| pixel[3]), Some(255));
+	assert_eq!(opaque.get_pixel(10, 10), image.get_pixel(10, 10));
+	
+	let empty = image::Image::new(1, 1).unwrap();
+	assert_eq!(image::Image::decode_png(&empty.encode_png(image::PngFormat::Rgba8).unwrap()).unwrap(), empty);
+	assert!(matches!(image::Image::new(0, 3).unwrap().encode_png(image::PngFormat::Rgba8), Err(image::ImageError::Unsupported(_))));
+	assert!(matches!(image::Image::default().encode_png(image::PngFormat::Rgb8), Err(image::ImageError::Unsupported(_))));
+}
+
+#[test]
+fn png_stops_inflating_at_the_image_size() {
+	fn crc32(bytes: &[u8]) -> u32 {
+		!bytes.iter().fold(!0u32, |crc, &byte| (0..8).fold(crc ^ byte as u32, |crc, _| if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 }))
+	}
+	
+	// A large blank image compresses to a few KB, its header is then rewritten to claim 1x1.
+	let mut png = image::Image::new(1024, 1024).unwrap().encode_png(image::PngFormat::Rgba8).unwrap();
+	png[16..24].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]);
+	let crc = crc32(&png[12..29]);
+	png[29..33].copy_from_slice(&crc.to_be_bytes());
+	assert!(png.len() < 16384);
+	assert_eq!(image::Image::decode_png(&png), Err(image::ImageError::Malformed(String::from("zlib data is longer than expected."))));
+}
+
+#[test]
+fn captures_frames() {
+	// A 3x2 frame whose padding bytes hold leftovers, as they can in the window's pixel buffer.
+	let frame: Vec<u8> = (0..24).collect();
+	let client_rect = Rect { left: 0, top: 0, right: 3, bottom: 2 };
+	let image = image::Image::from_frame(&frame, &client_rect);
+	assert_eq!((image.width(), image.height()), (3, 2));
+	assert_eq!(image.get_pixel(0, 1), Some([12, 13, 14, 255]));
+	
+	let bmp = image.encode_bmp();
+	assert_eq!(bmp.len(), 54 + 2 * 12);
+	assert_eq!(image::Image::decode(&bmp).unwrap(), image);
+	assert_eq!(image::Image::decode(&image.encode_png(image::PngFormat::Rgb8).unwrap()).unwrap(), image);
+}
+
+#[test]
+fn encodes_animation_frames() {
+	let mut frame = image::Image::new(2, 1).unwrap();
+	frame.set_pixel(0, 0, [255, 255, 255, 255]);
+	frame.set_pixel(1, 0, [0, 0, 255, 255]);
+	
+	let mut gif = image::GifEncoder::new(Vec::new(), 2, 1).unwrap();
+	gif.add_frame(&frame, std::time::Duration::from_millis(250)).unwrap();
+	assert!(gif.add_frame(&image::Image::new(1, 1).unwrap(), std::time::Duration::ZERO).is_err());
+	let gif = gif.finish().unwrap();
+	assert!(gif.starts_with(b"GIF89a\x02\x00\x01\x00"));
+	assert_eq!(gif.last(), Some(&0x3B));
+	// Graphic control block with the delay in hundredths, then the palette in order of first use.
+	let control = gif.windows(4).position(|bytes| bytes == [0x21, 0xF9, 0x04, 0x04]).unwrap();
+	assert_eq!(&gif[control + 4..control + 6], &25u16.to_le_bytes());
+	assert_eq!(&gif[control + 18..control + 24], &[255, 255, 255, 255, 0, 0]);
+	
+	let mut y4m = image::Y4mEncoder::new(Vec::new(), 2, 1, 25).unwrap();
+	y4m.add_frame(&frame).unwrap();
+	let y4m = y4m.finish().unwrap();
+	let header = b"YUV4MPEG2 W2 H1 F25:1 Ip A1:1 C444\nFRAME\n";
+	assert_eq!(&y4m[..header.len()], header);
+	assert_eq!(&y4m[header.len()..], &[235, 82, 128, 90, 128, 240]);
+}
+
+#[test]
+fn blits_images() {
+	// 2x3 source with distinct pixels, drawn into a 4x4 buffer.
+	let mut sprite = image::Image::new(2, 3).unwrap();
+	for y in 0..3 {
+		for x in 0..2 {
+			sprite.set_pixel(x, y, [(10 * y + x) as u8, 0, 0, 255]);
+		}
+	}
+	let client_rect = Rect { left: 0, top: 0, right: 4, bottom: 4 };
+	let blue = |buffer: &[u8], x: usize, y: usize| buffer[4 * (y * 4 + x)];
+	
+	let mut buffer = vec![99u8; 64];
+	sprite.blit(&mut buffer, &client_rect, 0, 0, &image::Blit::new().rotate(image::Rotation::Quarter));
+	assert_eq!([blue(&buffer, 0, 0), blue(&buffer, 1, 0), blue(&buffer, 2, 0), blue(&buffer, 0, 1), blue(&buffer, 2, 1), blue(&buffer, 3, 0)], [20, 10, 0, 21, 1, 99]);
+	
+	let mut buffer = vec![99u8; 64];
+	sprite.blit(&mut buffer, &client_rect, 3, -1, &image::Blit::new().flip_horizontal(true).flip_vertical(true));
+	assert_eq!([blue(&buffer, 3, 0), blue(&buffer, 3, 1), blue(&buffer, 2, 0)], [11, 1, 99]);
+	
+	let mut buffer = vec![99u8; 64];
+	let sub_rect = Rect { left: 1, top: 1, right: 2, bottom: 3 };
+	sprite.blit(&mut buffer, &client_rect, 1, 1, &image::Blit::new().source(sub_rect).rotate(image::Rotation::ThreeQuarters));
+	assert_eq!([blue(&buffer, 1, 1), blue(&buffer, 2, 1), blue(&buffer, 1, 2)], [11, 21, 99]);
+	
+	let mut buffer = vec![99u8; 64];
+	sprite.blit(&mut buffer, &client_rect, 0, 0, &image::Blit::new().blend(image::BlendMode::ColorKey([10, 0, 0])));
+	assert_eq!([blue(&buffer, 0, 0), blue(&buffer, 0, 1)], [0, 99]);
+	
+	// Half transparent white over black, straight and premultiplied.
+	let translucent = image::Image::from_bgra(1, 1, vec![255, 255, 255, 128]).unwrap();
+	let mut buffer = vec![0, 0, 0, 255];
+	translucent.blit(&mut buffer, &Rect { left: 0, top: 0, right: 1, bottom: 1 }, 0, 0, &image::Blit::new().blend(image::BlendMode::Alpha));
+	assert_eq!(buffer, [128, 128, 128, 255]);
+	let premultiplied = image::Image::from_bgra(1, 1, vec![128, 128, 128, 128]).unwrap();
+	let mut buffer = vec![0, 0, 0, 255];
+	premultiplied.blit(&mut buffer, &Rect { left: 0, top: 0, right: 1, bottom: 1 }, 0, 0, &image::Blit::new().blend(image::BlendMode::PremultipliedAlpha));
+	assert_eq!(buffer, [128, 128, 128, 255]);
+}
+
+#[test]
+fn draws_transformed_images() {
+	let transform = image::Affine::scaling(2.0, 3.0).then(&image::Affine::rotation(std::f64::consts::FRAC_PI_2)).then(&image::Affine::translation(10.0, 0.0));
+	let (x, y) = transform.apply(1.0, 1.0);
+	assert!((x - 7.0).abs() < 1e-9 && (y - 2.0).abs() < 1e-9);
+	let (x, y) = transform.inverse().unwrap().apply(7.0, 2.0);
+	assert!((x - 1.0).abs() < 1e-9 && (y - 1.0).abs() < 1e-9);
+	assert_eq!(image::Affine::scaling(0.0, 1.0).inverse(), None);
+	
+	// A black and white 2x1 image scaled up 2x into a 4x2 buffer.
+	let image = image::Image::from_bgra(2, 1, vec![0, 0, 0, 255, 255, 255, 255, 255]).unwrap();
+	let client_rect = Rect { left: 0, top: 0, right: 4, bottom: 2 };
+	let row = |buffer: &[u8]| [buffer[0], buffer[4], buffer[8], buffer[12]];
+	let mut buffer = vec![7u8; 32];
+	image.draw_transformed(&mut buffer, &client_rect, &image::Affine::scaling(2.0, 2.0), image::Filter::Nearest, None);
+	assert_eq!(row(&buffer), [0, 0, 255, 255]);
+	image.draw_transformed(&mut buffer, &client_rect, &image::Affine::scaling(2.0, 2.0), image::Filter::Bilinear, None);
+	assert_eq!(row(&buffer), [0, 64, 191, 255]);
+	image.draw_transformed(&mut buffer, &client_rect, &image::Affine::scaling(2.0, 2.0), image::Filter::Bicubic, None);
+	assert_eq!((buffer[0], buffer[4] < 64, buffer[8] > 191), (0, true, true));
+	
+	let mut buffer = vec![7u8; 32];
+	image.draw_transformed(&mut buffer, &client_rect, &image::Affine::scaling(2.0, 2.0), image::Filter::Nearest, Some(&Rect { left: 1, top: 0, right: 3, bottom: 1 }));
+	assert_eq!((row(&buffer), row(&buffer[16..])), ([7, 0, 255, 7], [7; 4]));
+	
+	// Area averaging keeps the mean of what each pixel covers, transparent pixels don't darken their neighbours.
+	let image = image::Image::from_bgra(3, 1, vec![0, 0, 0, 255, 255, 255, 255, 255, 90, 90, 90, 0]).unwrap();
+	let thumbnail = image.downscale(2, 1).unwrap();
+	assert_eq!(thumbnail.pixels(), &[85, 85, 85, 255, 255, 255, 255, 85]);
+	assert!(image.downscale(4, 1).is_err());
+}
+
+#[test]
+fn viewport_pans_and_zooms() {
+	let mut viewport = Viewport::new().zoom_limits(0.5, 4.0).zoom_per_notch(2.0);
+	let client_rect = Rect { left: 0, top: 0, right: 4, bottom: 3 };
+	let mut buffer: Vec<u8> = (0..12).flat_map(|i| [i, 0, 0, 255]).collect();
+	assert_eq!(viewport.take_repaint(&mut buffer, &client_rect), vec![client_rect]);
+	
+	// Dragging with the middle button moves the contents and leaves strips to paint.
+	assert!(!viewport.on_mouse_move(1, 1));
+	viewport.on_mouse_middle_down(1, 1);
+	assert!(viewport.on_mouse_move(2, 0));
+	assert!(!viewport.on_mouse_middle_up(2, 0));
+	assert_eq!(viewport.offset(), (1.0, -1.0));
+	assert_eq!(viewport.take_repaint(&mut buffer, &client_rect), vec![Rect { left: 0, top: 0, right: 1, bottom: 3 }, Rect { left: 0, top: 2, right: 4, bottom: 3 }]);
+	assert_eq!(buffer.iter().step_by(4).copied().collect::<Vec<u8>>()[..8], [0, 4, 5, 6, 4, 8, 9, 10]);
+	assert_eq!(viewport.take_repaint(&mut buffer, &client_rect), vec![]);
+	
+	// Zooming keeps the world point under the mouse in place and is clamped.
+	let anchor = viewport.mouse_world();
+	assert!(viewport.on_scroll(120));
+	assert_eq!((viewport.zoom(), viewport.mouse_world()), (2.0, anchor));
+	assert!(viewport.on_scroll(240));
+	assert_eq!(viewport.zoom(), 4.0);
+	assert!(!viewport.on_scroll(120));
+	assert_eq!(viewport.world_to_screen(anchor.0, anchor.1), (2.0, 0.0));
+	assert_eq!(viewport.transform().apply(anchor.0, anchor.1), (2.0, 0.0));
+	assert_eq!(viewport.take_repaint(&mut buffer, &client_rect), vec![client_rect]);
+}
+
+#[test]
+fn clipboard_exchanges_text_and_images() {
+	let mut image = image::Image::new(2, 2).unwrap();
+	image.set_pixel(0, 0, [1, 2, 3, 128]);
+	image.set_pixel(1, 1, [4, 5, 6, 255]);
+	
+	// Images go through the clipboard as packed bitmaps.
+	let dib = image.encode_dib();
+	assert_eq!(dib.len(), 124 + 16);
+	assert_eq!(image::Image::decode_dib(&dib).unwrap(), image);
+	let mut old_dib = vec![40, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 24, 0];
+	old_dib.extend_from_slice(&[0; 24]);
+	old_dib.extend_from_slice(&[9, 8, 7, 0]);
+	assert_eq!(image::Image::decode_dib(&old_dib).unwrap().get_pixel(0, 0), Some([9, 8, 7, 255]));
+	
+	let mut clipboard = MemoryClipboard::default();
+	let clipboard: &mut dyn Clipboard = &mut clipboard;
+	assert_eq!(clipboard.get_text(), Ok(None));
+	clipboard.set_text("copied").unwrap();
+	assert_eq!(clipboard.get_text(), Ok(Some(String::from("copied"))));
+	clipboard.set_image(&image).unwrap();
+	assert_eq!((clipboard.get_text(), clipboard.get_image()), (Ok(None), Ok(Some(image))));
+}
+
+#[test]
+fn collects_dropped_paths() {
+	let dropped = ["C:\\one.txt", "D:\\folder\\två.png", ""];
+	let paths = file_drop::collect_paths(dropped.len() as u32, |index, buffer| {
+		let path: Vec<u16> = dropped[index as usize].encode_utf16().collect();
+		match buffer {
+			Some(buffer) => {
+				let copied = path.len().min(buffer.len() - 1);
+				buffer[..copied].copy_from_slice(&path[..copied]);
+				buffer[copied] = 0;
+				copied as u32
+			}
+			None => path.len() as u32
+		}
+	});
+	assert_eq!(paths, dropped.iter().map(std::path::PathBuf::from).collect::<Vec<_>>());
+	assert!(file_drop::collect_paths(0, |_, _| unreachable!()).is_empty());
+}
+
+#[test]
+fn dialogs_answer_from_script() {
+	let mut dialogs = ScriptedDialogs::new([Some(std::path::PathBuf::from("picture.png")), None]);
+	dialogs.push_answer(Some(std::path::PathBuf::from("pictures")));
+	let dialogs: &mut dyn DialogProvider = &mut dialogs;
+	assert_eq!(dialogs.open_file(&[("Images", "*.png;*.bmp")], None), Ok(Some(std::path::PathBuf::from("picture.png"))));
+	assert_eq!(dialogs.save_file(&[], Some(std::path::Path::new("."))), Ok(None));
+	assert_eq!(dialogs.pick_folder(None), Ok(Some(std::path::PathBuf::from("pictures"))));
+	assert!(dialogs.open_file(&[], None).is_err());
+}
+
+#[test]
+fn maps_message_box_buttons() {
+	use windows::Win32::UI::WindowsAndMessaging::{MESSAGEBOX_RESULT, MB_OK, MB_OKCANCEL, MB_YESNO, MB_YESNOCANCEL, MB_RETRYCANCEL, MB_ICONWARNING, MB_ICONQUESTION, IDOK, IDCANCEL, IDYES, IDNO, IDRETRY};
+	use message_box::{message_box_style, pressed_button};
+	assert_eq!(message_box_style(MessageBoxButtons::Ok, MessageBoxIcon::None), MB_OK);
+	assert_eq!(message_box_style(MessageBoxButtons::OkCancel, MessageBoxIcon::None), MB_OKCANCEL);
+	assert_eq!(message_box_style(MessageBoxButtons::YesNo, MessageBoxIcon::Question), MB_YESNO | MB_ICONQUESTION);
+	assert_eq!(message_box_style(MessageBoxButtons::YesNoCancel, MessageBoxIcon::Warning), MB_YESNOCANCEL | MB_ICONWARNING);
+	assert_eq!(message_box_style(MessageBoxButtons::RetryCancel, MessageBoxIcon::None), MB_RETRYCANCEL);
+	
+	assert_eq!(pressed_button(IDOK), Some(MessageBoxButton::Ok));
+	assert_eq!(pressed_button(IDCANCEL), Some(MessageBoxButton::Cancel));
+	assert_eq!(pressed_button(IDYES), Some(MessageBoxButton::Yes));
+	assert_eq!(pressed_button(IDNO), Some(MessageBoxButton::No));
+	assert_eq!(pressed_button(IDRETRY), Some(MessageBoxButton::Retry));
+	assert_eq!(pressed_button(MESSAGEBOX_RESULT(0)), None);
+}
+
+#[test]
+fn event_proxy_crosses_threads() {
+	use windows::{core::w, Win32::{Foundation::HWND, UI::WindowsAndMessaging::{CreateWindowExW, DestroyWindow, PeekMessageW, HWND_MESSAGE, MSG, PM_REMOVE, WINDOW_EX_STYLE, WINDOW_STYLE}}};
+	// A message-only window stands in for the app's window, its queue is read directly.
+	let hwnd = unsafe { CreateWindowExW(WINDOW_EX_STYLE(0), w!("STATIC"), None, WINDOW_STYLE(0), 0, 0, 0, 0, HWND_MESSAGE, None, None, None) };
+	assert_ne!(hwnd, HWND(0));
+	
+	let proxy = WindowHandle { hwnd }.event_proxy();
+	let sender = std::thread::spawn(move || {
+		for value in 0..3 {
+			proxy.send(value).unwrap();
+		}
+		proxy.send(String::from("done")).unwrap();
+		std::thread::current().id()
+	});
+	let sender_thread = sender.join().unwrap();
+	assert_ne!(sender_thread, std::thread::current().id());
+	
+	let mut payloads = Vec::new();
+	let mut message = MSG::default();
+	while unsafe { PeekMessageW(&mut message, hwnd, event_proxy::WM_USER_EVENT, event_proxy::WM_USER_EVENT, PM_REMOVE) }.as_bool() {
+		payloads.push(event_proxy::take_payload(message.lParam));
+	}
+	unsafe { DestroyWindow(hwnd) }.unwrap();
+	
+	assert_eq!(payloads.len(), 4);
+	for (value, payload) in payloads[..3].iter().enumerate() {
+		assert_eq!(payload.downcast_ref::<i32>(), Some(&(value as i32)));
+	}
+	assert_eq!(payloads[3].downcast_ref::<String>().map(String::as_str), Some("done"));
+}
+
+#[test]
+fn event_proxy_frees_undelivered_payloads() {
+	use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
+	struct Payload(Arc<AtomicUsize>);
+	impl Drop for Payload {
+		fn drop(&mut self) {
+			self.0.fetch_add(1, Ordering::SeqCst);
+		}
+	}
+	
+	let drops = Arc::new(AtomicUsize::new(0));
+	let proxy = WindowHandle { hwnd: windows::Win32::Foundation::HWND(0x7FFF_FFF0) }.event_proxy();
+	assert!(proxy.send(Payload(drops.clone())).is_err());
+	assert_eq!(drops.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn background_work_and_channels_wake_tasks() {
+	struct ThreadWaker(std::thread::Thread);
+	impl std::task::Wake for ThreadWaker {
+		fn wake(self: std::sync::Arc<Self>) {
+			self.0.unpark();
+		}
+	}
+	fn block_on<T>(future: impl std::future::Future<Output = T>) -> T {
+		let waker = std::task::Waker::from(std::sync::Arc::new(ThreadWaker(std::thread::current())));
+		let mut future = std::pin::pin!(future);
+		loop {
+			if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut std::task::Context::from_waker(&waker)) {
+				return output;
+			}
+			std::thread::park();
+		}
+	}
+	
+	assert_eq!(block_on(tasks::run_in_background(|| 6 * 7)), Ok(42));
+	assert!(block_on(tasks::run_in_background(|| panic!("worker failed"))).is_err());
+	
+	let (sender, mut receiver) = tasks::channel();
+	std::thread::spawn(move || {
+		for progress in 0..3 {
+			sender.send(progress).unwrap();
+		}
+	});
+	let received: Vec<i32> = block_on(async {
+		let mut received = Vec::new();
+		while let Some(progress) = receiver.recv().await {
+			received.push(progress);
+		}
+		received
+	});
+	assert_eq!(received, [0, 1, 2]);
+}
+
+#[test]
+fn app_access_borrows_the_state_once() {
+	use windows::Win32::Foundation::HWND;
+	#[derive(Debug, PartialEq)]
+	struct Counter(u32);
+	impl SimpleWindowApp for Counter {}
+	let new_app = |hwnd: isize| App {
+		window_handle: WindowHandle { hwnd: HWND(hwnd) },
+		client_rect: Rect::default(),
+		pixel_buffer: pixel_buffer::PixelBuffer::default(),
+		user_state_type: std::any::TypeId::of::<Counter>(),
+		user_state: Box::new(Counter(0)),
+		panic: None,
+		dispatch_depth: 0,
+		state_borrowed: false,
+		deferred_messages: std::collections::VecDeque::new()
+	};
+	let (mut app, mut other_app) = (new_app(0x7FFF_FFF8), new_app(0x7FFF_FFF9));
+	let app_ptr = &mut app as *mut App;
+	let access = WindowHandle { hwnd: HWND(0x7FFF_FFF8) }.app_access::<Counter>();
+	
+	assert!(access.with(|_, _, _, _| ()).is_err());
+	assert_eq!(tasks::polling(app_ptr, || access.with(|counter, _, _, _| {
+		counter.0 += 1;
+		access.with(|_, _, _, _| ()).is_err()
+	})), Ok(true));
+	assert!(tasks::polling(app_ptr, || WindowHandle { hwnd: HWND(0x7FFF_FFF8) }.app_access::<MyAppState>().with(|_, _, _, _| ())).is_err());
+	assert!(tasks::polling(&mut other_app, || access.with(|_, _, _, _| ())).is_err());
+	
+	// A callback further out has the state.
+	unsafe { (*app_ptr).state_borrowed = true };
+	assert!(tasks::polling(app_ptr, || access.with(|_, _, _, _| ())).is_err());
+	unsafe { (*app_ptr).state_borrowed = false };
+	
+	// A panicking poll doesn't leave its app behind.
+	assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| tasks::polling(app_ptr, || panic!("task failed")))).is_err());
+	assert!(access.with(|_, _, _, _| ()).is_err());
+	assert_eq!(tasks::polling(app_ptr, || access.with(|counter, _, _, _| counter.0)), Ok(1));
+}
+
+#[test]
+fn user_events_go_to_their_receiver() {
+	let handle = WindowHandle { hwnd: windows::Win32::Foundation::HWND(0x7FFF_FFF4) };
+	let payload = |value: i32| -> Box<dyn std::any::Any + Send> { Box::new(value) };
+	let value = |payload: Box<dyn std::any::Any + Send>| *payload.downcast::<i32>().unwrap();
+	
+	assert_eq!(tasks::deliver_user_event(handle.hwnd, payload(1)).map(value), Some(1));
+	let mut events = handle.user_events().unwrap();
+	assert!(handle.user_events().is_err());
+	assert!(tasks::deliver_user_event(handle.hwnd, payload(2)).is_none());
+	assert!(tasks::deliver_user_event(handle.hwnd, payload(3)).is_none());
+	
+	let waker = std::task::Waker::noop();
+	let mut next = std::pin::pin!(events.recv());
+	assert!(matches!(std::future::Future::poll(next.as_mut(), &mut std::task::Context::from_waker(waker)), std::task::Poll::Ready(payload) if payload.downcast_ref::<i32>() == Some(&2)));
+	assert_eq!(events.try_recv().map(value), Some(3));
+	assert!(events.try_recv().is_none());
+	
+	drop(events);
+	assert_eq!(tasks::deliver_user_event(handle.hwnd, payload(4)).map(value), Some(4));
+	assert!(handle.user_events().is_ok());
+	
+	assert!(handle.app_access::<MyAppState>().with(|_, _, _, _| ()).is_err());
+}
+
+#[test]
+fn renders_tiles_in_parallel() {
+	let client_rect = Rect { left: 0, top: 0, right: 50, bottom: 30 };
+	let mut pixel_buffer = vec![0u8; 4 * 50 * 30];
+	let shade = |x: i32, y: i32| [x as u8, y as u8, 0, 255];
+	TileRenderer::new().tile_size(16).threads(3).render_pixels(&mut pixel_buffer, &client_rect, 1, shade);
+	assert!(pixel_buffer.chunks_exact(4).enumerate().all(|(i, pixel)| pixel == shade(i as i32 % 50, i as i32 / 50)));
+	
+	// Coarse squares line up across tiles that don't divide evenly by them.
+	TileRenderer::new().tile_size(7).render_pixels(&mut pixel_buffer, &client_rect, 4, shade);
+	assert!(pixel_buffer.chunks_exact(4).enumerate().all(|(i, pixel)| pixel == shade(i as i32 % 50 / 4 * 4, i as i32 / 50 / 4 * 4)));
+	
+	let covered = std::sync::Mutex::new(0);
+	TileRenderer::new().tile_size(16).render_tiles(&mut pixel_buffer, &client_rect, |tile| {
+		let rect = tile.rect();
+		assert_eq!(tile.rows_mut().len() as i32, rect.height());
+		assert!(tile.rows_mut().iter().all(|row| row.len() as i32 == 4 * rect.width()));
+		*covered.lock().unwrap() += rect.width() * rect.height();
+	});
+	assert_eq!(covered.into_inner().unwrap(), 50 * 30);
+}
+
+#[test]
+fn resizing_keeps_contents() {
+	let old_rect = Rect { left: 0, top: 0, right: 2, bottom: 2 };
+	let old: Vec<u8> = (1..=4).flat_map(|i| [i; 4]).collect();
+	let resized = |right: i32, bottom: i32, resize_contents: ResizeContents| {
+		let new_rect = Rect { left: 0, top: 0, right, bottom };
+		let mut new = vec![0; 4 * (right * bottom) as usize];
+		pixel_buffer::copy_contents(&old, &old_rect, &mut new, &new_rect, resize_contents);
+		new.chunks_exact(4).map(|pixel| pixel[0]).collect::<Vec<u8>>()
+	};
+	assert_eq!(resized(3, 2, ResizeContents::Discard), [0, 0, 0, 0, 0, 0]);
+	assert_eq!(resized(3, 2, ResizeContents::KeepTopLeft), [1, 2, 0, 3, 4, 0]);
+	assert_eq!(resized(4, 4, ResizeContents::KeepCentered), [0, 0, 0, 0, 0, 1, 2, 0, 0, 3, 4, 0, 0, 0, 0, 0]);
+	assert_eq!(resized(1, 1, ResizeContents::KeepTopLeft), [1]);
+	assert_eq!(resized(1, 2, ResizeContents::KeepCentered), [1, 3]);
+}
+
+#[test]
+fn reports_panic_messages() {
+	assert_eq!(panic_message(std::panic::catch_unwind(|| panic!("callback failed")).unwrap_err()), "callback failed");
+	assert_eq!(panic_message(std::panic::catch_unwind(|| panic!("frame {}", 3)).unwrap_err()), "frame 3");
+	assert_eq!(panic_message(Box::new(7)), "Unknown panic.");
+}
+
+#[test]
+fn defers_nested_messages_that_need_callbacks() {
+	use windows::Win32::UI::WindowsAndMessaging::{WM_COMMAND, WM_SIZE, WM_CLOSE, WM_DROPFILES, WM_TIMER, WM_MOUSEMOVE, WM_KEYDOWN, WM_MENUSELECT, WM_INITMENUPOPUP};
+	for message in [WM_COMMAND, WM_SIZE, WM_CLOSE, WM_DROPFILES, event_proxy::WM_USER_EVENT, recording::WM_RECORDING_ERROR] {
+		assert!(is_deferred_while_borrowed(message), "{message:#x}");
+	}
+	// Timers fire again, and late input or menu notifications would be stale.
+	for message in [WM_TIMER, WM_MOUSEMOVE, WM_KEYDOWN, WM_MENUSELECT, WM_INITMENUPOPUP, tasks::WM_WAKE_TASK] {
+		assert!(!is_deferred_while_borrowed(message), "{message:#x}");
+	}
+}
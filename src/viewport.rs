@@ -0,0 +1,194 @@
+use crate::{Rect, image::Affine};
+
+
+// The scroll distance of one wheel notch.
+const WHEEL_DELTA: f64 = 120.0;
+
+// Pan and zoom state for a view onto a world plane, where screen = world * zoom + offset.
+// Feed it the window's mouse and scroll events, then call `take_repaint` while painting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Viewport {
+	zoom: f64,
+	offset_x: f64,
+	offset_y: f64,
+	min_zoom: f64,
+	max_zoom: f64,
+	zoom_per_notch: f64,
+	mouse: (i16, i16),
+	dragging: bool,
+	// Whole pixels the view moved by since the last repaint, or None once the contents have to be redrawn entirely.
+	pending_shift: Option<(i32, i32)>,
+	last_size: (i32, i32)
+}
+impl Default for Viewport {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+impl Viewport {
+	pub fn new() -> Self {
+		Self {
+			zoom: 1.0,
+			offset_x: 0.0,
+			offset_y: 0.0,
+			min_zoom: 1.0 / 64.0,
+			max_zoom: 64.0,
+			zoom_per_notch: 1.25,
+			mouse: (0, 0),
+			dragging: false,
+			pending_shift: None,
+			last_size: (0, 0)
+		}
+	}
+	
+	pub fn zoom_limits(mut self, min_zoom: f64, max_zoom: f64) -> Self {
+		self.min_zoom = min_zoom.min(max_zoom);
+		self.max_zoom = max_zoom.max(min_zoom);
+		self.zoom = self.zoom.clamp(self.min_zoom, self.max_zoom);
+		self
+	}
+	
+	pub fn zoom_per_notch(mut self, factor: f64) -> Self {
+		self.zoom_per_notch = factor;
+		self
+	}
+	
+	pub fn zoom(&self) -> f64 {
+		self.zoom
+	}
+	
+	pub fn offset(&self) -> (f64, f64) {
+		(self.offset_x, self.offset_y)
+	}
+	
+	pub fn is_dragging(&self) -> bool {
+		self.dragging
+	}
+	
+	pub fn transform(&self) -> Affine {
+		Affine { xx: self.zoom, yx: 0.0, xy: 0.0, yy: self.zoom, x0: self.offset_x, y0: self.offset_y }
+	}
+	
+	pub fn screen_to_world(&self, x: f64, y: f64) -> (f64, f64) {
+		((x - self.offset_x) / self.zoom, (y - self.offset_y) / self.zoom)
+	}
+	
+	pub fn world_to_screen(&self, x: f64, y: f64) -> (f64, f64) {
+		(x * self.zoom + self.offset_x, y * self.zoom + self.offset_y)
+	}
+	
+	// World position of the mouse, as last seen by `on_mouse_move`.
+	pub fn mouse_world(&self) -> (f64, f64) {
+		self.screen_to_world(self.mouse.0 as f64, self.mouse.1 as f64)
+	}
+	
+	// Moves the view by whole screen pixels.
+	pub fn pan_by(&mut self, dx: i32, dy: i32) {
+		if dx == 0 && dy == 0 {
+			return;
+		}
+		self.offset_x += dx as f64;
+		self.offset_y += dy as f64;
+		if let Some((shift_x, shift_y)) = self.pending_shift {
+			self.pending_shift = Some((shift_x + dx, shift_y + dy));
+		}
+	}
+	
+	// Zooms by `factor` while keeping the world point under screen position `x`, `y` in place. Returns whether the zoom changed.
+	pub fn zoom_at(&mut self, factor: f64, x: f64, y: f64) -> bool {
+		let zoom = (self.zoom * factor).clamp(self.min_zoom, self.max_zoom);
+		if zoom == self.zoom || !zoom.is_finite() {
+			return false;
+		}
+		let (world_x, world_y) = self.screen_to_world(x, y);
+		self.zoom = zoom;
+		self.offset_x = x - world_x * zoom;
+		self.offset_y = y - world_y * zoom;
+		self.pending_shift = None;
+		true
+	}
+	
+	pub fn set_view(&mut self, zoom: f64, offset_x: f64, offset_y: f64) {
+		self.zoom = zoom.clamp(self.min_zoom, self.max_zoom);
+		self.offset_x = offset_x;
+		self.offset_y = offset_y;
+		self.pending_shift = None;
+	}
+	
+	// The event handlers return whether the view changed and the window should be redrawn.
+	pub fn on_mouse_move(&mut self, mouse_x: i16, mouse_y: i16) -> bool {
+		let (last_x, last_y) = self.mouse;
+		self.mouse = (mouse_x, mouse_y);
+		if !self.dragging {
+			return false;
+		}
+		self.pan_by(mouse_x as i32 - last_x as i32, mouse_y as i32 - last_y as i32);
+		(mouse_x, mouse_y) != (last_x, last_y)
+	}
+	
+	pub fn on_mouse_middle_down(&mut self, mouse_x: i16, mouse_y: i16) {
+		self.mouse = (mouse_x, mouse_y);
+		self.dragging = true;
+	}
+	
+	pub fn on_mouse_middle_up(&mut self, mouse_x: i16, mouse_y: i16) -> bool {
+		let changed = self.on_mouse_move(mouse_x, mouse_y);
+		self.dragging = false;
+		changed
+	}
+	
+	// `on_scroll` doesn't carry a position, the zoom centers on the last position passed to `on_mouse_move`.
+	pub fn on_scroll(&mut self, scroll_distance: i16) -> bool {
+		let factor = self.zoom_per_notch.powf(scroll_distance as f64 / WHEEL_DELTA);
+		self.zoom_at(factor, self.mouse.0 as f64, self.mouse.1 as f64)
+	}
+	
+	// Brings the pixel buffer up to date with the view and returns the areas that still need painting.
+	// After a pan the old contents are moved along with the view, so only the uncovered strips are returned. Anything else repaints the whole client area.
+	pub fn take_repaint(&mut self, pixel_buffer: &mut [u8], client_rect: &Rect) -> Vec<Rect> {
+		let (width, height) = (client_rect.width().max(0), client_rect.height().max(0));
+		let whole = vec![Rect { left: 0, top: 0, right: width, bottom: height }];
+		let size_changed = self.last_size != (width, height);
+		self.last_size = (width, height);
+		let shift = self.pending_shift.replace((0, 0));
+		
+		let (dx, dy) = match shift {
+			Some(shift) if !size_changed && pixel_buffer.len() >= 4 * width as usize * height as usize => shift,
+			_ => return whole
+		};
+		if dx.abs() >= width || dy.abs() >= height {
+			return whole;
+		}
+		if (dx, dy) == (0, 0) {
+			return Vec::new();
+		}
+		
+		let stride = 4 * width as usize;
+		let length = 4 * (width - dx.abs()) as usize;
+		let (source_x, destination_x) = if dx >= 0 { (0, 4 * dx as usize) } else { (4 * -dx as usize, 0) };
+		// Rows are copied in the direction that doesn't overwrite rows still to be moved.
+		for i in 0..height - dy.abs() {
+			let row = if dy > 0 { height - 1 - i } else { i };
+			let source = (row - dy) as usize * stride + source_x;
+			pixel_buffer.copy_within(source..source + length, row as usize * stride + destination_x);
+		}
+		
+		let mut exposed = Vec::new();
+		match dx {
+			0 => {}
+			dx if dx > 0 => exposed.push(Rect { left: 0, top: 0, right: dx, bottom: height }),
+			dx => exposed.push(Rect { left: width + dx, top: 0, right: width, bottom: height })
+		}
+		match dy {
+			0 => {}
+			dy if dy > 0 => exposed.push(Rect { left: 0, top: 0, right: width, bottom: dy }),
+			dy => exposed.push(Rect { left: 0, top: height + dy, right: width, bottom: height })
+		}
+		exposed
+	}
+	
+	// Forces the next `take_repaint` to repaint everything, for when the world itself changed.
+	pub fn invalidate(&mut self) {
+		self.pending_shift = None;
+	}
+}